@@ -0,0 +1,578 @@
+//! Pluggable compression backends.
+//!
+//! A [`CompressionBackend`] turns decompressed NBT into the bytes that get
+//! written back to disk. New codecs only need to implement this trait to be
+//! usable from [`compress_file`](crate::file::compress_file).
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind::Unsupported, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::codec::{self, Format, IterationStrategy};
+
+/// Backends are shared between worker threads, so they must be `Sync`.
+pub trait CompressionBackend: Sync {
+    /// A short human-readable name, used in reports.
+    fn name(&self) -> &str;
+
+    /// Compresses decompressed NBT data into `format`.
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>>;
+
+    /// The format this backend always produces, regardless of the input's, or `None` if
+    /// it can keep gzip and zlib data in their original format.
+    fn output_format(&self) -> Option<Format> {
+        None
+    }
+
+    /// Like [`compress_as`](Self::compress_as), but writes into `dest`, replacing what it
+    /// held. Backends that can reuse its allocation override this, so that compressing
+    /// many small streams like region chunks doesn't allocate for each one.
+    fn compress_into(&self, data: &[u8], format: Format, dest: &mut Vec<u8>) -> Result<()> {
+        *dest = self.compress_as(data, format)?;
+        Ok(())
+    }
+
+    /// Compresses decompressed NBT data into a gzip stream.
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.compress_as(data, Format::Gzip)
+    }
+
+    /// Whether this backend is so much slower than libdeflate that it is worth checking
+    /// first whether it can help at all, see [`Options::quick_check`](crate::file::Options::quick_check).
+    fn is_slow(&self) -> bool {
+        false
+    }
+
+    /// The name along with the settings that would be used for `data`, such as a level or
+    /// iteration count.
+    fn describe(&self, _data: &[u8]) -> String {
+        self.name().to_string()
+    }
+
+    /// Like [`compress_as`](Self::compress_as), but also records which backend produced
+    /// the output and how long each attempt took. Only backends that delegate to others,
+    /// like [`Best`], or that pick their settings while compressing need to override this.
+    fn compress_traced(&self, data: &[u8], format: Format) -> Result<Compressed> {
+        let start = Instant::now();
+        let output = self.compress_as(data, format)?;
+        let attempt = Attempt { backend: self.describe(data), size: output.len(), elapsed: start.elapsed() };
+        Ok(Compressed { data: output, backend: self.name().to_string(), attempts: vec![attempt] })
+    }
+
+    /// Like [`compress_traced`](Self::compress_traced), keeping only the output and the
+    /// name of the backend that produced it.
+    fn compress_named(&self, data: &[u8], format: Format) -> Result<(Vec<u8>, String)> {
+        let compressed = self.compress_traced(data, format)?;
+        Ok((compressed.data, compressed.backend))
+    }
+}
+
+/// The output of [`CompressionBackend::compress_traced`].
+#[derive(Clone, Debug)]
+pub struct Compressed {
+    pub data: Vec<u8>,
+    /// The backend whose output was used.
+    pub backend: String,
+    /// Every compression that was run, in order, including those whose output was discarded.
+    pub attempts: Vec<Attempt>,
+}
+
+/// A single run of a backend, as recorded for verbose reports.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Attempt {
+    /// The backend and its settings, see [`CompressionBackend::describe`].
+    pub backend: String,
+    pub size: usize,
+    pub elapsed: Duration,
+}
+
+/// gzip via libdeflate, fast and usually close to optimal.
+pub struct Libdeflater {
+    pub level: u8,
+}
+
+impl CompressionBackend for Libdeflater {
+    fn name(&self) -> &str {
+        "libdeflater"
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        codec::compress_libdeflater(data, format, self.level)
+    }
+
+    fn compress_into(&self, data: &[u8], format: Format, dest: &mut Vec<u8>) -> Result<()> {
+        codec::compress_libdeflater_into(data, format, self.level, dest)
+    }
+
+    fn describe(&self, _data: &[u8]) -> String {
+        format!("libdeflater (level {})", self.level)
+    }
+}
+
+/// gzip via zopfli, much slower but squeezes out a few more bytes.
+///
+/// A non-positive `iterations` lets [`codec::optimise_zopfli`] choose based on input size.
+pub struct Zopfli {
+    pub iterations: i32,
+    /// See [`codec::DEFAULT_BLOCK_SPLITS`].
+    pub block_splits: u16,
+}
+
+impl CompressionBackend for Zopfli {
+    fn name(&self) -> &str {
+        "zopfli"
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        codec::optimise_zopfli(data, format, self.iterations, self.block_splits)
+    }
+
+    fn is_slow(&self) -> bool {
+        true
+    }
+
+    fn describe(&self, data: &[u8]) -> String {
+        let iterations = codec::zopfli_iterations(data.len(), self.iterations);
+        format!("zopfli ({} iterations{})", iterations, block_splits_suffix(self.block_splits))
+    }
+}
+
+/// The zopfli backend that picks its iteration count with `strategy`: [`TimedZopfli`]
+/// for a time budget, and [`Zopfli`] otherwise.
+pub fn zopfli(strategy: IterationStrategy, block_splits: u16) -> Box<dyn CompressionBackend + Send> {
+    match strategy {
+        IterationStrategy::Fixed(iterations) => Box::new(Zopfli { iterations: iterations.get().min(i32::MAX as u64) as i32, block_splits }),
+        IterationStrategy::SizeBased => Box::new(Zopfli { iterations: -1, block_splits }),
+        IterationStrategy::TimeBudget(budget) => Box::new(TimedZopfli { budget, block_splits }),
+    }
+}
+
+/// zstd, which Minecraft can't read but which often beats both deflate backends.
+pub struct Zstd {
+    pub level: i32,
+}
+
+impl CompressionBackend for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        match format {
+            Format::Zstd => codec::compress_zstd(data, self.level),
+            _ => Err(Error::new(Unsupported, format!("zstd can't produce {:?} output", format))),
+        }
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        Some(Format::Zstd)
+    }
+
+    fn describe(&self, _data: &[u8]) -> String {
+        format!("zstd (level {})", self.level)
+    }
+}
+
+/// brotli, which Minecraft can't read either, for other tools that expect it.
+pub struct Brotli {
+    pub quality: u32,
+}
+
+impl CompressionBackend for Brotli {
+    fn name(&self) -> &str {
+        "brotli"
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        match format {
+            Format::Brotli => codec::compress_brotli(data, self.quality),
+            _ => Err(Error::new(Unsupported, format!("brotli can't produce {:?} output", format))),
+        }
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        Some(Format::Brotli)
+    }
+
+    fn describe(&self, _data: &[u8]) -> String {
+        format!("brotli (quality {})", self.quality)
+    }
+}
+
+/// zopfli with as many iterations as fit in a time budget, see [`codec::zopfli_time_budget`].
+///
+/// The budget applies to each compressed stream, so every chunk of a region file gets
+/// its own. As the iteration count depends on how fast the machine is, the output isn't
+/// reproducible.
+pub struct TimedZopfli {
+    pub budget: Duration,
+    /// See [`codec::DEFAULT_BLOCK_SPLITS`].
+    pub block_splits: u16,
+}
+
+impl CompressionBackend for TimedZopfli {
+    fn name(&self) -> &str {
+        "zopfli"
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        Ok(codec::zopfli_time_budget(data, format, self.budget, self.block_splits)?.0)
+    }
+
+    fn is_slow(&self) -> bool {
+        true
+    }
+
+    fn compress_traced(&self, data: &[u8], format: Format) -> Result<Compressed> {
+        let start = Instant::now();
+        let (output, iterations) = codec::zopfli_time_budget(data, format, self.budget, self.block_splits)?;
+        let backend = format!("zopfli ({} iterations{})", iterations, block_splits_suffix(self.block_splits));
+        let attempt = Attempt { backend: backend.clone(), size: output.len(), elapsed: start.elapsed() };
+        Ok(Compressed { data: output, backend, attempts: vec![attempt] })
+    }
+}
+
+/// The block split limit for a zopfli description, left out if it is the default.
+fn block_splits_suffix(block_splits: u16) -> String {
+    match block_splits {
+        codec::DEFAULT_BLOCK_SPLITS => String::new(),
+        0 => ", unlimited block splits".to_string(),
+        n => format!(", {} block splits", n),
+    }
+}
+
+/// Runs every candidate backend and keeps whichever output is smallest.
+pub struct Best {
+    pub candidates: Vec<Box<dyn CompressionBackend>>,
+}
+
+impl CompressionBackend for Best {
+    fn name(&self) -> &str {
+        "best"
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        self.candidates.first().and_then(|c| c.output_format())
+    }
+
+    fn is_slow(&self) -> bool {
+        self.candidates.iter().any(|c| c.is_slow())
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        Ok(self.compress_traced(data, format)?.data)
+    }
+
+    fn compress_traced(&self, data: &[u8], format: Format) -> Result<Compressed> {
+        let mut best: Option<Compressed> = None;
+        let mut attempts = Vec::new();
+        for candidate in &self.candidates {
+            let mut compressed = candidate.compress_traced(data, format)?;
+            log::debug!("best: {} produced {} bytes", compressed.backend, compressed.data.len());
+            attempts.append(&mut compressed.attempts);
+            if best.as_ref().is_none_or(|b| compressed.data.len() < b.data.len()) {
+                best = Some(compressed);
+            }
+        }
+        let best = best.ok_or_else(|| Error::other("No backends to choose from"))?;
+        Ok(Compressed { attempts, ..best })
+    }
+}
+
+/// Uses `primary`, falling back to `secondary` only if `primary` fails. The reported
+/// backend name records the failure, so the fallback is never silent.
+pub struct Fallback {
+    pub primary: Box<dyn CompressionBackend>,
+    pub secondary: Box<dyn CompressionBackend>,
+}
+
+impl CompressionBackend for Fallback {
+    fn name(&self) -> &str {
+        self.primary.name()
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        self.primary.output_format()
+    }
+
+    fn is_slow(&self) -> bool {
+        self.primary.is_slow()
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        Ok(self.compress_traced(data, format)?.data)
+    }
+
+    fn compress_traced(&self, data: &[u8], format: Format) -> Result<Compressed> {
+        match self.primary.compress_traced(data, format) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                log::debug!("{} failed, falling back to {}: {}", self.primary.name(), self.secondary.name(), e);
+                let compressed = self.secondary.compress_traced(data, format)?;
+                let backend = format!("{} (fallback, {} failed: {})", compressed.backend, self.primary.name(), e);
+                Ok(Compressed { backend, ..compressed })
+            }
+        }
+    }
+}
+
+/// What [`TimeLimit`] appends to the backend of an output it fell back to.
+pub const TIMED_OUT: &str = "timed out";
+
+/// Runs `primary` on a worker thread, and if it takes longer than `timeout` for one
+/// input, gives up waiting and uses `fallback`'s output instead. The limit applies to
+/// each stream, or each chunk of a region file.
+///
+/// A thread can't be stopped from outside, so an abandoned compression keeps running in
+/// the background until it finishes; only the wait for it is cut short. Each input is
+/// copied for the worker, as it may outlive the call.
+pub struct TimeLimit {
+    pub primary: Arc<dyn CompressionBackend + Send>,
+    pub fallback: Box<dyn CompressionBackend>,
+    pub timeout: Duration,
+    timed_out: Arc<AtomicUsize>,
+}
+
+impl TimeLimit {
+    pub fn new(primary: Arc<dyn CompressionBackend + Send>, fallback: Box<dyn CompressionBackend>, timeout: Duration) -> Self {
+        TimeLimit { primary, fallback, timeout, timed_out: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// How many inputs `primary` ran out of time on.
+    pub fn timed_out(&self) -> usize {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// The count behind [`timed_out`](Self::timed_out), which can still be read once this
+    /// is part of another backend. Region files only report the backend they were given,
+    /// so this is the only way to tell that their chunks timed out.
+    pub fn timed_out_counter(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.timed_out)
+    }
+}
+
+impl CompressionBackend for TimeLimit {
+    fn name(&self) -> &str {
+        self.primary.name()
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        self.primary.output_format()
+    }
+
+    fn is_slow(&self) -> bool {
+        self.primary.is_slow()
+    }
+
+    fn describe(&self, data: &[u8]) -> String {
+        self.primary.describe(data)
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        Ok(self.compress_traced(data, format)?.data)
+    }
+
+    fn compress_traced(&self, data: &[u8], format: Format) -> Result<Compressed> {
+        let (sender, receiver) = mpsc::channel();
+        let primary = Arc::clone(&self.primary);
+        let input = data.to_vec();
+        std::thread::spawn(move || {
+            // nobody is listening any more if the wait timed out
+            let _ = sender.send(primary.compress_traced(&input, format));
+        });
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+                log::debug!("{} took longer than {:?} for {} bytes, using {}", self.primary.name(), self.timeout, data.len(), self.fallback.name());
+                let compressed = self.fallback.compress_traced(data, format)?;
+                let backend = format!("{} ({} {} after {:?})", compressed.backend, self.primary.name(), TIMED_OUT, self.timeout);
+                Ok(Compressed { backend, ..compressed })
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::other(format!("{} panicked", self.primary.name()))),
+        }
+    }
+}
+
+/// A backend that reuses `inner`'s output for any data it was given before, keeping
+/// outputs in `S`: see [`Dedupe`] and [`Lru`]. Inputs are told apart by their BLAKE3 hash
+/// and the format they are compressed to.
+///
+/// Two threads given the same data at the same time may both compress it.
+pub struct Cached<'a, S> {
+    inner: &'a dyn CompressionBackend,
+    outputs: Mutex<S>,
+    reused: AtomicUsize,
+}
+
+/// The hash of an input to [`Cached`], and the format it was compressed to.
+pub type Key = ([u8; 32], Format);
+
+/// Where [`Cached`] keeps its outputs.
+pub trait Store: Send {
+    /// What reuses are logged as.
+    const NAME: &'static str;
+    /// What the backend's name gets appended in reused outputs.
+    const SUFFIX: &'static str;
+
+    fn get(&mut self, key: &Key) -> Option<&Compressed>;
+
+    /// Whether an output of `len` bytes would be kept at all, so that one that wouldn't
+    /// isn't copied for nothing.
+    fn keeps(&self, _len: usize) -> bool {
+        true
+    }
+
+    /// Keeps `compressed` as the output for `key`.
+    fn put(&mut self, key: Key, compressed: Compressed);
+}
+
+impl<S: Store> Cached<'_, S> {
+    /// Copies the output kept for `key` into `dest`, returning its backend's name if there
+    /// was one.
+    fn reuse(&self, key: &Key, len: usize, dest: &mut Vec<u8>) -> Option<String> {
+        let mut outputs = self.outputs.lock().unwrap();
+        let output = outputs.get(key)?;
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        log::debug!("{}: {} bytes seen before, reusing {} output", S::NAME, len, output.backend);
+        dest.clear();
+        dest.extend_from_slice(&output.data);
+        Some(format!("{}{}", output.backend, S::SUFFIX))
+    }
+}
+
+impl<S: Store> CompressionBackend for Cached<'_, S> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn output_format(&self) -> Option<Format> {
+        self.inner.output_format()
+    }
+
+    fn is_slow(&self) -> bool {
+        self.inner.is_slow()
+    }
+
+    fn describe(&self, data: &[u8]) -> String {
+        self.inner.describe(data)
+    }
+
+    fn compress_as(&self, data: &[u8], format: Format) -> Result<Vec<u8>> {
+        Ok(self.compress_traced(data, format)?.data)
+    }
+
+    fn compress_traced(&self, data: &[u8], format: Format) -> Result<Compressed> {
+        let key = (*blake3::hash(data).as_bytes(), format);
+        let mut output = Vec::new();
+        if let Some(backend) = self.reuse(&key, data.len(), &mut output) {
+            return Ok(Compressed { data: output, backend, attempts: Vec::new() });
+        }
+
+        // compressed without holding the lock, so other inputs aren't held up
+        let compressed = self.inner.compress_traced(data, format)?;
+        let mut outputs = self.outputs.lock().unwrap();
+        if outputs.keeps(compressed.data.len()) {
+            outputs.put(key, compressed.clone());
+        }
+        Ok(compressed)
+    }
+
+    fn compress_into(&self, data: &[u8], format: Format, dest: &mut Vec<u8>) -> Result<()> {
+        let key = (*blake3::hash(data).as_bytes(), format);
+        if self.reuse(&key, data.len(), dest).is_some() {
+            return Ok(());
+        }
+
+        self.inner.compress_into(data, format, dest)?;
+        let mut outputs = self.outputs.lock().unwrap();
+        // only a copy is kept, so `dest` can still be reused
+        if outputs.keeps(dest.len()) {
+            outputs.put(key, Compressed { data: dest.clone(), backend: self.inner.name().to_string(), attempts: Vec::new() });
+        }
+        Ok(())
+    }
+}
+
+/// Compresses each distinct input with `inner` only once, reusing the output for any
+/// identical data that comes later. Every output is kept in memory until this is dropped.
+pub type Dedupe<'a> = Cached<'a, HashMap<Key, Compressed>>;
+
+impl Store for HashMap<Key, Compressed> {
+    const NAME: &'static str = "dedupe";
+    const SUFFIX: &'static str = " (duplicate)";
+
+    fn get(&mut self, key: &Key) -> Option<&Compressed> {
+        HashMap::get(self, key)
+    }
+
+    fn put(&mut self, key: Key, compressed: Compressed) {
+        self.insert(key, compressed);
+    }
+}
+
+impl<'a> Dedupe<'a> {
+    pub fn new(inner: &'a dyn CompressionBackend) -> Self {
+        Cached { inner, outputs: Mutex::new(HashMap::new()), reused: AtomicUsize::new(0) }
+    }
+
+    /// How many inputs were duplicates whose output was reused.
+    pub fn duplicates(&self) -> usize {
+        self.reused.load(Ordering::Relaxed)
+    }
+}
+
+/// Like [`Dedupe`], but only keeps the most recently used outputs, up to `capacity` bytes
+/// of them, so memory stays bounded however much data goes through it. Meant for the
+/// chunks of region files, many of which (unmodified ocean, say) decompress to exactly the
+/// same data, both within a file and across the files of a world. An output larger than
+/// `capacity` isn't kept at all.
+pub type Lru<'a> = Cached<'a, Recent>;
+
+/// The [`Store`] behind [`Lru`].
+pub struct Recent {
+    outputs: lru::LruCache<Key, Compressed>,
+    /// How many bytes of output `outputs` holds in total.
+    size: usize,
+    capacity: usize,
+}
+
+impl Store for Recent {
+    const NAME: &'static str = "lru";
+    const SUFFIX: &'static str = "";
+
+    fn get(&mut self, key: &Key) -> Option<&Compressed> {
+        self.outputs.get(key)
+    }
+
+    fn keeps(&self, len: usize) -> bool {
+        len <= self.capacity
+    }
+
+    /// Evicts the least recently used outputs until `compressed` fits.
+    fn put(&mut self, key: Key, compressed: Compressed) {
+        self.size += compressed.data.len();
+        if let Some(replaced) = self.outputs.put(key, compressed) {
+            self.size -= replaced.data.len();
+        }
+        while self.size > self.capacity {
+            let Some((_, evicted)) = self.outputs.pop_lru() else { break };
+            self.size -= evicted.data.len();
+        }
+    }
+}
+
+impl<'a> Lru<'a> {
+    pub fn new(inner: &'a dyn CompressionBackend, capacity: usize) -> Self {
+        let outputs = Recent { outputs: lru::LruCache::unbounded(), size: 0, capacity };
+        Cached { inner, outputs: Mutex::new(outputs), reused: AtomicUsize::new(0) }
+    }
+
+    /// How many inputs were found in the cache.
+    pub fn hits(&self) -> usize {
+        self.reused.load(Ordering::Relaxed)
+    }
+}
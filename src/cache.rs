@@ -0,0 +1,70 @@
+//! Remembering which files are already as small as a backend can make them.
+//!
+//! The cache maps each path to a fingerprint (length and CRC-32) of its contents and the
+//! backend that last processed it. A file whose fingerprint still matches can be skipped
+//! without decompressing it. The cache is only an optimisation: if it can't be read it is
+//! simply rebuilt.
+
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::file::write_file;
+
+/// The cache's file name, created in the working directory.
+pub const CACHE_FILE: &str = ".nbt-compress-cache.json";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Cache {
+    entries: HashMap<String, Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    len: usize,
+    crc32: u32,
+    backend: String,
+}
+
+impl Entry {
+    fn new(contents: &[u8], backend: &str) -> Self {
+        Entry { len: contents.len(), crc32: libdeflater::crc32(contents), backend: backend.to_string() }
+    }
+}
+
+impl Cache {
+    /// Loads the cache at `path`. A missing, unreadable or corrupt cache loads as empty.
+    pub fn load(path: &Path) -> Cache {
+        std::fs::read(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Writes the cache to `path` atomically, so an interrupted run can't corrupt it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self)?;
+        write_file(&path.to_string_lossy(), &json)
+    }
+
+    /// Whether `file` was last left holding exactly `contents` by `backend`, so
+    /// processing it again can't save anything.
+    pub fn is_optimal(&self, file: &str, contents: &[u8], backend: &str) -> bool {
+        self.entries.get(file).is_some_and(|entry| *entry == Entry::new(contents, backend))
+    }
+
+    /// Records that `backend` can't make `contents` any smaller.
+    pub fn record(&mut self, file: &str, contents: &[u8], backend: &str) {
+        self.entries.insert(file.to_string(), Entry::new(contents, backend));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,554 @@
+//! Command line arguments. Each subcommand has its own flags; running without one
+//! compresses, as before subcommands existed.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::{ArgGroup, Args, ColorChoice, CommandFactory, Parser, Subcommand, ValueEnum};
+use nbt_compress::codec::{Format, IterationStrategy};
+
+/// Recompresses Minecraft NBT files (level.dat, region files, structures, ...) in place to
+/// save space.
+#[derive(Parser)]
+#[command(name = "nbt-compress", version, propagate_version = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// only ever one, parsed once, so its size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum Command {
+    /// Recompress files in place, the default when no subcommand is given
+    Compress(CompressArgs),
+    /// Write a decompressed copy of each file next to it, with .nbt appended
+    Decompress(DecompressArgs),
+    /// Print each file's format and sizes, its contents or its tag counts, without changing it
+    Inspect(InspectArgs),
+    /// Compare sizes and times from several backends without writing anything
+    Benchmark(BenchmarkArgs),
+    /// Check that every file decompresses and is valid NBT, listing those that aren't
+    Check(CheckArgs),
+}
+
+impl Command {
+    pub fn input(&self) -> &InputArgs {
+        match self {
+            Command::Compress(args) => &args.input,
+            Command::Decompress(args) => &args.input,
+            Command::Inspect(args) => &args.input,
+            Command::Benchmark(args) => &args.input,
+            Command::Check(args) => &args.input,
+        }
+    }
+
+    pub fn display(&self) -> &DisplayArgs {
+        match self {
+            Command::Compress(args) => &args.display,
+            Command::Decompress(args) => &args.display,
+            Command::Inspect(args) => &args.display,
+            Command::Benchmark(args) => &args.display,
+            Command::Check(args) => &args.display,
+        }
+    }
+}
+
+/// Which files to work on and how to read them.
+#[derive(Args)]
+pub struct InputArgs {
+    /// Files to process, - reads stdin
+    pub files: Vec<String>,
+    /// Read a file from stdin, like -
+    #[arg(long)]
+    pub stdin: bool,
+    /// Read paths to process from a file, one per line, or - for stdin
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Vec<String>,
+    /// Process the files inside directories
+    #[arg(short, long)]
+    pub recursive: bool,
+    /// Follow symlinks with -r, visiting each directory only once
+    #[arg(long)]
+    pub follow_symlinks: bool,
+    /// File extensions to pick up with -r
+    #[arg(long, value_name = "EXT,...", value_delimiter = ',')]
+    pub ext: Option<Vec<String>>,
+    /// Pick up backups like level.dat_old and *.bak with -r
+    #[arg(long, overrides_with = "exclude_backups")]
+    pub include_backups: bool,
+    /// Leave out backups with -r (default)
+    #[arg(long, overrides_with = "include_backups")]
+    pub exclude_backups: bool,
+    /// Treat every file as a Bedrock Edition level.dat
+    #[arg(long)]
+    pub bedrock: bool,
+    /// Treat files as raw deflate streams without a header
+    #[arg(long)]
+    pub raw: bool,
+    /// Memory-map files instead of reading them
+    #[arg(long)]
+    pub mmap: bool,
+    /// Reject streams that decompress to more than this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = nbt_compress::codec::DEFAULT_MAX_DECOMPRESSED)]
+    pub max_decompressed: usize,
+    /// Retry reading a file, or writing its output, this many times after a transient
+    /// error like EAGAIN or EBUSY, waiting twice as long each time, for network filesystems
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+}
+
+/// How results are printed.
+#[derive(Args)]
+pub struct DisplayArgs {
+    /// Only print errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Print details about each file
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// Print results as JSON
+    #[arg(long)]
+    pub json: bool,
+    /// Print sizes in bytes
+    #[arg(long)]
+    pub bytes: bool,
+    /// Always show a progress bar
+    #[arg(long)]
+    pub progress: bool,
+    /// Color the results: auto colors them on a terminal, unless NO_COLOR is set
+    #[arg(long, value_name = "WHEN", default_value_t = ColorChoice::Auto, overrides_with = "no_color")]
+    pub color: ColorChoice,
+    /// Don't color the results, like --color never
+    #[arg(long, overrides_with = "color")]
+    pub no_color: bool,
+}
+
+/// Where output goes and what happens to the originals.
+#[derive(Args)]
+pub struct WriteArgs {
+    /// Report what would be done without writing anything
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+    /// Write the output to stdout instead of next to or over the file
+    #[arg(short = 'c', long, conflicts_with = "output_dir")]
+    pub stdout: bool,
+    /// Write the output under this directory instead, mirroring the input paths
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<String>,
+    /// Number of threads to work on files and chunks with; 0 or 1 does one at a time
+    /// [default: one per logical CPU]
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+    /// Also write every file's result and the totals to this file, as JSON if it ends in .json
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<String>,
+    /// Skip files finished by an earlier interrupted run
+    #[arg(long)]
+    pub resume: bool,
+    /// Keep each file's modification time and permissions (default)
+    #[arg(long, overrides_with = "no_preserve")]
+    pub preserve: bool,
+    /// Don't keep modification times or permissions
+    #[arg(long, overrides_with = "preserve")]
+    pub no_preserve: bool,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("backend").args(["zopfli", "zstd", "brotli", "best"])))]
+pub struct CompressArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub write: WriteArgs,
+    #[command(flatten)]
+    pub display: DisplayArgs,
+
+    /// Compress with zopfli, which is slower but smaller than libdeflate
+    #[arg(short, long)]
+    pub zopfli: bool,
+    /// Write a zstd copy next to each file, with .zst appended
+    #[arg(long)]
+    pub zstd: bool,
+    /// Write a brotli copy next to each file, with .br appended
+    #[arg(long)]
+    pub brotli: bool,
+    /// Try both libdeflate and zopfli and keep the smaller output
+    #[arg(long)]
+    pub best: bool,
+    /// Write streams in this format, to a new file next to the original if it changes
+    #[arg(long, conflicts_with_all = ["zstd", "brotli"])]
+    pub format: Option<OutputFormat>,
+    /// Give streams that end up in another format this extension instead of the one they
+    /// had, like dat or nbt.gz for raw .nbt files, which are otherwise compressed in place.
+    /// Only for gzip, zlib and raw deflate: zstd and brotli always get .zst or .br
+    /// appended, as does anything whose path wouldn't change. A file that already has the
+    /// new name is never overwritten: the file being converted fails instead
+    #[arg(long, value_name = "EXT")]
+    pub out_ext: Option<String>,
+    /// A named set of defaults for the backend, level, iterations and verification, which
+    /// any of those flags override
+    #[arg(long)]
+    pub profile: Option<Profile>,
+    /// libdeflate compression level [default: 12]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=12))]
+    pub level: Option<u8>,
+    /// brotli quality, implies --brotli
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u32).range(0..=11))]
+    pub brotli_quality: Option<u32>,
+    /// zopfli iterations
+    #[arg(short, long, allow_negative_numbers = true)]
+    pub iterations: Option<i32>,
+    /// Dry run every Nth file only, and estimate the savings over all of them from those
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "stdout")]
+    pub sample: Option<u64>,
+    /// Give zopfli as many iterations as fit in this time per file, implies --zopfli
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, conflicts_with = "reproducible")]
+    pub time_budget: Option<Duration>,
+    /// How to pick zopfli iterations: size (fewer for large inputs), fixed:N or
+    /// time:SECONDS, implies --zopfli
+    #[arg(long, value_name = "STRATEGY", value_parser = parse_strategy, conflicts_with_all = ["iterations", "time_budget"])]
+    pub iteration_strategy: Option<IterationStrategy>,
+    /// Give up on zopfli for a stream or chunk after this long, and use libdeflate instead
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, conflicts_with = "reproducible")]
+    pub zopfli_timeout: Option<Duration>,
+    /// Always use the same zopfli iterations, so output doesn't depend on timing
+    #[arg(long)]
+    pub reproducible: bool,
+    /// Maximum zopfli block splits, 0 for unlimited
+    #[arg(long, value_name = "N", default_value_t = nbt_compress::codec::DEFAULT_BLOCK_SPLITS)]
+    pub block_splits: u16,
+    /// Fall back to libdeflate if the chosen backend fails
+    #[arg(long)]
+    pub fallback: bool,
+    /// Compress identical data only once
+    #[arg(long)]
+    pub dedupe: bool,
+    /// How many bytes of recent region chunk outputs to keep for reuse by identical
+    /// chunks, 0 to turn off. Other files are never kept
+    #[arg(long, value_name = "BYTES", default_value_t = 16 << 20)]
+    pub chunk_cache: usize,
+    /// Only recompress the chunk at these coordinates in region files, may be repeated
+    #[arg(long, value_name = "X,Z", value_parser = parse_chunk, allow_hyphen_values = true, conflicts_with = "strip")]
+    pub chunk: Vec<(i32, i32)>,
+    /// How many files to read ahead of the ones being compressed, and to hold for writing
+    /// after them
+    #[arg(long, value_name = "FILES", default_value_t = 8, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub queue_depth: usize,
+
+    /// Keep a copy of each original with .bak appended
+    #[arg(short, long)]
+    pub backup: bool,
+    /// When a file can't be replaced because another process, like a running server, has
+    /// it open, keep trying for a few seconds before giving up. Windows only
+    #[arg(long)]
+    pub share_aware: bool,
+    /// Don't keep a copy, even if the config file asks for one
+    #[arg(long, conflicts_with_all = ["backup", "backup_suffix"])]
+    pub no_backup: bool,
+    /// Keep a copy of each original with this suffix appended
+    #[arg(long, value_name = "SUFFIX")]
+    pub backup_suffix: Option<String>,
+    /// Only rewrite files that shrink by at least this many bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    pub min_saving: usize,
+    /// Only rewrite files that shrink by at least this percentage
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0, value_parser = parse_percent)]
+    pub min_saving_percent: f64,
+    /// Rewrite files even if the output is larger
+    #[arg(long)]
+    pub keep_larger: bool,
+    /// Report files whose output is identical to them as already optimal. They are never
+    /// rewritten anyway, except with --keep-larger, which this overrides
+    #[arg(long)]
+    pub no_overwrite_identical: bool,
+    /// Skip slow backends on streams that are already near optimal (default)
+    #[arg(long, overrides_with = "no_quick_check")]
+    pub quick_check: bool,
+    /// Always run slow backends
+    #[arg(long, overrides_with = "quick_check")]
+    pub no_quick_check: bool,
+    /// Skip files that libdeflate level 9 can't make any smaller
+    #[arg(long)]
+    pub only_if_improvable: bool,
+    /// Check that the output decompresses back to the same data (default)
+    #[arg(long, overrides_with = "no_verify")]
+    pub verify: bool,
+    /// Don't check that the output decompresses back to the same data
+    #[arg(long, overrides_with = "verify")]
+    pub no_verify: bool,
+    /// Don't check that the data is well-formed NBT
+    #[arg(long)]
+    pub no_validate: bool,
+    /// Drop any junk left after the end of a gzip stream instead of keeping it
+    #[arg(long)]
+    pub trim: bool,
+    /// Keep the file name and comment stored in gzip headers
+    #[arg(long)]
+    pub preserve_gzip_meta: bool,
+    /// Put the data of every gzip member into one, which is smallest (default)
+    #[arg(long, overrides_with = "preserve_members")]
+    pub single_member: bool,
+    /// Keep each gzip member separate, for tools that expect the same member boundaries
+    #[arg(long, overrides_with = "single_member", conflicts_with_all = ["normalize", "strip"])]
+    pub preserve_members: bool,
+    /// Parse the output back and check it holds the same NBT tree, in any key order
+    #[arg(long)]
+    pub show_diff: bool,
+    /// Sort compound keys if that makes the output smaller
+    #[arg(long)]
+    pub normalize: bool,
+    /// Remove the tag at this path, like Data.Player or sections.BlockLight, before
+    /// compressing, may be repeated. The data is lost for good
+    #[arg(long, value_name = "PATH")]
+    pub strip: Vec<String>,
+    /// With --normalize or --strip, parse the output back and warn if its tag count or
+    /// depth changed more than they explain
+    #[arg(long)]
+    pub warn_on_grow_parse: bool,
+    /// Skip files already known to be optimal
+    #[arg(long, overrides_with = "no_cache")]
+    pub cache: bool,
+    /// Don't use the cache (default)
+    #[arg(long, overrides_with = "cache")]
+    pub no_cache: bool,
+}
+
+#[derive(Args)]
+pub struct DecompressArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub write: WriteArgs,
+    #[command(flatten)]
+    pub display: DisplayArgs,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("view").args(["print", "pretty", "stats"])))]
+pub struct InspectArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub display: DisplayArgs,
+
+    /// Print each file as SNBT
+    #[arg(long)]
+    pub print: bool,
+    /// Print each file as indented SNBT
+    #[arg(long)]
+    pub pretty: bool,
+    /// Print how many of each tag type each file holds
+    #[arg(long)]
+    pub stats: bool,
+    /// Print each gzip file's size uncompressed, read from its footer without decompressing
+    #[arg(long, conflicts_with_all = ["print", "pretty", "stats"])]
+    pub sizes: bool,
+    /// Compare each file's size with the file at the same path under this directory, the
+    /// way --output-dir lays them out, and say which tree is smaller. Paths are taken as
+    /// given, so run from inside the first tree with relative paths
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["print", "pretty", "stats", "sizes"])]
+    pub compare: Option<String>,
+}
+
+#[derive(Args)]
+pub struct BenchmarkArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub display: DisplayArgs,
+
+    /// Include zstd
+    #[arg(long)]
+    pub zstd: bool,
+    /// Include brotli
+    #[arg(long)]
+    pub brotli: bool,
+    /// brotli quality, implies --brotli
+    #[arg(long, value_name = "QUALITY", value_parser = clap::value_parser!(u32).range(0..=11))]
+    pub brotli_quality: Option<u32>,
+    /// Maximum zopfli block splits, 0 for unlimited
+    #[arg(long, value_name = "N", default_value_t = nbt_compress::codec::DEFAULT_BLOCK_SPLITS)]
+    pub block_splits: u16,
+    /// Number of threads for the chunks of region files; 0 or 1 does one at a time, for
+    /// stable timings [default: one per logical CPU]
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    #[command(flatten)]
+    pub input: InputArgs,
+    #[command(flatten)]
+    pub display: DisplayArgs,
+
+    /// Number of files to check at once [default: one per logical CPU]
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+    /// Also check that each file decompresses to exactly the same NBT as the file at the
+    /// same path under this directory, like a backup from before compressing, laid out as
+    /// with --output-dir. Files missing there fail
+    #[arg(long, value_name = "DIR")]
+    pub reference: Option<String>,
+}
+
+/// The formats `--format` can pick.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Gzip,
+    Zlib,
+    /// Raw deflate, without any header
+    Raw,
+}
+
+impl From<OutputFormat> for Format {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Gzip => Format::Gzip,
+            OutputFormat::Zlib => Format::Zlib,
+            OutputFormat::Raw => Format::Deflate,
+        }
+    }
+}
+
+/// The profiles `--profile` can pick.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// libdeflate level 6, without verifying the output
+    Fast,
+    /// libdeflate level 9
+    Balanced,
+    /// zopfli with 1000 iterations, verifying the output
+    Max,
+}
+
+impl Profile {
+    /// The libdeflate level, unless `--level` is given.
+    pub fn level(self) -> Option<u8> {
+        match self {
+            Profile::Fast => Some(6),
+            Profile::Balanced => Some(9),
+            Profile::Max => None,
+        }
+    }
+
+    /// The zopfli iterations, for profiles that use zopfli unless another backend is given.
+    pub fn iterations(self) -> Option<i32> {
+        match self {
+            Profile::Max => Some(1000),
+            Profile::Fast | Profile::Balanced => None,
+        }
+    }
+
+    /// Whether to verify the output, unless `--verify` or `--no-verify` is given.
+    pub fn verify(self) -> bool {
+        self != Profile::Fast
+    }
+}
+
+/// The flags that picked a mode before subcommands existed, and the subcommand each now
+/// belongs to. `None` means the flag is dropped, as it is the subcommand's default.
+const LEGACY_MODES: &[(&str, &str, Option<&str>)] = &[
+    ("-d", "decompress", None),
+    ("--decompress", "decompress", None),
+    ("--benchmark", "benchmark", None),
+    ("--check", "check", None),
+    ("--list", "inspect", None),
+    ("--print", "inspect", Some("--print")),
+    ("--pretty", "inspect", Some("--pretty")),
+    ("--stats", "inspect", Some("--stats")),
+    ("--sizes", "inspect", Some("--sizes")),
+    ("--compare", "inspect", Some("--compare")),
+];
+
+/// Inserts a subcommand into `args` if there isn't one, so the old flag-only command lines
+/// keep working: `-d` means `decompress`, `--print` means `inspect --print`, and so on, and
+/// anything else means `compress`. Only flags before the first file or `--` pick the mode,
+/// and a file named like a subcommand needs a path like `./check` unless it's alone.
+pub fn with_default_command(args: Vec<OsString>) -> Vec<OsString> {
+    let Some(first) = args.get(1).and_then(|arg| arg.to_str()) else {
+        return args;
+    };
+    let subcommands = ["compress", "decompress", "inspect", "benchmark", "check", "help"];
+    // the subcommand on its own would have nothing to do, so an existing file is meant
+    let lone_file = args.len() == 2 && Path::new(first).exists();
+    if (subcommands.contains(&first) && !lone_file) || ["-h", "--help", "-V", "--version"].contains(&first) {
+        return args;
+    }
+
+    let takes_value = options_with_values();
+    let mut command = "compress";
+    let mut rest = Vec::new();
+    let mut remaining = args.iter().skip(1);
+    while let Some(arg) = remaining.next() {
+        let Some(flag) = arg.to_str().filter(|arg| arg.starts_with('-') && *arg != "-" && *arg != "--") else {
+            rest.push(arg.clone());
+            break;
+        };
+        match LEGACY_MODES.iter().find(|(legacy, _, _)| flag == *legacy) {
+            Some((_, mode, replacement)) => {
+                command = mode;
+                rest.extend(replacement.map(OsString::from));
+            }
+            None => {
+                rest.push(arg.clone());
+                // the value is passed along as it is, even if it looks like a legacy flag
+                if takes_value.iter().any(|option| option == flag) {
+                    rest.extend(remaining.next().cloned());
+                }
+            }
+        }
+    }
+    rest.extend(remaining.cloned());
+    args.into_iter().take(1).chain([OsString::from(command)]).chain(rest).collect()
+}
+
+/// Every flag of any subcommand that takes its value as the next argument, as `--long`
+/// and `-s`.
+fn options_with_values() -> Vec<String> {
+    let cli = Cli::command();
+    let arguments = cli.get_arguments().chain(cli.get_subcommands().flat_map(|command| command.get_arguments()));
+    arguments
+        .filter(|arg| !arg.is_positional() && arg.get_action().takes_values())
+        .flat_map(|arg| {
+            let long = arg.get_long().map(|long| format!("--{}", long));
+            long.into_iter().chain(arg.get_short().map(|short| format!("-{}", short)))
+        })
+        .collect()
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(secs) if secs >= 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err("needs a number of seconds".to_string()),
+    }
+}
+
+fn parse_strategy(value: &str) -> Result<IterationStrategy, String> {
+    match value.split_once(':') {
+        None if value == "size" => Ok(IterationStrategy::SizeBased),
+        Some(("fixed", count)) => match count.parse() {
+            Ok(count) => Ok(IterationStrategy::Fixed(count)),
+            Err(_) => Err("fixed needs a positive iteration count".to_string()),
+        },
+        Some(("time", seconds)) => parse_seconds(seconds).map(IterationStrategy::TimeBudget),
+        _ => Err("needs size, fixed:N or time:SECONDS".to_string()),
+    }
+}
+
+fn parse_chunk(value: &str) -> Result<(i32, i32), String> {
+    let coordinates = value.split_once(',').map(|(x, z)| (x.trim().parse(), z.trim().parse()));
+    match coordinates {
+        Some((Ok(x), Ok(z))) => Ok((x, z)),
+        _ => Err("needs chunk coordinates as X,Z".to_string()),
+    }
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(percent) if (0.0..100.0).contains(&percent) => Ok(percent),
+        _ => Err("needs a percentage below 100".to_string()),
+    }
+}
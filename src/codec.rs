@@ -0,0 +1,556 @@
+//! gzip, zlib, zstd and brotli encoding and decoding used by the NBT recompression pipeline.
+//!
+//! Every function borrows its input and returns a freshly allocated buffer,
+//! so callers never have to clone the data they want to keep around.
+//!
+//! ```
+//! use nbt_compress::codec::{self, Format};
+//!
+//! let nbt = b"\x0a\x00\x00\x00";
+//! let gzip = codec::compress_libdeflater(nbt, Format::Gzip, 12).unwrap();
+//! assert_eq!(codec::decompress(&gzip).unwrap(), (Format::Gzip, nbt.to_vec()));
+//! ```
+
+use std::io::{Error, ErrorKind::{InvalidData, OutOfMemory, Unsupported}, Read, Result};
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use libdeflater::*;
+
+use crate::error::CorruptData;
+
+/// The container around a deflate stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Gzip,
+    Zlib,
+    /// Plain NBT that was never compressed; "compressing" to it copies the data.
+    Uncompressed,
+    /// Not deflate at all, and not readable by Minecraft. Only [`compress_zstd`] produces it.
+    Zstd,
+    /// Not readable by Minecraft either. Only [`compress_brotli`] produces it, and as it
+    /// has no magic bytes it is never detected, only recognized by a `.br` file name.
+    Brotli,
+    /// A deflate stream with no container at all, as found inside other formats that
+    /// have already unwrapped it. Having no magic bytes, it is never detected, so it has
+    /// to be asked for with [`decompress_as`].
+    Deflate,
+}
+
+/// Identifies the container format from its magic bytes.
+pub fn detect_format(data: &[u8]) -> Option<Format> {
+    match data {
+        [0x1f, 0x8b, ..] => Some(Format::Gzip),
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => Some(Format::Zstd),
+        // zlib: deflate with a 32K window, and the header checksum must hold
+        [cmf @ 0x78, flg, ..] if (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0 => Some(Format::Zlib),
+        // a root compound tag, followed by a name that fits in the data
+        [0x0a, hi, lo, rest @ ..] if usize::from(u16::from_be_bytes([*hi, *lo])) <= rest.len() => {
+            Some(Format::Uncompressed)
+        }
+        _ => None,
+    }
+}
+
+/// Decompresses a gzip, zlib or zstd stream, returning the detected format alongside the data
+/// so it can be recompressed the same way. Uncompressed NBT is returned as-is.
+pub fn decompress(data: &[u8]) -> Result<(Format, Vec<u8>)> {
+    decompress_trailing(data).map(|(format, output, _)| (format, output))
+}
+
+/// Like [`decompress`], but also returns how many bytes at the end of `data` weren't part
+/// of the stream. Decoders skip over these, but they still take up space. Only gzip keeps
+/// track of where its stream ends, so for any other format this is always 0.
+pub fn decompress_trailing(data: &[u8]) -> Result<(Format, Vec<u8>, usize)> {
+    let format = detect_format(data)
+        .ok_or_else(|| Error::new(InvalidData, "Data is not gzip, zlib, zstd or uncompressed NBT"))?;
+    match format {
+        Format::Gzip => decompress_gzip(data).map(|(output, trailing)| (format, output, trailing)),
+        _ => Ok((format, decompress_as(data, format)?, 0)),
+    }
+}
+
+/// Decompresses a stream that is known to be in `format`.
+pub fn decompress_as(data: &[u8], format: Format) -> Result<Vec<u8>> {
+    match format {
+        Format::Uncompressed => return Ok(data.to_vec()),
+        Format::Zstd => {
+            let decoder = zstd::stream::read::Decoder::new(data).map_err(|_| CorruptData { format, offset: 0, decompressed: 0 })?;
+            return read_limited(decoder, format);
+        }
+        Format::Brotli => return read_limited(brotli::Decompressor::new(data, 4096), format),
+        Format::Gzip => return decompress_gzip(data).map(|(output, _)| output),
+        Format::Zlib | Format::Deflate => {}
+    }
+
+    let mut output = Vec::new();
+    decompress_into(data, format, &mut output)?;
+    Ok(output)
+}
+
+/// Like [`decompress_as`], but writes into `dest`, replacing what it held and reusing its
+/// allocation, so that decompressing many small streams like region chunks doesn't
+/// allocate for each one. Only gzip, zlib and raw deflate reuse it.
+pub fn decompress_into(data: &[u8], format: Format, dest: &mut Vec<u8>) -> Result<()> {
+    match format {
+        Format::Gzip => {
+            *dest = decompress_gzip_members(data, std::mem::take(dest))?.0;
+            return Ok(());
+        }
+        Format::Uncompressed | Format::Zstd | Format::Brotli => {
+            *dest = decompress_as(data, format)?;
+            return Ok(());
+        }
+        Format::Zlib | Format::Deflate => {}
+    }
+
+    let mut decompressor = Decompressor::new();
+    dest.clear();
+    dest.resize(initial_buffer_size(data, format), 0);
+    loop {
+        let result = match format {
+            Format::Zlib => decompressor.zlib_decompress(data, dest),
+            Format::Deflate => decompressor.deflate_decompress(data, dest),
+            Format::Gzip | Format::Uncompressed | Format::Zstd | Format::Brotli => unreachable!(),
+        };
+        match result {
+            Ok(len) => {
+                dest.truncate(len);
+                return Ok(());
+            }
+            Err(DecompressionError::InsufficientSpace) => {
+                let size = grown_buffer_size(dest.len())?;
+                log::debug!("{} byte buffer too small for {:?} data, growing to {}", dest.len(), format, size);
+                dest.resize(size, 0);
+            }
+            Err(DecompressionError::BadData) => return Err(CorruptData { format, offset: 0, decompressed: 0 }.into()),
+        }
+    }
+}
+
+/// Decompresses every member of a gzip stream, concatenating their output. Some tools
+/// write several members into one file, and a gzip reader is expected to read them all.
+/// Anything after the last member that isn't another gzip header is ignored, and its
+/// length returned alongside the output.
+fn decompress_gzip(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    decompress_gzip_members(data, Vec::new()).map(|(output, _, trailing)| (output, trailing))
+}
+
+/// How much each member of a gzip stream decompresses to, in order, so the output can be
+/// split the same way. Most files have only one.
+pub fn gzip_member_sizes(data: &[u8]) -> Result<Vec<usize>> {
+    decompress_gzip_members(data, Vec::new()).map(|(_, members, _)| members)
+}
+
+/// [`decompress_gzip`], also returning the size of each member's output. `dest` is used as
+/// the buffer to decompress into, and returned as the output if there is only one member.
+fn decompress_gzip_members(data: &[u8], mut dest: Vec<u8>) -> Result<(Vec<u8>, Vec<usize>, usize)> {
+    let decompressor = GzipDecompressor::new();
+    let mut output = Vec::new();
+    let mut members = Vec::new();
+    let mut rest = data;
+    dest.clear();
+    dest.resize(initial_buffer_size(data, Format::Gzip), 0);
+    loop {
+        match decompressor.decompress_member(rest, &mut dest) {
+            Ok((consumed, written)) => {
+                rest = &rest[consumed..];
+                let more = rest.starts_with(&[0x1f, 0x8b]);
+                members.push(written);
+                if output.is_empty() && !more {
+                    // the common single-member case, which needs no copy
+                    dest.truncate(written);
+                    return Ok((dest, members, rest.len()));
+                }
+                if output.len() + written > max_decompressed() {
+                    return Err(too_large());
+                }
+                output.extend_from_slice(&dest[..written]);
+                if !more {
+                    return Ok((output, members, rest.len()));
+                }
+                log::debug!("gzip member ended with {} bytes left, reading the next one", rest.len());
+            }
+            Err(DecompressionError::InsufficientSpace) => {
+                let size = grown_buffer_size(dest.len())?;
+                log::debug!("{} byte buffer too small for gzip data, growing to {}", dest.len(), size);
+                dest.resize(size, 0);
+            }
+            Err(DecompressionError::BadData) => {
+                let offset = data.len() - rest.len();
+                return Err(CorruptData { format: Format::Gzip, offset, decompressed: output.len() }.into());
+            }
+        }
+    }
+}
+
+/// The optional text fields of a gzip header. libdeflate skips over them when reading and
+/// never writes them, so they are lost on recompression unless put back with
+/// [`set_gzip_header`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GzipHeader {
+    /// FNAME, the original file name, without its terminating zero byte.
+    pub name: Option<Vec<u8>>,
+    /// FCOMMENT, a free-form comment, without its terminating zero byte.
+    pub comment: Option<Vec<u8>>,
+}
+
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+/// The size of a gzip header without any optional fields.
+const GZIP_HEADER_LEN: usize = 10;
+
+/// Reads the name and comment from the header of the first gzip member in `data`, or
+/// `None` if it has neither or isn't a valid gzip header.
+pub fn gzip_header(data: &[u8]) -> Option<GzipHeader> {
+    if !data.starts_with(&[0x1f, 0x8b]) || data.len() < GZIP_HEADER_LEN {
+        return None;
+    }
+    let flags = data[3];
+    let mut rest = &data[GZIP_HEADER_LEN..];
+    if flags & FLAG_EXTRA != 0 {
+        let len = usize::from(u16::from_le_bytes([*rest.first()?, *rest.get(1)?]));
+        rest = rest.get(2 + len..)?;
+    }
+    let mut field = |flag: u8| -> Option<Option<Vec<u8>>> {
+        if flags & flag == 0 {
+            return Some(None);
+        }
+        let end = rest.iter().position(|&b| b == 0)?;
+        let value = rest[..end].to_vec();
+        rest = &rest[end + 1..];
+        Some(Some(value))
+    };
+    let header = GzipHeader { name: field(FLAG_NAME)?, comment: field(FLAG_COMMENT)? };
+    (header.name.is_some() || header.comment.is_some()).then_some(header)
+}
+
+/// Rebuilds the header of `gzip`, a single member as written by any backend, to carry the
+/// fields of `header`. The deflate stream and footer are copied unchanged.
+pub fn set_gzip_header(gzip: &[u8], header: &GzipHeader) -> Result<Vec<u8>> {
+    if !gzip.starts_with(&[0x1f, 0x8b]) || gzip.len() < GZIP_HEADER_LEN || gzip[3] != 0 {
+        return Err(Error::new(InvalidData, "Expected gzip data with a plain header"));
+    }
+    let fields = [(FLAG_NAME, &header.name), (FLAG_COMMENT, &header.comment)];
+    let mut output = Vec::with_capacity(gzip.len() + fields.iter().map(|(_, f)| f.as_ref().map_or(0, |f| f.len() + 1)).sum::<usize>());
+    output.extend_from_slice(&gzip[..GZIP_HEADER_LEN]);
+    for (flag, field) in fields {
+        if let Some(field) = field {
+            if field.contains(&0) {
+                return Err(Error::new(InvalidData, "gzip header fields can't contain zero bytes"));
+            }
+            output[3] |= flag;
+            output.extend_from_slice(field);
+            output.push(0);
+        }
+    }
+    output.extend_from_slice(&gzip[GZIP_HEADER_LEN..]);
+    Ok(output)
+}
+
+extern "C" {
+    // part of libdeflate, but not exposed by libdeflate-sys
+    fn libdeflate_gzip_decompress_ex(
+        decompressor: *mut libdeflate_sys::libdeflate_decompressor,
+        input: *const std::ffi::c_void,
+        in_nbytes: usize,
+        output: *mut std::ffi::c_void,
+        out_nbytes_avail: usize,
+        actual_in_nbytes_ret: *mut usize,
+        actual_out_nbytes_ret: *mut usize,
+    ) -> libdeflate_sys::libdeflate_result;
+}
+
+/// A libdeflate decompressor that, unlike [`Decompressor`], reports how much input each
+/// gzip member used.
+struct GzipDecompressor(*mut libdeflate_sys::libdeflate_decompressor);
+
+impl GzipDecompressor {
+    fn new() -> Self {
+        // SAFETY: allocating has no preconditions; a null result is checked below
+        let decompressor = unsafe { libdeflate_sys::libdeflate_alloc_decompressor() };
+        assert!(!decompressor.is_null(), "libdeflate_alloc_decompressor returned NULL: out of memory");
+        GzipDecompressor(decompressor)
+    }
+
+    /// Decompresses the gzip member at the start of `data` into `out`, returning how many
+    /// bytes of `data` it took up and how many were written to `out`.
+    fn decompress_member(&self, data: &[u8], out: &mut [u8]) -> std::result::Result<(usize, usize), DecompressionError> {
+        let (mut consumed, mut written) = (0, 0);
+        // SAFETY: the pointers and lengths come from valid slices, and the decompressor
+        // is only used from this thread
+        let result = unsafe {
+            libdeflate_gzip_decompress_ex(
+                self.0,
+                data.as_ptr().cast(),
+                data.len(),
+                out.as_mut_ptr().cast(),
+                out.len(),
+                &mut consumed,
+                &mut written,
+            )
+        };
+        match result {
+            libdeflate_sys::libdeflate_result_LIBDEFLATE_SUCCESS => Ok((consumed, written)),
+            libdeflate_sys::libdeflate_result_LIBDEFLATE_INSUFFICIENT_SPACE => Err(DecompressionError::InsufficientSpace),
+            _ => Err(DecompressionError::BadData),
+        }
+    }
+}
+
+impl Drop for GzipDecompressor {
+    fn drop(&mut self) {
+        // SAFETY: the pointer came from libdeflate_alloc_decompressor and is freed once
+        unsafe { libdeflate_sys::libdeflate_free_decompressor(self.0) }
+    }
+}
+
+/// The smallest buffer [`decompress_as`] starts with, so that growing it always makes progress.
+const MIN_BUFFER_SIZE: usize = 1024;
+
+/// Deflate can't expand data by more than this factor, so a larger gzip size footer is bogus.
+const MAX_DEFLATE_RATIO: usize = 1032;
+
+/// No buffer can be larger than this, see [`Vec::with_capacity`].
+const MAX_BUFFER_SIZE: usize = isize::MAX as usize;
+
+/// The default for [`set_max_decompressed`], far more than any real NBT file holds.
+pub const DEFAULT_MAX_DECOMPRESSED: usize = 512 * 1024 * 1024;
+
+static MAX_DECOMPRESSED: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DECOMPRESSED);
+
+/// Sets the most any stream may decompress to, for every function in this module, so that
+/// a small file crafted to expand enormously is rejected instead of exhausting memory.
+pub fn set_max_decompressed(bytes: usize) {
+    MAX_DECOMPRESSED.store(bytes, Ordering::Relaxed);
+}
+
+/// See [`set_max_decompressed`].
+pub fn max_decompressed() -> usize {
+    MAX_DECOMPRESSED.load(Ordering::Relaxed)
+}
+
+fn too_large() -> Error {
+    Error::new(OutOfMemory, format!("Decompressed data is larger than the limit of {} bytes", max_decompressed()))
+}
+
+/// Reads all of `reader`, a decoder for `format`, failing as soon as it gives more than
+/// [`max_decompressed`] bytes.
+fn read_limited(reader: impl Read, format: Format) -> Result<Vec<u8>> {
+    let limit = max_decompressed();
+    let mut output = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(|_| CorruptData { format, offset: 0, decompressed: 0 })?;
+    match output.len() > limit {
+        true => Err(too_large()),
+        false => Ok(output),
+    }
+}
+
+/// Guesses how large the decompressed data will be. gzip records the uncompressed size
+/// (mod 2^32) in its last 4 bytes, which is exact for any sane single-member stream.
+fn initial_buffer_size(data: &[u8], format: Format) -> usize {
+    if format == Format::Gzip && data.len() >= 18 {
+        // on 32-bit targets, a u32 may not fit in an allocation
+        let footer = usize::try_from(u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap())).unwrap_or(usize::MAX);
+        if footer <= data.len().saturating_mul(MAX_DEFLATE_RATIO).min(MAX_BUFFER_SIZE) {
+            return footer.min(max_decompressed());
+        }
+    }
+    data.len().saturating_mul(2).clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE).min(max_decompressed())
+}
+
+/// The size to grow a `len` byte buffer to when the output didn't fit: double, up to the
+/// largest possible allocation and [`max_decompressed`]. A buffer that is already that
+/// large can't grow.
+fn grown_buffer_size(len: usize) -> Result<usize> {
+    if len >= MAX_BUFFER_SIZE {
+        return Err(Error::new(OutOfMemory, "Decompressed data is too large to fit in memory"));
+    }
+    if len >= max_decompressed() {
+        return Err(too_large());
+    }
+    Ok(len.saturating_mul(2).clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE).min(max_decompressed()))
+}
+
+/// Compresses `data` with libdeflate at the given level (0-12).
+pub fn compress_libdeflater(data: &[u8], format: Format, level: u8) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    compress_libdeflater_into(data, format, level, &mut output)?;
+    Ok(output)
+}
+
+/// Like [`compress_libdeflater`], but writes into `dest`, replacing what it held and
+/// reusing its allocation, see [`decompress_into`].
+pub fn compress_libdeflater_into(data: &[u8], format: Format, level: u8, dest: &mut Vec<u8>) -> Result<()> {
+    match format {
+        Format::Uncompressed => {
+            dest.clear();
+            dest.extend_from_slice(data);
+            return Ok(());
+        }
+        Format::Zstd | Format::Brotli => return Err(unsupported("libdeflater", format)),
+        _ => {}
+    }
+
+    let lvl = CompressionLvl::new(level.into())
+        .map_err(|_| Error::new(InvalidData, format!("Invalid compression level: {}", level)))?;
+    let mut compressor = Compressor::new(lvl);
+    let capacity = match format {
+        Format::Gzip => compressor.gzip_compress_bound(data.len()),
+        Format::Zlib => compressor.zlib_compress_bound(data.len()),
+        Format::Deflate => compressor.deflate_compress_bound(data.len()),
+        Format::Uncompressed | Format::Zstd | Format::Brotli => unreachable!(),
+    };
+    dest.clear();
+    dest.resize(capacity, 0);
+    let result = match format {
+        Format::Gzip => compressor.gzip_compress(data, dest),
+        Format::Zlib => compressor.zlib_compress(data, dest),
+        Format::Deflate => compressor.deflate_compress(data, dest),
+        Format::Uncompressed | Format::Zstd | Format::Brotli => unreachable!(),
+    };
+    match result {
+        Ok(len) => {
+            dest.truncate(len);
+            Ok(())
+        }
+        Err(e) => Err(Error::new(InvalidData, e)),
+    }
+}
+
+/// zopfli's own limit on how many blocks [`compress_zopfli`] may split the output into.
+/// More blocks let each use its own Huffman codes, which usually helps up to about this
+/// many; fewer are faster, and 0 means no limit, which can hurt some inputs.
+pub const DEFAULT_BLOCK_SPLITS: u16 = 15;
+
+/// Compresses `data` with zopfli, running exactly `iterations` iterations and splitting
+/// the output into at most `block_splits` blocks, see [`DEFAULT_BLOCK_SPLITS`].
+pub fn compress_zopfli(data: &[u8], format: Format, iterations: NonZeroU64, block_splits: u16) -> Result<Vec<u8>> {
+    match format {
+        Format::Uncompressed => return Ok(data.to_vec()),
+        Format::Zstd | Format::Brotli => return Err(unsupported("zopfli", format)),
+        _ => {}
+    }
+
+    let options = zopfli::Options {
+        iteration_count: iterations,
+        maximum_block_splits: block_splits,
+        ..Default::default()
+    };
+    let zopfli_format = match format {
+        Format::Gzip => zopfli::Format::Gzip,
+        Format::Zlib => zopfli::Format::Zlib,
+        Format::Deflate => zopfli::Format::Deflate,
+        Format::Uncompressed | Format::Zstd | Format::Brotli => unreachable!(),
+    };
+
+    let mut output = Vec::with_capacity(data.len());
+    zopfli::compress(options, zopfli_format, data, &mut output)?;
+    output.shrink_to_fit();
+    Ok(output)
+}
+
+/// Compresses `data` into a zstd frame at the given level (1-22).
+pub fn compress_zstd(data: &[u8], level: i32) -> Result<Vec<u8>> {
+    zstd::bulk::compress(data, level)
+}
+
+/// Compresses `data` into a brotli stream at the given quality (0-11).
+pub fn compress_brotli(data: &[u8], quality: u32) -> Result<Vec<u8>> {
+    let params = brotli::enc::BrotliEncoderParams { quality: quality as i32, ..Default::default() };
+    let mut output = Vec::new();
+    brotli::BrotliCompress(&mut &data[..], &mut output, &params)?;
+    Ok(output)
+}
+
+/// Compresses `data` with zopfli, picking an iteration count from the input size
+/// unless `iterations` is positive.
+pub fn optimise_zopfli(data: &[u8], format: Format, iterations: i32, block_splits: u16) -> Result<Vec<u8>> {
+    let actual_iter = zopfli_iterations(data.len(), iterations);
+    log::debug!("zopfli: {} iterations for {} bytes", actual_iter, data.len());
+    compress_zopfli(data, format, NonZeroU64::new(actual_iter).unwrap(), block_splits)
+}
+
+/// The iteration count [`optimise_zopfli`] uses for `len` bytes of input: `iterations` if
+/// it is positive, otherwise fewer for larger inputs so they finish in reasonable time,
+/// see [`IterationStrategy::SizeBased`].
+pub fn zopfli_iterations(len: usize, iterations: i32) -> u64 {
+    IterationStrategy::from_iterations(iterations).iterations(len).unwrap()
+}
+
+/// Inputs up to this many bytes get [`SMALL_INPUT_ITERATIONS`] with
+/// [`IterationStrategy::SizeBased`], larger ones [`LARGE_INPUT_ITERATIONS`].
+pub const SMALL_INPUT_LEN: usize = 20_000;
+pub const SMALL_INPUT_ITERATIONS: u64 = 500;
+pub const LARGE_INPUT_ITERATIONS: u64 = 100;
+
+/// How many iterations zopfli runs on each stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IterationStrategy {
+    /// Always the same count.
+    Fixed(NonZeroU64),
+    /// Fewer for larger inputs, so they finish in reasonable time, see [`SMALL_INPUT_LEN`].
+    SizeBased,
+    /// As many as fit in this long, see [`zopfli_time_budget`]. The count then depends on
+    /// how fast the machine is, so the output isn't reproducible.
+    TimeBudget(Duration),
+}
+
+impl IterationStrategy {
+    /// The strategy for an iteration count as given to `-i` or in the config file: a
+    /// positive count is fixed, anything else leaves it to the input size.
+    pub fn from_iterations(iterations: i32) -> Self {
+        match NonZeroU64::new(iterations.max(0) as u64) {
+            Some(iterations) => IterationStrategy::Fixed(iterations),
+            None => IterationStrategy::SizeBased,
+        }
+    }
+
+    /// The iteration count for `len` bytes of input, or `None` with a time budget, where
+    /// it is only known after compressing.
+    pub fn iterations(self, len: usize) -> Option<u64> {
+        match self {
+            IterationStrategy::Fixed(iterations) => Some(iterations.get()),
+            IterationStrategy::SizeBased if len > SMALL_INPUT_LEN => Some(LARGE_INPUT_ITERATIONS),
+            IterationStrategy::SizeBased => Some(SMALL_INPUT_ITERATIONS),
+            IterationStrategy::TimeBudget(_) => None,
+        }
+    }
+}
+
+/// Compresses `data` with zopfli, doubling the iteration count after each attempt for as
+/// long as the next attempt is expected to finish within `budget`.
+///
+/// Returns the smallest output found and the iteration count that produced it. At least
+/// one single-iteration attempt is always made, however small the budget.
+pub fn zopfli_time_budget(data: &[u8], format: Format, budget: Duration, block_splits: u16) -> Result<(Vec<u8>, u64)> {
+    let start = Instant::now();
+    let mut iterations = 1;
+    let mut best: Option<(Vec<u8>, u64)> = None;
+
+    loop {
+        let attempt_start = Instant::now();
+        let output = compress_zopfli(data, format, NonZeroU64::new(iterations).unwrap(), block_splits)?;
+        let attempt_time = attempt_start.elapsed();
+
+        // on ties, prefer the later attempt so the reported count reflects the work done
+        if best.as_ref().is_none_or(|(b, _)| output.len() <= b.len()) {
+            best = Some((output, iterations));
+        }
+
+        // zopfli's run time grows roughly linearly with iterations
+        if start.elapsed() + attempt_time * 2 > budget {
+            return Ok(best.unwrap());
+        }
+        iterations *= 2;
+    }
+}
+
+fn unsupported(codec: &str, format: Format) -> Error {
+    Error::new(Unsupported, format!("{} can't produce {:?} output", codec, format))
+}
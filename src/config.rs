@@ -0,0 +1,69 @@
+//! Default flags read from a config file, for settings that are the same on every run.
+//!
+//! The file is TOML, named [`CONFIG_FILE`], and looked for in the working directory and
+//! then in `$HOME/.config`. Only the first one found is used. Flags given on the command
+//! line always win over the config file, which wins over the built-in defaults.
+//!
+//! ```toml
+//! backend = "zopfli"
+//! iterations = 300
+//! threads = 8
+//! backup = true
+//! ```
+
+use std::io::{Error, ErrorKind::InvalidData, Result};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::error::context;
+
+/// The config file's name.
+pub const CONFIG_FILE: &str = "nbt-compress.toml";
+
+/// The backend to use when none is picked on the command line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Libdeflater,
+    Zopfli,
+    Zstd,
+    Brotli,
+    Best,
+}
+
+/// Defaults for command line flags. Anything left out keeps its built-in default.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub backend: Option<Backend>,
+    /// Zopfli iterations, as with `-i`.
+    pub iterations: Option<i32>,
+    /// Worker threads, as with `--threads`: 0 and 1 both mean sequential, and leaving it
+    /// out gives one per logical CPU.
+    pub threads: Option<usize>,
+    /// Back up files before replacing them, as with `--backup`.
+    #[serde(default)]
+    pub backup: bool,
+    /// The backup suffix, as with `--backup-suffix`. Setting it implies `backup`.
+    pub backup_suffix: Option<String>,
+}
+
+impl Config {
+    /// Reads and parses the config file at `path`. Unlike the [`cache`](crate::cache), a
+    /// broken config is an error, as silently ignoring it would change what a run does.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path).map_err(|e| context(e, format!("Error reading {}", path.display())))?;
+        toml::from_str(&text).map_err(|e| {
+            let line = e.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+            Error::new(InvalidData, format!("Invalid config file {} at line {}: {}", path.display(), line, e.message()))
+        })
+    }
+
+    /// Where the config file is, if there is one: [`CONFIG_FILE`] in the working directory,
+    /// or else in `$HOME/.config`.
+    pub fn find() -> Option<PathBuf> {
+        let home = std::env::var_os("HOME").map(|home| Path::new(&home).join(".config").join(CONFIG_FILE));
+        std::iter::once(PathBuf::from(CONFIG_FILE)).chain(home).find(|path| path.is_file())
+    }
+}
@@ -0,0 +1,90 @@
+//! Error details that callers may want to act on, carried inside [`std::io::Error`].
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{Error, ErrorKind, ErrorKind::InvalidData};
+
+use crate::codec::Format;
+
+/// Compressed data that couldn't be decompressed: it was damaged or cut short.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptData {
+    pub format: Format,
+    /// Where the undecodable stream (for gzip, the member) starts in the input.
+    pub offset: usize,
+    /// How many bytes had been decompressed before it.
+    pub decompressed: usize,
+}
+
+impl fmt::Display for CorruptData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Corrupt or truncated {:?} data at byte {}", self.format, self.offset)?;
+        if self.decompressed > 0 {
+            write!(f, " ({} bytes decompressed before it)", self.decompressed)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for CorruptData {}
+
+impl From<CorruptData> for Error {
+    fn from(corrupt: CorruptData) -> Self {
+        Error::new(InvalidData, corrupt)
+    }
+}
+
+/// Whether `e`, or any error it wraps, is [`CorruptData`].
+pub fn is_corrupt(e: &Error) -> bool {
+    let mut source: Option<&(dyn StdError + 'static)> = e.get_ref().map(|e| e as _);
+    while let Some(e) = source {
+        if e.is::<CorruptData>() {
+            return true;
+        }
+        // io::Error's own source() skips over the error it wraps
+        source = match e.downcast_ref::<Error>() {
+            Some(io) => io.get_ref().map(|e| e as _),
+            None => e.source(),
+        };
+    }
+    false
+}
+
+/// Whether `e` may go away if the operation is tried again, like a file that is busy or
+/// a read that would block, as network filesystems sometimes report. Errors like a missing
+/// file or a denied permission are permanent.
+pub fn is_transient(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::ResourceBusy | ErrorKind::Interrupted | ErrorKind::TimedOut)
+}
+
+/// Whether `e` is Windows refusing to replace a file that another process has open, like
+/// the `level.dat` of a running server. Never true elsewhere, where open files can be
+/// replaced.
+pub fn is_sharing_violation(e: &Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    cfg!(windows) && matches!(e.raw_os_error(), Some(32 | 33))
+}
+
+/// Prefixes `e`'s message with `message`, keeping its kind and the original error as the
+/// source, so details like [`CorruptData`] survive.
+pub(crate) fn context(e: Error, message: String) -> Error {
+    Error::new(e.kind(), Context { message, source: e })
+}
+
+#[derive(Debug)]
+struct Context {
+    message: String,
+    source: Error,
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.message, self.source)
+    }
+}
+
+impl StdError for Context {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&self.source)
+    }
+}
@@ -0,0 +1,1271 @@
+//! Recompressing NBT files on disk.
+
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind::{AlreadyExists, InvalidData}, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::backend::{Attempt, CompressionBackend, Compressed, Libdeflater};
+use crate::codec::{self, decompress_as, decompress_trailing, detect_format, Format, GzipHeader};
+use crate::error::{context, is_sharing_violation, is_transient};
+use crate::{nbt, region};
+
+/// The file name that stands for stdin. Its output always goes to stdout.
+pub const STDIN: &str = "-";
+
+/// Settings that control what [`compress_file`] does with its result.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Compress as usual, but never write the result back.
+    pub dry_run: bool,
+    /// Copy the original to a file with this suffix before overwriting it.
+    pub backup: Option<String>,
+    /// Check that the output decompresses to the original data before keeping it. With
+    /// [`normalize`](Self::normalize), that is the data with its keys sorted, so the
+    /// output's tree is also checked against the original's in any key order, see
+    /// [`nbt::Equality::Normalized`].
+    pub verify: bool,
+    /// Check that the decompressed data is well-formed NBT before compressing it, see
+    /// [`nbt::validate`].
+    pub validate: bool,
+    /// Keep the original's modification time and permissions when replacing it.
+    pub preserve: bool,
+    /// Sort compound keys before compressing, see [`nbt::normalize`]. This is lossless for
+    /// Minecraft, but the key order in the file changes, so the output no longer
+    /// decompresses to the original bytes. Each stream is compressed both ways and the
+    /// smaller output kept. Region files are not normalized.
+    pub normalize: bool,
+    /// Memory-map files of at least [`MMAP_THRESHOLD`] bytes instead of reading them into
+    /// memory, see [`read_contents`].
+    pub mmap: bool,
+    /// Treat every file as a Bedrock Edition `level.dat`, for when the header isn't
+    /// recognized automatically. These are only validated, see [`compress_file`].
+    pub bedrock: bool,
+    /// Write the result to stdout instead of replacing the file, which is never touched.
+    /// If recompressing doesn't help, the original bytes are written unchanged.
+    pub stdout: bool,
+    /// Only write the output if it saves more than this many bytes, so files that are
+    /// already compressed about as well as they can be aren't rewritten for nothing.
+    pub min_saving: usize,
+    /// Only write the output if it saves more than this percentage of the original size.
+    pub min_saving_percent: f64,
+    /// Write the output even if it is no smaller, or larger, than the original, for
+    /// converting to another format on purpose. Bedrock files are still left alone.
+    pub keep_larger: bool,
+    /// Compare the output of a file compressed in place with the file itself, and report
+    /// it as already optimal if they are byte for byte the same: the report's
+    /// [`skip_reason`](CompressionReport::skip_reason) is then [`IDENTICAL`]. Such a file
+    /// saves nothing, so it would only be rewritten with [`keep_larger`](Self::keep_larger),
+    /// which this overrides to leave its modification time alone.
+    pub skip_identical: bool,
+    /// Only recompress the chunks in these header slots of region files, see
+    /// [`region::chunk_index`] and [`region::recompress_chunks`]; all of them if empty.
+    /// Other files are recompressed as usual.
+    pub chunks: Vec<usize>,
+    /// Before running a slow backend like zopfli on a stream, check whether libdeflate can
+    /// make it noticeably smaller, and leave it alone if not, see [`QUICK_CHECK_MARGIN`].
+    /// Region files and format conversions aren't checked.
+    pub quick_check: bool,
+    /// Before running any backend, recompress with libdeflate at level 9 and leave the
+    /// file alone unless that is smaller than it already is, reporting the gap in
+    /// [`CompressionReport::skip_reason`]. Stricter than [`quick_check`](Self::quick_check),
+    /// which it replaces: it also applies to fast backends and region files, whose chunks
+    /// are compared in total. Format conversions aren't checked.
+    pub only_if_improvable: bool,
+    /// Treat every file that isn't a region file as raw deflate, which has no header to
+    /// detect it by, see [`Format::Deflate`]. The output is raw deflate too.
+    pub raw: bool,
+    /// Write results under this directory instead of replacing files, which are never
+    /// touched, see [`mirrored_path`]. If recompressing doesn't help, the original is
+    /// copied, so the directory ends up with every file.
+    pub output_dir: Option<String>,
+    /// Drop any bytes after the end of a gzip stream, which some buggy writers leave
+    /// behind, see [`CompressionReport::trailing`]. Without it they are copied to the end
+    /// of the output, unless it is in another format.
+    pub trim: bool,
+    /// Parse the output back into an NBT tree and check it against the original's, see
+    /// [`nbt::first_difference`]. Unlike [`verify`](Self::verify), this still passes when
+    /// [`normalize`](Self::normalize) has reordered keys. Region files and Bedrock files
+    /// aren't checked.
+    pub show_diff: bool,
+    /// Copy the name and comment from the original gzip header into the output, see
+    /// [`CompressionReport::gzip_header`]. Without it they are dropped, as no backend
+    /// writes them.
+    pub preserve_gzip_meta: bool,
+    /// Compress each member of a multi-member gzip file separately, so the output has the
+    /// same member boundaries, for tools that rely on them. Without it, every member's data
+    /// goes into one member, which is smaller as matches can reach across the boundaries
+    /// and there is only one header and footer. This costs a second pass to find the
+    /// boundaries, and [`normalize`](Self::normalize) is ignored for files with more than
+    /// one member, as reordering keys would move data across them. For the same reason,
+    /// files that lost tags to [`strip`](Self::strip) get one member.
+    pub preserve_members: bool,
+    /// Remove the tags at these paths before compressing, see [`nbt::strip`], which loses
+    /// data for good. Streams are verified against the stripped data rather than the
+    /// original, and region files that lost any tags are only checked to hold valid NBT,
+    /// as their chunks no longer match.
+    pub strip: Vec<String>,
+    /// With [`normalize`](Self::normalize) or [`strip`](Self::strip), parse the output back
+    /// and compare its [`nbt::stats`] with the input's, reporting any change those don't
+    /// explain in [`CompressionReport::tag_warning`]. Normalizing shouldn't change the
+    /// number of tags of any type or how deep they nest, and stripping should only remove
+    /// tags. This catches data lost by mistake when the output is still the right size.
+    /// Region files aren't checked.
+    pub warn_on_grow_parse: bool,
+    /// Write streams in this format rather than the one they were in. A stream that ends
+    /// up in another format is written to [`converted_path`] instead of replacing the
+    /// original, and is verified even without [`verify`](Self::verify). Region files keep
+    /// each chunk's compression type, and Bedrock files are left alone as usual.
+    pub format: Option<Format>,
+    /// The extension for streams that end up in another format than they were in, like
+    /// raw NBT that gets gzip'd, see [`mapped_path`]. Without it, raw NBT is compressed in
+    /// place and conversions get the format's extension appended, see [`converted_path`].
+    /// Either way, an existing file at the new path is never replaced; the file fails instead.
+    pub out_ext: Option<String>,
+    /// How many times to retry reading a file, or writing its output, after a transient
+    /// error, see [`with_retries`]. Permanent errors fail the file straight away.
+    pub retries: u32,
+    /// When a file can't be replaced because another process has it open, which only
+    /// happens on Windows, wait [`SHARE_DELAY`] and try again, up to [`SHARE_RETRIES`]
+    /// times, for a server that only holds it open for a moment while saving. Without it,
+    /// or once those run out, the file fails with an error saying it is in use.
+    pub share_aware: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            dry_run: false,
+            backup: None,
+            verify: true,
+            validate: true,
+            normalize: false,
+            bedrock: false,
+            mmap: false,
+            preserve: true,
+            stdout: false,
+            min_saving: 0,
+            min_saving_percent: 0.0,
+            keep_larger: false,
+            skip_identical: false,
+            chunks: Vec::new(),
+            quick_check: true,
+            only_if_improvable: false,
+            raw: false,
+            output_dir: None,
+            trim: false,
+            show_diff: false,
+            preserve_gzip_meta: false,
+            preserve_members: false,
+            strip: Vec::new(),
+            warn_on_grow_parse: false,
+            format: None,
+            out_ext: None,
+            retries: 0,
+            share_aware: false,
+        }
+    }
+}
+
+/// What [`compress_file`] or [`decompress_file`] did with one file.
+#[derive(Clone, Debug)]
+pub struct CompressionReport {
+    pub path: String,
+    pub original: usize,
+    /// The size of the recompressed output, which is only written if it is smaller. For
+    /// [`decompress_file`], the size of the plain NBT. With [`Options::chunks`], the
+    /// original's size less the chunk payload bytes freed, as the file keeps its size.
+    pub compressed: usize,
+    /// The time spent compressing, not counting reading, verifying or writing.
+    pub elapsed: Duration,
+    /// The backend whose output was used.
+    pub backend: String,
+    /// The input's format, or `None` for region files, whose chunks each have their own.
+    pub format: Option<Format>,
+    /// The size of the plain NBT data, in total over the recompressed chunks for region
+    /// files. `None` if it wasn't decompressed, like region files only recompressed in
+    /// part with [`Options::chunks`].
+    pub decompressed: Option<usize>,
+    /// Every compression attempt, see [`Compressed::attempts`](crate::backend::Compressed::attempts).
+    /// Empty for region files, which run one per chunk.
+    pub attempts: Vec<Attempt>,
+    /// With [`Options::normalize`], how many bytes smaller the output was for the
+    /// normalized data than for the original; negative if normalizing didn't help, in
+    /// which case the original order was kept.
+    pub normalization_saved: Option<i64>,
+    /// Whether the output was parsed and found to hold the same tree as the original, see
+    /// [`Options::show_diff`]. A different tree fails the file instead.
+    pub tree_checked: bool,
+    /// How many bytes followed the end of the compressed stream, which are kept unless
+    /// [`Options::trim`] is set.
+    pub trailing: usize,
+    /// The name and comment in the original's gzip header, if it had either, which are
+    /// only kept with [`Options::preserve_gzip_meta`].
+    pub gzip_header: Option<GzipHeader>,
+    /// Why the backend wasn't run at all, see [`Options::quick_check`].
+    pub skip_reason: Option<String>,
+    /// How many bytes of NBT [`Options::strip`] removed.
+    pub stripped_bytes: usize,
+    /// With [`Options::warn_on_grow_parse`], how the output's tags differ from what
+    /// normalizing and stripping should have left, if they do. The output is still written.
+    pub tag_warning: Option<String>,
+    /// Whether the output was written, to disk or stdout. It isn't if it was no smaller,
+    /// saved less than [`Options::min_saving`] or [`Options::min_saving_percent`], or with
+    /// [`Options::dry_run`]. With [`Options::keep_larger`], it may be larger than the
+    /// original.
+    pub written: bool,
+    /// Whether the output goes to the original's own path, so that writing it replaces the
+    /// original. Not for conversions to a new name, [`Options::output_dir`] or stdout.
+    pub in_place: bool,
+}
+
+impl CompressionReport {
+    /// A report for a file that was left alone without being processed, such as one the
+    /// [`cache`](crate::cache) knows can't be improved.
+    pub fn skipped(path: &str, len: usize, backend: &str) -> Self {
+        CompressionReport {
+            path: path.to_string(),
+            original: len,
+            compressed: len,
+            elapsed: Duration::ZERO,
+            backend: backend.to_string(),
+            format: None,
+            decompressed: None,
+            attempts: Vec::new(),
+            normalization_saved: None,
+            skip_reason: None,
+            stripped_bytes: 0,
+            tag_warning: None,
+            tree_checked: false,
+            trailing: 0,
+            gzip_header: None,
+            written: false,
+            in_place: false,
+        }
+    }
+
+    /// How many bytes smaller the output is than the original.
+    pub fn saved(&self) -> usize {
+        self.original.saturating_sub(self.compressed)
+    }
+
+    /// Like [`saved`](Self::saved), but negative if a larger output was written anyway
+    /// with [`Options::keep_larger`].
+    pub fn net_saved(&self) -> i64 {
+        match self.written {
+            true => self.original as i64 - self.compressed as i64,
+            false => self.saved() as i64,
+        }
+    }
+}
+
+/// Recompresses `file` with `backend`, only writing the result if it is smaller by more
+/// than [`Options::min_saving`] and [`Options::min_saving_percent`].
+///
+/// Region files (`.mca` and `.mcr`) are recompressed chunk by chunk; anything else is treated as a
+/// single gzip or zlib stream and recompressed in the same format. Uncompressed NBT is
+/// gzip'd. The result replaces the original, unless the backend produces a format
+/// Minecraft can't read, see [`output_path`], or [`Options::output_dir`] is set. Bedrock Edition's `level.dat` has to stay
+/// uncompressed, so it is validated (as little-endian NBT) and otherwise left alone.
+///
+/// Nothing is printed, and nothing is written with [`Options::dry_run`]; errors carry the
+/// file name and the step that failed.
+pub fn compress_file(file: &str, backend: &dyn CompressionBackend, options: &Options) -> Result<CompressionReport> {
+    compress_contents(file, read_input(file, options)?, backend, options)
+}
+
+/// Like [`compress_file`], but with `file` already read by [`read_input`], so that reading
+/// the next files can overlap with compressing this one.
+pub fn compress_contents(file: &str, contents: Contents, backend: &dyn CompressionBackend, options: &Options) -> Result<CompressionReport> {
+    let (report, output) = compress_deferred(file, contents, backend, options)?;
+    output.write(options)?;
+    Ok(report)
+}
+
+/// Output from [`compress_deferred`] that hasn't been written yet.
+pub struct PendingWrite {
+    file: String,
+    output: String,
+    original: Contents,
+    data: Vec<u8>,
+    improved: bool,
+    in_place: bool,
+    /// Whether `output` is the backend's usual [`output_path`], rather than a new name from
+    /// [`Options::format`] or [`Options::out_ext`] that may belong to another file.
+    replaces: bool,
+}
+
+/// Like [`compress_contents`], but leaves the writing to [`PendingWrite::write`], so that
+/// it can happen on another thread while the next file is compressed. The report says
+/// what will have happened once the write succeeds.
+pub fn compress_deferred(file: &str, contents: Contents, backend: &dyn CompressionBackend, options: &Options) -> Result<(CompressionReport, PendingWrite)> {
+    let original_len = contents.len();
+    let start_time = Instant::now();
+
+    // kept for verification, so the original doesn't have to be decompressed twice
+    let mut uncompressed = None;
+    let mut input_format = None;
+    let mut normalization_saved = None;
+    let mut rewritable = true;
+    let mut skip_reason = None;
+    let mut trailing = 0;
+    let mut gzip_header = None;
+    let mut tree_checked = false;
+    let mut region_decompressed = None;
+    let mut stripped_bytes = 0;
+    let mut tag_warning = None;
+    let mut freed_bytes = None;
+    // minutes of zopfli shouldn't go to waste on a read-only directory
+    if backend.is_slow() && !options.dry_run && !options.stdout && file != STDIN {
+        // only a guess at the format, which doesn't change the directory
+        let format = match options.raw {
+            true => Some(Format::Deflate),
+            false => detect_format(&contents).filter(|_| !region::is_region_file(file)),
+        };
+        let output = destination(file, format, backend, options);
+        let output = match &options.output_dir {
+            Some(dir) => mirrored_path(dir, &output),
+            None => output,
+        };
+        check_writable(&output).map_err(|e| context(e, format!("Error writing to {}, not compressed", output)))?;
+    }
+    let compressed = if region::is_region_file(file) {
+        match backend.output_format() {
+            Some(format) => Err(Error::new(InvalidData, format!("{:?} can't be stored in region files", format))),
+            None if options.only_if_improvable && options.chunks.is_empty() && options.strip.is_empty() => improvable_region_check(&contents).and_then(|skip| match skip {
+                Some((reason, attempt)) => {
+                    log::debug!("{}: {}", file, reason);
+                    skip_reason = Some(reason);
+                    Ok(Compressed { data: contents.to_vec(), backend: "none".to_string(), attempts: vec![attempt] })
+                }
+                None => region::recompress_sized(&contents, backend).map(|(data, size)| {
+                    region_decompressed = Some(size);
+                    Compressed { data, backend: backend.name().to_string(), attempts: Vec::new() }
+                }),
+            }),
+            None if !options.chunks.is_empty() => region::recompress_chunks(&contents, backend, &options.chunks).map(|(data, freed)| {
+                freed_bytes = Some(freed);
+                Compressed { data, backend: backend.name().to_string(), attempts: Vec::new() }
+            }),
+            None => region::recompress_stripped(&contents, backend, &options.strip).map(|(data, size, removed)| {
+                region_decompressed = Some(size);
+                stripped_bytes = removed;
+                Compressed { data, backend: backend.name().to_string(), attempts: Vec::new() }
+            }),
+        }
+    } else if options.bedrock || (!options.raw && nbt::bedrock_payload(&contents).is_some()) {
+        // Bedrock reads level.dat uncompressed, so there is nothing to gain, and gzipping it
+        // would break the world. It is only checked, and never rewritten.
+        input_format = Some(Format::Uncompressed);
+        rewritable = false;
+        let check = if options.validate { nbt::validate_bedrock(&contents) } else { Ok(()) };
+        check.map(|()| Compressed { data: contents.to_vec(), backend: "none (Bedrock)".to_string(), attempts: Vec::new() })
+    } else {
+        decompress_input(file, &contents, options).and_then(|(format, mut data, garbage)| {
+            input_format = Some(format);
+            trailing = garbage;
+            log::debug!("{}: {:?}, {} bytes decompressed", file, format, data.len());
+            if trailing > 0 {
+                log::debug!("{}: {} bytes of trailing garbage after the {:?} stream", file, trailing, format);
+            }
+            if format == Format::Gzip {
+                gzip_header = codec::gzip_header(&contents);
+                if let Some(header) = &gzip_header {
+                    log::debug!("{}: gzip header has {:?}", file, header);
+                }
+            }
+            if options.validate {
+                nbt::validate(&data)?;
+            }
+            let transformed = options.normalize || !options.strip.is_empty();
+            let original_stats = (options.warn_on_grow_parse && transformed).then(|| nbt::stats(&data)).transpose()?;
+            // before the checks below, so they judge what would be compressed
+            let mut removed = 0;
+            let mut removed_tags = 0;
+            if !options.strip.is_empty() {
+                if let Some((stripped, tags)) = nbt::strip(&data, &options.strip)? {
+                    log::debug!("{}: stripped {} tags", file, tags);
+                    removed = data.len() - stripped.len();
+                    removed_tags = tags;
+                    data = Cow::Owned(stripped);
+                }
+            }
+            let target = options.format.unwrap_or_else(|| target_format(backend, format));
+            let check = if options.keep_larger || target != format {
+                None
+            } else if options.only_if_improvable {
+                improvable_check(&data, format, original_len)?
+            } else if options.quick_check && backend.is_slow() {
+                quick_check(&data, format, original_len)?
+            } else {
+                None
+            };
+            if let Some((reason, attempt)) = check {
+                log::debug!("{}: {}", file, reason);
+                skip_reason = Some(reason);
+                uncompressed = Some(data);
+                return Ok(Compressed { data: contents.to_vec(), backend: "none".to_string(), attempts: vec![attempt] });
+            }
+            stripped_bytes = removed;
+            // stripping moves the boundaries, so the data goes into one member after all
+            let members = match (options.preserve_members && removed == 0, format, target) {
+                (true, Format::Gzip, Format::Gzip) => codec::gzip_member_sizes(&contents)?,
+                _ => Vec::new(),
+            };
+            let format = target;
+            let original_tree = (options.show_diff || options.verify && options.normalize).then(|| nbt::parse(&data)).transpose()?;
+            let mut result = if members.len() > 1 {
+                log::debug!("{}: compressing {} gzip members separately", file, members.len());
+                compress_members(&data, &members, backend)?
+            } else {
+                backend.compress_traced(&data, format)?
+            };
+            if options.normalize && members.len() <= 1 {
+                let normalized = nbt::normalize(&data)?;
+                let sorted = backend.compress_traced(&normalized, format)?;
+                normalization_saved = Some(result.data.len() as i64 - sorted.data.len() as i64);
+                let mut attempts = std::mem::take(&mut result.attempts);
+                attempts.extend(sorted.attempts.iter().cloned());
+                if sorted.data.len() < result.data.len() {
+                    data = Cow::Owned(normalized);
+                    result = sorted;
+                }
+                result.attempts = attempts;
+            }
+            if let Some(original) = original_tree {
+                let mode = if options.normalize { nbt::Equality::Normalized } else { nbt::Equality::Strict };
+                check_tree(&original, &result.data, format, mode)?;
+                // only reported when asked for, verifying normalized output always does this
+                tree_checked = options.show_diff;
+            }
+            if let Some(before) = original_stats {
+                let after = nbt::stats(&decompress_as(&result.data, format)?)?;
+                tag_warning = tag_count_check(&before, &after, removed_tags);
+                if let Some(warning) = &tag_warning {
+                    log::debug!("{}: {}", file, warning);
+                }
+            }
+            if let (Some(header), true, Format::Gzip) = (&gzip_header, options.preserve_gzip_meta, format) {
+                result.data = codec::set_gzip_header(&result.data, header)?;
+            }
+            // only kept in the same format, as other decoders might not skip over it
+            if !options.trim && format == input_format.unwrap() {
+                result.data.extend_from_slice(&contents[contents.len() - trailing..]);
+            }
+            uncompressed = Some(data);
+            Ok(result)
+        })
+    }
+    .map_err(|e| context(e, format!("Error compressing {} with {}", file, backend.name())))?;
+
+    let elapsed_time = start_time.elapsed();
+    let optimized_contents = &compressed.data;
+
+    // streams converted with Options::format go to a new file, and are always verified
+    let stream_format = input_format.filter(|_| uncompressed.is_some());
+    let converted = match (options.format, stream_format) {
+        (Some(target), Some(format)) if target != format => Some(target),
+        _ => None,
+    };
+    let output = destination(file, stream_format, backend, options);
+
+    // chunks recompressed in place leave the file its size, but free space inside it
+    let saved_space = freed_bytes.unwrap_or_else(|| original_len.saturating_sub(optimized_contents.len()));
+    let in_place = !options.stdout && file != STDIN && options.output_dir.is_none() && output == file;
+    // checked first, so the file is reported as optimal whether or not it would be written
+    let identical = options.skip_identical && in_place && optimized_contents[..] == contents[..];
+    if identical && skip_reason.is_none() {
+        skip_reason = Some(IDENTICAL.to_string());
+    }
+    let improved = !identical
+        && ((saved_space > 0
+            && saved_space > options.min_saving
+            && saved_space as f64 * 100.0 / original_len as f64 > options.min_saving_percent)
+            || (options.keep_larger && rewritable)
+            || converted.is_some());
+    if improved && (options.verify || converted.is_some()) {
+        match &uncompressed {
+            Some(expected) => {
+                let format = options.format.unwrap_or_else(|| target_format(backend, input_format.unwrap()));
+                verify_stream(expected, optimized_contents, format)
+            }
+            None if stripped_bytes > 0 => region::inspect(optimized_contents).map(|_| ()),
+            None => region::verify(&contents, optimized_contents),
+        }
+        .map_err(|e| context(e, format!("Error verifying {}, not written", file)))?;
+    }
+
+    let report = CompressionReport {
+        path: file.to_string(),
+        original: original_len,
+        compressed: freed_bytes.map_or(optimized_contents.len(), |freed| original_len - freed),
+        elapsed: elapsed_time,
+        backend: compressed.backend,
+        format: input_format,
+        decompressed: uncompressed.as_ref().map(|data| data.len()).or(region_decompressed),
+        attempts: compressed.attempts,
+        normalization_saved,
+        skip_reason,
+        stripped_bytes,
+        tag_warning,
+        tree_checked,
+        trailing,
+        gzip_header,
+        written: improved && !options.dry_run,
+        in_place,
+    };
+    let replaces = output == output_path(file, backend);
+    let output = PendingWrite { file: file.to_string(), output, original: contents, data: compressed.data, improved, in_place, replaces };
+    Ok((report, output))
+}
+
+impl PendingWrite {
+    /// Writes the output where [`compress_deferred`] decided it goes, with the same
+    /// `options` it was given.
+    pub fn write(self, options: &Options) -> Result<()> {
+        let (file, output) = (&self.file[..], &self.output);
+        if options.dry_run {
+            // nothing to write
+        } else if options.stdout || file == STDIN {
+            let output = if self.improved { &self.data[..] } else { &self.original[..] };
+            std::io::stdout().lock().write_all(output)
+                .map_err(|e| context(e, format!("Error writing {} to stdout", file)))?;
+        } else if let Some(dir) = &options.output_dir {
+            let (output, data) = match self.improved {
+                true => (mirrored_path(dir, output), &self.data[..]),
+                false => (mirrored_path(dir, file), &self.original[..]),
+            };
+            write_mirrored(file, &output, data, options.preserve, options.retries).map_err(|e| match self.improved {
+                true => rescue(file, &output, data, e),
+                false => e,
+            })?;
+        } else if self.improved {
+            // a converted stream's new name can be an unrelated file's, which is kept
+            if !self.replaces && Path::new(output).exists() {
+                return Err(Error::new(AlreadyExists, format!("{} already exists, {} not written", output, file)));
+            }
+            if let (Some(suffix), true) = (&options.backup, self.in_place) {
+                backup_file(file, suffix).map_err(|e| context(e, format!("Error backing up {}", file)))?;
+            }
+
+            let metadata = match options.preserve && self.in_place {
+                true => Some(std::fs::metadata(file).map_err(|e| context(e, format!("Error reading metadata of {}", file)))?),
+                false => None,
+            };
+            write_output(output, &self.data, options)
+                .map_err(|e| rescue(file, output, &self.data, context(e, format!("Error writing to {}", output))))?;
+            if let Some(metadata) = metadata {
+                restore_metadata(file, &metadata)
+                    .map_err(|e| context(e, format!("Error restoring modification time and permissions of {}", file)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Recompresses one NBT stream read from `reader` with `backend`, writing it to `writer`,
+/// for callers that get their data from a socket or pipe rather than a file.
+///
+/// The stream is converted the same way [`compress_file`] converts a file, and if
+/// recompressing doesn't make it smaller the original bytes are written instead. The
+/// deflate backends can only compress whole buffers, so the input is read to the end
+/// before anything is written. Region files aren't supported.
+pub fn compress_stream<R: Read, W: Write>(backend: &dyn CompressionBackend, mut reader: R, mut writer: W) -> Result<()> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    let (format, data, _) = decompress_borrowed(&contents)?;
+    let compressed = backend.compress_as(&data, target_format(backend, format))?;
+    log::debug!("stream: {:?}, {} bytes recompressed to {}", format, contents.len(), compressed.len());
+    writer.write_all(if compressed.len() < contents.len() { &compressed } else { &contents })?;
+    writer.flush()
+}
+
+/// How much smaller than the original libdeflate at level 9 has to make a stream for
+/// [`Options::quick_check`] to let a slow backend try, as a fraction of the original.
+pub const QUICK_CHECK_MARGIN: f64 = 0.001;
+
+/// Compresses `data` with libdeflate at level 9, which takes a fraction of the time zopfli
+/// does. If that doesn't beat the `current` size by more than [`QUICK_CHECK_MARGIN`], the
+/// stream is most likely about as small as it gets already, and the reason to skip it is
+/// returned along with the attempt.
+fn quick_check(data: &[u8], format: Format, current: usize) -> Result<Option<(String, Attempt)>> {
+    let start = Instant::now();
+    let size = codec::compress_libdeflater(data, format, 9)?.len();
+    let attempt = Attempt { backend: "libdeflater (level 9, quick check)".to_string(), size, elapsed: start.elapsed() };
+    if (size as f64) < current as f64 * (1.0 - QUICK_CHECK_MARGIN) {
+        return Ok(None);
+    }
+    let reason = format!("libdeflater level 9 made {} bytes of {}, so it is likely optimal already", size, current);
+    Ok(Some((reason, attempt)))
+}
+
+/// Compresses `data` as one gzip member per entry in `members`, the size of each one's
+/// data in order, and concatenates them, see [`Options::preserve_members`].
+fn compress_members(data: &[u8], members: &[usize], backend: &dyn CompressionBackend) -> Result<Compressed> {
+    let mut output = Compressed { data: Vec::new(), backend: backend.name().to_string(), attempts: Vec::new() };
+    if members.iter().sum::<usize>() != data.len() {
+        return Err(Error::new(InvalidData, format!("gzip members hold {} bytes in total, not {}", members.iter().sum::<usize>(), data.len())));
+    }
+    let mut rest = data;
+    for &len in members {
+        let (member, next) = rest.split_at(len);
+        let compressed = backend.compress_traced(member, Format::Gzip)?;
+        output.data.extend_from_slice(&compressed.data);
+        output.attempts.extend(compressed.attempts);
+        output.backend = compressed.backend;
+        rest = next;
+    }
+    Ok(output)
+}
+
+/// For `--only-if-improvable`: recompresses `data` with libdeflater at level 9 and
+/// returns a skip reason if that isn't smaller than the `current` stream. Unlike
+/// [`quick_check`] there is no margin, any saving at all is worth the slow backend.
+fn improvable_check(data: &[u8], format: Format, current: usize) -> Result<Option<(String, Attempt)>> {
+    let start = Instant::now();
+    let size = codec::compress_libdeflater(data, format, 9)?.len();
+    let attempt = Attempt { backend: "libdeflater (level 9, improvable check)".to_string(), size, elapsed: start.elapsed() };
+    if size < current {
+        return Ok(None);
+    }
+    Ok(Some((not_improvable(current, size), attempt)))
+}
+
+/// [`improvable_check`] for region files, comparing the total size of their chunks.
+fn improvable_region_check(data: &[u8]) -> Result<Option<(String, Attempt)>> {
+    let start = Instant::now();
+    let (current, size) = region::payload_sizes(data, &Libdeflater { level: 9 })?;
+    let attempt = Attempt { backend: "libdeflater (level 9, improvable check)".to_string(), size, elapsed: start.elapsed() };
+    if size < current {
+        return Ok(None);
+    }
+    Ok(Some((not_improvable(current, size), attempt)))
+}
+
+fn not_improvable(current: usize, level_9: usize) -> String {
+    format!("not improvable, already {} bytes smaller than libdeflater level 9 ({} vs {} bytes)", level_9 - current, current, level_9)
+}
+
+/// Compresses `file` with each of `backends` in turn, so they can be compared on the same
+/// input. Nothing is written. Region files are recompressed as a whole by every backend
+/// that can be stored in one; the others are skipped.
+///
+/// Returns the original size and one attempt per backend run, in order.
+pub fn benchmark_file(file: &str, backends: &[Box<dyn CompressionBackend>]) -> Result<(usize, Vec<Attempt>)> {
+    let contents = read_file(file).map_err(|e| context(e, format!("Error reading from {}", file)))?;
+    let mut attempts = Vec::new();
+
+    if region::is_region_file(file) {
+        for backend in backends.iter().filter(|b| b.output_format().is_none()) {
+            let start = Instant::now();
+            let output = region::recompress(&contents, backend.as_ref())
+                .map_err(|e| context(e, format!("Error compressing {} with {}", file, backend.name())))?;
+            attempts.push(Attempt { backend: backend.describe(&[]), size: output.len(), elapsed: start.elapsed() });
+        }
+    } else {
+        let (format, data, _) = decompress_borrowed(&contents).map_err(|e| context(e, format!("Error decompressing {}", file)))?;
+        for backend in backends {
+            let compressed = backend.compress_traced(&data, target_format(backend.as_ref(), format))
+                .map_err(|e| context(e, format!("Error compressing {} with {}", file, backend.name())))?;
+            attempts.extend(compressed.attempts);
+        }
+    }
+    Ok((contents.len(), attempts))
+}
+
+/// The format `backend` should produce for a stream that was in `format`: the same one,
+/// unless the backend only has one, and uncompressed data gets gzip'd.
+fn target_format(backend: &dyn CompressionBackend, format: Format) -> Format {
+    match backend.output_format() {
+        Some(format) => format,
+        None if format == Format::Uncompressed => Format::Gzip,
+        None => format,
+    }
+}
+
+/// Where [`compress_file`] writes the output for `file`. That is `file` itself, except
+/// for zstd and brotli output which go next to it with `.zst` or `.br` appended, as
+/// Minecraft can't read either.
+pub fn output_path(file: &str, backend: &dyn CompressionBackend) -> String {
+    match backend.output_format() {
+        Some(Format::Zstd) => format!("{}.zst", file),
+        Some(Format::Brotli) => format!("{}.br", file),
+        _ => file.to_string(),
+    }
+}
+
+/// Where [`compress_file`] writes a stream converted to `format` with [`Options::format`]:
+/// next to `file`, with `.gz`, `.zlib` or `.deflate` appended, so that a file Minecraft
+/// reads in one format is never replaced by another.
+pub fn converted_path(file: &str, format: Format) -> String {
+    let extension = match format {
+        Format::Gzip => "gz",
+        Format::Zlib => "zlib",
+        Format::Deflate => "deflate",
+        Format::Zstd => "zst",
+        Format::Brotli => "br",
+        Format::Uncompressed => "nbt",
+    };
+    format!("{}.{}", file, extension)
+}
+
+/// Where [`compress_file`] writes a stream that ends up in `format`, unlike the input, with
+/// [`Options::out_ext`] set to `out_ext`. Gzip, zlib and raw deflate, which Minecraft
+/// reads, go to `file` with its last extension replaced by `out_ext`, or `out_ext`
+/// appended if it has none: with `dat`, `level.nbt` becomes `level.dat`, and with
+/// `nbt.gz`, `level.nbt.gz`. A leading dot in `out_ext` is ignored.
+///
+/// Zstd and brotli still get `.zst` or `.br` appended, so that nothing Minecraft can't
+/// read ends up with one of its extensions. So does every format without `out_ext`, or if
+/// replacing the extension would give `file` itself, as a file is never replaced by one
+/// in another format; see [`converted_path`].
+pub fn mapped_path(file: &str, format: Format, out_ext: Option<&str>) -> String {
+    let out_ext = out_ext.map(|ext| ext.trim_start_matches('.')).filter(|ext| !ext.is_empty());
+    let (Some(out_ext), Format::Gzip | Format::Zlib | Format::Deflate) = (out_ext, format) else {
+        return converted_path(file, format);
+    };
+    let path = Path::new(file);
+    let mapped = match path.extension() {
+        Some(_) => path.with_extension(out_ext).to_string_lossy().into_owned(),
+        None => format!("{}.{}", file, out_ext),
+    };
+    match mapped == file {
+        true => converted_path(file, format),
+        false => mapped,
+    }
+}
+
+/// Decompresses `file` into a new file at [`decompressed_path`] (under
+/// [`Options::output_dir`] if set), or to stdout with [`Options::stdout`]. The original is
+/// left untouched.
+///
+/// Data that isn't compressed is rejected, as are region files.
+pub fn decompress_file(file: &str, options: &Options) -> Result<CompressionReport> {
+    let contents = with_retries(input_retries(file, options), || read_file(file)).map_err(|e| context(e, format!("Error reading from {}", file)))?;
+    let start_time = Instant::now();
+
+    let (format, uncompressed) = if region::is_region_file(file) {
+        Err(Error::new(InvalidData, "Region files can't be decompressed as a whole"))
+    } else {
+        match decompress_input(file, &contents, options) {
+            Ok((Format::Uncompressed, _, _)) => Err(Error::new(InvalidData, "Data is not compressed")),
+            result => result.map(|(format, data, _)| (format, data.into_owned())),
+        }
+    }
+    .map_err(|e| context(e, format!("Error decompressing {}", file)))?;
+
+    let elapsed_time = start_time.elapsed();
+    let size = uncompressed.len();
+
+    if options.dry_run {
+        // nothing to write
+    } else if options.stdout || file == STDIN {
+        std::io::stdout().lock().write_all(&uncompressed)
+            .map_err(|e| context(e, format!("Error writing {} to stdout", file)))?;
+    } else if let Some(dir) = &options.output_dir {
+        write_mirrored(file, &mirrored_path(dir, &decompressed_path(file)), &uncompressed, false, options.retries)?;
+    } else {
+        let output = decompressed_path(file);
+        write_output(&output, &uncompressed, options).map_err(|e| context(e, format!("Error writing to {}", output)))?;
+    }
+
+    Ok(CompressionReport {
+        path: file.to_string(),
+        original: contents.len(),
+        compressed: size,
+        elapsed: elapsed_time,
+        backend: "decompress".to_string(),
+        format: Some(format),
+        decompressed: Some(size),
+        attempts: Vec::new(),
+        normalization_saved: None,
+        skip_reason: None,
+        stripped_bytes: 0,
+        tag_warning: None,
+        tree_checked: false,
+        trailing: 0,
+        gzip_header: codec::gzip_header(&contents),
+        written: !options.dry_run,
+        in_place: false,
+    })
+}
+
+/// What a file holds, see [`inspect_file`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A single stream in this format.
+    Stream(Format),
+    /// A Bedrock Edition `level.dat`.
+    Bedrock,
+    /// A region file with this many chunks.
+    Region(usize),
+}
+
+/// What [`inspect_file`] found out about one file.
+#[derive(Debug)]
+pub struct FileInfo {
+    /// `None` if the format wasn't recognized.
+    pub kind: Option<Kind>,
+    pub size: usize,
+    /// The size of the plain NBT, in total for region files. `None` if it couldn't be
+    /// decompressed.
+    pub decompressed: Option<usize>,
+    /// Why the file can't be decompressed or isn't valid NBT, or `None` if it is fine.
+    pub problem: Option<Error>,
+}
+
+/// Works out what `file` is, how large it is decompressed, and whether it is valid NBT,
+/// the same way [`compress_file`] would with `options`. Nothing is written.
+///
+/// Only failing to read the file is an error; anything wrong with its contents is
+/// reported in [`FileInfo::problem`].
+pub fn inspect_file(file: &str, options: &Options) -> Result<FileInfo> {
+    let contents = read_input(file, options)?;
+    let mut info = FileInfo { kind: None, size: contents.len(), decompressed: None, problem: None };
+
+    let result = if region::is_region_file(file) {
+        region::inspect(&contents).map(|(chunks, decompressed)| {
+            info.kind = Some(Kind::Region(chunks));
+            info.decompressed = Some(decompressed);
+        })
+    } else if options.bedrock || (!options.raw && nbt::bedrock_payload(&contents).is_some()) {
+        info.kind = Some(Kind::Bedrock);
+        info.decompressed = Some(contents.len());
+        nbt::validate_bedrock(&contents)
+    } else {
+        decompress_input(file, &contents, options).and_then(|(format, data, _)| {
+            info.kind = Some(Kind::Stream(format));
+            info.decompressed = Some(data.len());
+            nbt::validate(&data)
+        })
+    };
+    info.problem = result.err();
+    Ok(info)
+}
+
+/// Compressed files at least this large might hold 4 GiB or more of NBT, at the 16:1 ratio
+/// NBT rarely compresses better than, which the gzip footer can't tell apart from a small
+/// size, see [`FooterSizes::may_wrap`].
+pub const FOOTER_WRAP_RISK: usize = 256 << 20;
+
+/// A gzip file's sizes, as read by [`footer_sizes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FooterSizes {
+    pub compressed: usize,
+    /// The ISIZE field of the footer, which is the uncompressed size modulo 4 GiB.
+    pub uncompressed: u32,
+}
+
+impl FooterSizes {
+    /// Whether [`uncompressed`](Self::uncompressed) may have wrapped around, because it
+    /// is close to 4 GiB or the file is at least [`FOOTER_WRAP_RISK`].
+    pub fn may_wrap(&self) -> bool {
+        self.uncompressed >= 0xC000_0000 || self.compressed >= FOOTER_WRAP_RISK
+    }
+}
+
+/// Reads how large `file` is uncompressed from its gzip footer, without decompressing it:
+/// only the magic bytes at the start and the four bytes at the end are read. Nothing is
+/// checked beyond that, so for a multi-member file this is only the last member's size,
+/// and junk after the stream gives nonsense. Files that aren't gzip are an error.
+pub fn footer_sizes(file: &str) -> Result<FooterSizes> {
+    let read = || {
+        let mut input = std::fs::File::open(file)?;
+        let compressed = input.metadata()?.len() as usize;
+        let mut magic = [0; 2];
+        // a header and a footer, with at least an empty deflate block between them
+        if compressed < 20 || input.read_exact(&mut magic).is_err() || magic != [0x1f, 0x8b] {
+            return Err(Error::new(InvalidData, "Not a gzip file"));
+        }
+        let mut footer = [0; 4];
+        input.seek(SeekFrom::End(-4))?;
+        input.read_exact(&mut footer)?;
+        Ok(FooterSizes { compressed, uncompressed: u32::from_le_bytes(footer) })
+    };
+    read().map_err(|e| context(e, format!("Error reading from {}", file)))
+}
+
+/// Reads, decompresses and parses `file`, which is never modified. Region files, which
+/// hold many trees, are rejected.
+pub fn read_nbt(file: &str) -> Result<(String, nbt::Tag)> {
+    with_nbt(file, nbt::parse_as)
+}
+
+/// Reads and decompresses `file` and tallies where its bytes go, see [`nbt::stats`]. The
+/// file is never modified, and region files are rejected.
+pub fn read_stats(file: &str) -> Result<nbt::Stats> {
+    with_nbt(file, nbt::stats_as)
+}
+
+/// Reads and decompresses `file`, a Java or Bedrock Edition NBT file but not a region
+/// file, and passes the NBT to `f`.
+fn with_nbt<T>(file: &str, f: impl FnOnce(&[u8], nbt::Edition) -> Result<T>) -> Result<T> {
+    let contents = read_file(file).map_err(|e| context(e, format!("Error reading from {}", file)))?;
+    if region::is_region_file(file) {
+        return Err(Error::new(InvalidData, format!("Error parsing {}: Region files hold one tree per chunk", file)));
+    }
+    if let Some(payload) = nbt::bedrock_payload(&contents) {
+        return f(payload, nbt::Edition::Bedrock).map_err(|e| context(e, format!("Error parsing {}", file)));
+    }
+    decompress_borrowed(&contents)
+        .and_then(|(_, data, _)| f(&data, nbt::Edition::Java))
+        .map_err(|e| context(e, format!("Error parsing {}", file)))
+}
+
+/// Where [`decompress_file`] puts the plain NBT for `file`: the same path with `.nbt`
+/// appended, so it can never collide with the original.
+pub fn decompressed_path(file: &str) -> String {
+    format!("{}.nbt", file)
+}
+
+/// Where the output for `path` goes under `dir` with [`Options::output_dir`]: the same
+/// relative path, without any root or `..` so it can't end up outside `dir`.
+pub fn mirrored_path(dir: &str, path: &str) -> String {
+    let mut output = PathBuf::from(dir);
+    output.extend(Path::new(path).components().filter(|c| matches!(c, Component::Normal(_))));
+    output.to_string_lossy().into_owned()
+}
+
+/// The size of `file`, and of its counterpart under `dir` at the same relative path as
+/// with [`Options::output_dir`], see [`mirrored_path`], or `None` if there is none. For
+/// comparing two copies of the same files, so neither is read.
+pub fn compared_sizes(file: &str, dir: &str) -> Result<(u64, Option<u64>)> {
+    let size = std::fs::metadata(file).map_err(|e| context(e, format!("Error reading from {}", file)))?.len();
+    let other = mirrored_path(dir, file);
+    match std::fs::metadata(&other) {
+        Ok(metadata) => Ok((size, Some(metadata.len()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((size, None)),
+        Err(e) => Err(context(e, format!("Error reading from {}", other))),
+    }
+}
+
+/// Writes the output for `file` to `output`, creating its parent directories first. With
+/// `preserve`, `file`'s modification time and permissions are copied over.
+fn write_mirrored(file: &str, output: &str, contents: &[u8], preserve: bool, retries: u32) -> Result<()> {
+    if let Some(parent) = Path::new(output).parent() {
+        std::fs::create_dir_all(parent).map_err(|e| context(e, format!("Error creating directory {}", parent.display())))?;
+    }
+    with_retries(retries, || write_file(output, contents)).map_err(|e| context(e, format!("Error writing to {}", output)))?;
+    if preserve {
+        std::fs::metadata(file)
+            .and_then(|metadata| restore_metadata(output, &metadata))
+            .map_err(|e| context(e, format!("Error copying modification time and permissions of {} to {}", file, output)))?;
+    }
+    Ok(())
+}
+
+/// Where [`compress_file`] writes the output for `file`, before [`Options::output_dir`]:
+/// [`mapped_path`] for a stream in `format` that ends up in another one, and otherwise
+/// [`output_path`]. `format` is `None` for files that aren't recompressed as one stream,
+/// like region files.
+fn destination(file: &str, format: Option<Format>, backend: &dyn CompressionBackend, options: &Options) -> String {
+    let Some(format) = format else { return output_path(file, backend) };
+    let target = options.format.unwrap_or_else(|| target_format(backend, format));
+    match options.out_ext.as_deref() {
+        out_ext if options.format.is_some_and(|target| target != format) => mapped_path(file, target, out_ext),
+        Some(out_ext) if target != format => mapped_path(file, target, Some(out_ext)),
+        _ => output_path(file, backend),
+    }
+}
+
+/// Checks that `path` can be written by creating and removing a temporary file next to
+/// it, like [`write_file`] would, creating its directory first if needed. Existing files,
+/// including `path` itself, are left alone.
+fn check_writable(path: &str) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let (temp_path, _) = create_temp(path)?;
+    std::fs::remove_file(&temp_path)
+}
+
+/// Creates a new file next to `path` to write it through, named after it but under a name
+/// no other file has, so nothing already there is overwritten.
+fn create_temp(path: &str) -> Result<(String, std::fs::File)> {
+    let mut n = 0;
+    loop {
+        let temp_path = format!("{}.{}-{}.tmp", path, std::process::id(), n);
+        match std::fs::File::options().write(true).create_new(true).open(&temp_path) {
+            Ok(file) => return Ok((temp_path, file)),
+            Err(e) if e.kind() == AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Saves `data`, the output for `file` that couldn't be written to `output`, under
+/// [`RESCUE_DIR`] in the system's temporary directory so the work that went into it isn't
+/// lost, and adds where it went to `e`. Nothing is saved if `output` was written after all,
+/// and something after that failed.
+fn rescue(file: &str, output: &str, data: &[u8], e: Error) -> Error {
+    if std::fs::read(output).is_ok_and(|written| written == data) {
+        return e;
+    }
+    let rescued = mirrored_path(&std::env::temp_dir().join(RESCUE_DIR).to_string_lossy(), output);
+    match write_mirrored(file, &rescued, data, false, 0) {
+        Ok(()) => Error::new(e.kind(), format!("{}, the output was saved to {} instead", e, rescued)),
+        Err(_) => e,
+    }
+}
+
+/// The [`CompressionReport::skip_reason`] for a file left alone with
+/// [`Options::skip_identical`].
+pub const IDENTICAL: &str = "already optimal, unchanged";
+
+/// Where outputs that couldn't be written are saved instead, under the system's temporary
+/// directory, mirroring the paths they were meant for.
+pub const RESCUE_DIR: &str = "nbt-compress";
+
+/// Checks that `optimized` holds exactly the same NBT data as `original`, chunk by chunk
+/// for region files.
+pub fn verify(file: &str, original: &[u8], optimized: &[u8]) -> Result<()> {
+    if region::is_region_file(file) {
+        return region::verify(original, optimized);
+    }
+
+    let (format, expected, _) = decompress_borrowed(original)?;
+    verify_stream(&expected, optimized, format)
+}
+
+/// Checks that `file` holds exactly the same NBT data as `reference`, an earlier copy of
+/// it like a backup from before it was recompressed: the same bytes once decompressed,
+/// whatever format each is in, or chunk by chunk for region files, see [`region::verify`].
+/// Both are read the way [`compress_file`] would with `options`. Files that are byte for
+/// byte the same pass without being decompressed.
+pub fn verify_against(file: &str, reference: &str, options: &Options) -> Result<()> {
+    let contents = read_input(file, options)?;
+    let original = read_input(reference, options)?;
+    if contents[..] == original[..] {
+        return Ok(());
+    }
+    if region::is_region_file(file) {
+        return region::verify(&original, &contents).map_err(|e| context(e, format!("{} differs from {}", file, reference)));
+    }
+
+    let (_, expected, _) = decompress_input(reference, &original, options).map_err(|e| context(e, format!("Error decompressing {}", reference)))?;
+    let (_, actual, _) = decompress_input(file, &contents, options).map_err(|e| context(e, format!("Error decompressing {}", file)))?;
+    if expected != actual {
+        let offset = expected.iter().zip(actual.iter()).position(|(a, b)| a != b).unwrap_or(expected.len().min(actual.len()));
+        return Err(Error::new(InvalidData, format!(
+            "{} differs from {} at byte {} of the decompressed data, {} bytes against {}",
+            file, reference, offset, actual.len(), expected.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `optimized`, in `format`, holds the same tree as `original`, compared as
+/// `mode` says.
+fn check_tree(original: &(String, nbt::Tag), optimized: &[u8], format: Format, mode: nbt::Equality) -> Result<()> {
+    let (name, tree) = nbt::parse(&decompress_as(optimized, format)?)?;
+    let difference = match name == original.0 {
+        true => nbt::first_difference_as(&original.1, &tree, mode),
+        false => Some("the root's name".to_string()),
+    };
+    match difference {
+        Some(path) => Err(Error::new(InvalidData, format!("Recompressed NBT differs from the original at {}", path))),
+        None => Ok(()),
+    }
+}
+
+/// Compares the tags of the input, `before`, with the output's, `after`, returning what is
+/// wrong if stripping `removed` tags and normalizing wouldn't have left them that way.
+/// Every removed tag may have taken others with it, so after stripping there can be fewer
+/// tags than that, but no type may have gained any and nothing may nest deeper.
+fn tag_count_check(before: &nbt::Stats, after: &nbt::Stats, removed: usize) -> Option<String> {
+    let expected = before.tags() - removed;
+    let grew = before.counts.iter().zip(&after.counts).any(|(before, after)| after > before);
+    let unexpected = match removed {
+        0 => before.counts != after.counts || before.depth != after.depth,
+        _ => grew || after.tags() > expected || after.depth > before.depth,
+    };
+    if !unexpected {
+        return None;
+    }
+    let bound = if removed == 0 { "" } else { "at most " };
+    Some(format!(
+        "output has {} tags nested {} deep, expected {}{} nested {}{} deep",
+        after.tags(), after.depth, bound, expected, bound, before.depth
+    ))
+}
+
+/// Checks that `optimized`, in `format`, decompresses to exactly `expected`.
+fn verify_stream(expected: &[u8], optimized: &[u8], format: Format) -> Result<()> {
+    // not reported as corrupt, which is about the input
+    let actual = decompress_as(optimized, format)
+        .map_err(|e| Error::new(InvalidData, format!("Recompressed data can't be decompressed: {}", e)))?;
+    if expected != actual {
+        return Err(Error::new(InvalidData, "Recompressed data does not match the original"));
+    }
+    Ok(())
+}
+
+/// Like [`decompress_trailing`], but borrows uncompressed NBT instead of copying it.
+fn decompress_borrowed(data: &[u8]) -> Result<(Format, Cow<'_, [u8]>, usize)> {
+    match detect_format(data) {
+        Some(Format::Uncompressed) => Ok((Format::Uncompressed, Cow::Borrowed(data), 0)),
+        _ => decompress_trailing(data).map(|(format, data, trailing)| (format, Cow::Owned(data), trailing)),
+    }
+}
+
+/// Like [`decompress_borrowed`], but reads raw deflate with [`Options::raw`] or if `file`
+/// ends in `.deflate`, and brotli if it ends in `.br`, as neither can be detected.
+fn decompress_input<'a>(file: &str, data: &'a [u8], options: &Options) -> Result<(Format, Cow<'a, [u8]>, usize)> {
+    let format = match options.raw {
+        true => Format::Deflate,
+        false if file.ends_with(".deflate") => Format::Deflate,
+        false if file.ends_with(".br") => Format::Brotli,
+        false => return decompress_borrowed(data),
+    };
+    decompress_as(data, format).map(|data| (format, Cow::Owned(data), 0))
+}
+
+/// Reads the whole of `path`, or of stdin if it is [`STDIN`].
+pub fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    if path == STDIN {
+        std::io::stdin().lock().read_to_end(&mut contents)?;
+    } else {
+        std::fs::File::open(path)?.read_to_end(&mut contents)?;
+    }
+    Ok(contents)
+}
+
+/// Files smaller than this are always read into memory, which is cheaper than mapping them.
+pub const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// A file's contents, either read into memory or mapped.
+pub enum Contents {
+    Read(Vec<u8>),
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Contents::Read(data) => data,
+            Contents::Mapped(map) => map,
+        }
+    }
+}
+
+/// Like [`read_file`], but with `mmap` a file of at least [`MMAP_THRESHOLD`] bytes is
+/// memory-mapped, so that region files can be sliced into chunks, and large streams such
+/// as `.litematic` files decompressed, without copying the whole file. If mapping fails
+/// the file is read as usual.
+pub fn read_contents(path: &str, mmap: bool) -> Result<Contents> {
+    if mmap && path != STDIN {
+        let file = std::fs::File::open(path)?;
+        if file.metadata()?.len() >= MMAP_THRESHOLD {
+            // SAFETY: the file is only replaced by renaming a new one over it, which leaves
+            // the mapped original intact. Another process truncating it while it is mapped
+            // would be a problem, which is why this is opt-in.
+            if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+                return Ok(Contents::Mapped(map));
+            }
+        }
+    }
+    read_file(path).map(Contents::Read)
+}
+
+/// Reads `file` as [`compress_file`] would, with [`Options::mmap`] and
+/// [`Options::retries`], naming it in any error.
+pub fn read_input(file: &str, options: &Options) -> Result<Contents> {
+    with_retries(input_retries(file, options), || read_contents(file, options.mmap)).map_err(|e| context(e, format!("Error reading from {}", file)))
+}
+
+/// [`Options::retries`] for reading `file`, none for stdin, which can't be read again.
+fn input_retries(file: &str, options: &Options) -> u32 {
+    if file == STDIN { 0 } else { options.retries }
+}
+
+/// How long to wait before the first retry with [`with_retries`]. Each retry after that
+/// waits twice as long as the one before.
+pub const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Runs `f`, and runs it again up to `retries` more times while it fails with an error
+/// that [`is_transient`], waiting [`RETRY_DELAY`] and then twice as long each time. Any
+/// other error, or the last one, is returned as it is.
+pub fn with_retries<T>(retries: u32, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = RETRY_DELAY;
+    for _ in 0..retries {
+        match f() {
+            Err(e) if is_transient(&e) => {
+                log::debug!("{}, retrying in {:?}", e, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    f()
+}
+
+/// How many times [`Options::share_aware`] tries again to replace a file that another
+/// process has open, waiting [`SHARE_DELAY`] before each.
+pub const SHARE_RETRIES: u32 = 10;
+
+/// See [`SHARE_RETRIES`].
+pub const SHARE_DELAY: Duration = Duration::from_secs(1);
+
+/// Replaces `path` with `contents` like [`write_file`], with [`Options::retries`] and
+/// [`Options::share_aware`]. A file another process has open fails with an error saying
+/// so, rather than the bare OS error.
+fn write_output(path: &str, contents: &[u8], options: &Options) -> Result<()> {
+    let mut waited = 0;
+    with_retries(options.retries, || loop {
+        match write_file(path, contents) {
+            Err(e) if is_sharing_violation(&e) && options.share_aware && waited < SHARE_RETRIES => {
+                log::debug!("{} is in use, retrying in {:?}", path, SHARE_DELAY);
+                waited += 1;
+                std::thread::sleep(SHARE_DELAY);
+            }
+            Err(e) if is_sharing_violation(&e) => {
+                return Err(context(e, "The file is in use by another process, stop the server first".to_string()));
+            }
+            result => return result,
+        }
+    })
+}
+
+/// Replaces `path` with `contents` atomically, by writing a new sibling `.tmp` file and
+/// renaming it over the original. If anything fails the original is left untouched.
+pub fn write_file(path: &str, contents: &[u8]) -> Result<()> {
+    let (temp_path, mut file) = create_temp(path)?;
+    let result = (|| {
+        file.write_all(contents)?;
+        file.sync_all()?;
+        // Windows can't rename a file that is still open
+        drop(file);
+        std::fs::rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Sets the modification time and permissions of `path` back to those in `metadata`.
+pub fn restore_metadata(path: &str, metadata: &std::fs::Metadata) -> Result<()> {
+    // the original may be read-only, so the time has to be set first
+    let file = std::fs::File::options().write(true).open(path)?;
+    file.set_modified(metadata.modified()?)?;
+    std::fs::set_permissions(path, metadata.permissions())
+}
+
+/// Copies `path` to `path` + `suffix`, returning the backup's path. An existing backup is
+/// never overwritten; a numbered suffix (`.bak.1`, `.bak.2`, ...) is used instead.
+pub fn backup_file(path: &str, suffix: &str) -> Result<String> {
+    let mut backup_path = format!("{}{}", path, suffix);
+    let mut n = 0;
+    while std::path::Path::new(&backup_path).exists() {
+        n += 1;
+        backup_path = format!("{}{}.{}", path, suffix, n);
+    }
+
+    std::fs::copy(path, &backup_path)?;
+    Ok(backup_path)
+}
@@ -0,0 +1,70 @@
+//! Remembering which files a batch has finished, so an interrupted run can pick up where
+//! it left off.
+//!
+//! Each finished file is appended to the journal as soon as it is done, one path per
+//! line. As files are only ever replaced atomically, every file is either finished and
+//! listed, or untouched, whenever the run stops.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub struct Journal {
+    file: Mutex<File>,
+    done: HashSet<String>,
+}
+
+impl Journal {
+    /// Where the journal for a run with `args` in `dir` is kept by default: a file in the
+    /// temp directory named after a hash of both, so that only the same command run from
+    /// the same place resumes it.
+    pub fn default_path(dir: &Path, args: &[String]) -> PathBuf {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(dir.as_os_str().as_encoded_bytes());
+        for arg in args {
+            hasher.update(b"\0").update(arg.as_bytes());
+        }
+        let hash = hasher.finalize().to_hex();
+        std::env::temp_dir().join(format!("nbt-compress-{}.journal", &hash[..16]))
+    }
+
+    /// Opens the journal at `path`. With `resume`, the files it already lists count as
+    /// done; otherwise it is started afresh.
+    pub fn open(path: &Path, resume: bool) -> Result<Journal> {
+        let done = match resume {
+            true => match std::fs::read_to_string(path) {
+                Ok(text) => text.lines().filter(|line| !line.is_empty()).map(str::to_string).collect(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+                Err(e) => return Err(e),
+            },
+            false => HashSet::new(),
+        };
+        let file = OpenOptions::new().create(true).append(true).truncate(false).open(path)?;
+        if !resume {
+            file.set_len(0)?;
+        }
+        Ok(Journal { file: Mutex::new(file), done })
+    }
+
+    /// Whether `file` was finished by the run being resumed.
+    pub fn is_done(&self, file: &str) -> bool {
+        self.done.contains(file)
+    }
+
+    /// How many files the run being resumed finished.
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    /// Records that `file` is finished. The line is written in one go, so a run stopped
+    /// at any point leaves at worst a partial last line, which can't match a path.
+    pub fn record(&self, file: &str) -> Result<()> {
+        self.file.lock().unwrap().write_all(format!("{}\n", file).as_bytes())
+    }
+}
@@ -0,0 +1,21 @@
+//! Lossless recompression of gzip'd NBT data, the format Minecraft uses for
+//! `level.dat`, player data and most other save files.
+//!
+//! The [`codec`] module holds the decompression and compression primitives,
+//! [`backend`] wraps them behind a common trait, and [`file`] ties everything
+//! together for the `nbt-compress` binary. [`nbt`] checks that decompressed data really
+//! is NBT. Region files are handled by [`region`], and [`walk`] finds NBT files inside
+//! directories. [`cache`] lets repeated runs skip files that are already as small as
+//! they will get, [`journal`] lets an interrupted run resume, [`config`] reads default
+//! flags from a file, and [`error`] describes failures worth telling apart.
+
+pub mod backend;
+pub mod cache;
+pub mod codec;
+pub mod config;
+pub mod error;
+pub mod file;
+pub mod journal;
+pub mod nbt;
+pub mod region;
+pub mod walk;
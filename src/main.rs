@@ -1,186 +1,1431 @@
-use std::io::{Result, Read, Write, Error, ErrorKind::InvalidData};
-use std::num::NonZeroU64;
-use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
-use libdeflater::*;
-use zopfli::Format::Gzip;
+use clap::{ColorChoice, Parser};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{error, warn};
+use owo_colors::{AnsiColors, OwoColorize};
+use rayon::prelude::*;
+use serde::Serialize;
 
+use nbt_compress::backend::{self, Attempt, Best, Brotli, CompressionBackend, Dedupe, Fallback, Libdeflater, Lru, TimeLimit, Zopfli, Zstd, TIMED_OUT};
+use nbt_compress::cache::{Cache, CACHE_FILE};
+use nbt_compress::codec::{self, Format, IterationStrategy};
+use nbt_compress::config::{Backend as ConfigBackend, Config};
+use nbt_compress::error::is_corrupt;
+use nbt_compress::journal::Journal;
+use nbt_compress::nbt;
+use nbt_compress::region;
+use nbt_compress::file::{benchmark_file, compress_deferred, decompress_file, decompressed_path, compared_sizes, footer_sizes, inspect_file, mirrored_path, read_file, read_input, read_nbt, read_stats, verify_against, CompressionReport, Contents, FileInfo, Kind, Options, PendingWrite, IDENTICAL, STDIN};
+use nbt_compress::walk::{collect_files, expand_glob, is_glob, is_world, read_file_list, Category, DEFAULT_EXTENSIONS};
+
+mod cli;
+
+use cli::{Cli, Command, CompressArgs, DecompressArgs, InspectArgs, Profile};
+
+/// Prints a human-readable report line, on stderr if stdout is carrying compressed data.
+macro_rules! report {
+    ($options:expr, $($arg:tt)*) => {
+        if $options.stdout {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Like [`report!`], but prints above the progress bar while there is one.
+macro_rules! file_report {
+    ($options:expr, $style:expr, $($arg:tt)*) => {
+        match &$style.progress {
+            Some(bar) => bar.println(format!($($arg)*)),
+            None => report!($options, $($arg)*),
+        }
+    };
+}
+
+/// Exit code when any file could not be read, compressed or written.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code for invalid command line arguments.
+const EXIT_USAGE: i32 = 2;
+/// Exit code after Ctrl-C, as shells use for a process killed by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Set by the first Ctrl-C, after which no more files are started.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// The brotli quality used without `--brotli-quality`, the highest there is.
+const DEFAULT_BROTLI_QUALITY: u32 = 11;
+
+/// The libdeflate level used without `--level` or a `--profile` that sets one.
+const DEFAULT_LEVEL: u8 = 12;
+
+/// How many files it takes to show a progress bar without `--progress`.
+const PROGRESS_THRESHOLD: usize = 8;
 
 fn main() {
-    let usage = "Usage: nbt-compress [-i <iterations>] file1 file2 ...";
-    let args: Vec<String> = std::env::args().collect();
-    let mut iterations = -1;
-    let mut use_zopfli = false;
-    let mut files = Vec::new();
+    let args = cli::with_default_command(std::env::args_os().collect());
+    let command = Cli::parse_from(&args).command;
 
-    for (index, arg) in args.iter().enumerate() {
-        if index == 0 {
-            continue;
+    // the config file only sets defaults, so anything on the command line wins
+    let config = match Config::find().map(|path| Config::load(&path)).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_USAGE);
         }
+    };
+
+    let input = command.input();
+    let display = command.display();
+    let color = if display.no_color { ColorChoice::Never } else { display.color };
+    let mut style = Style {
+        json: display.json,
+        raw_bytes: display.bytes,
+        quiet: display.quiet,
+        verbose: display.verbose,
+        progress: None,
+        color: use_color(color, std::io::stdout().is_terminal()),
+        color_errors: use_color(color, std::io::stderr().is_terminal()),
+    };
+    let mut options = Options { bedrock: input.bedrock, raw: input.raw, mmap: input.mmap, retries: input.retries, ..Options::default() };
+    codec::set_max_decompressed(input.max_decompressed);
+    let extensions: Vec<String> = match &input.ext {
+        Some(list) => list.iter().map(|e| e.trim_start_matches('.').to_string()).collect(),
+        None => DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+    };
+    let mut paths = input.files.clone();
+    if input.stdin {
+        paths.push(STDIN.to_string());
+    }
+    let manifests = &input.files_from;
 
-        if arg == "-z" || arg == "--zopfli" {
-            use_zopfli = true;
+    let mut threads = config.threads;
+    let mut resume = false;
+    let mut report_path = None;
+    if let Command::Compress(CompressArgs { write, .. }) | Command::Decompress(DecompressArgs { write, .. }) = &command {
+        options.dry_run = write.dry_run;
+        options.stdout = write.stdout;
+        options.output_dir = write.output_dir.clone();
+        options.preserve = !write.no_preserve;
+        threads = write.threads.or(threads);
+        resume = write.resume;
+        report_path = write.report.as_deref();
+    }
+    if let Command::Compress(args) = &command {
+        if args.backup || args.backup_suffix.is_some() || (config.backup || config.backup_suffix.is_some()) && !args.no_backup {
+            let suffix = args.backup_suffix.as_ref().or(config.backup_suffix.as_ref());
+            options.backup = Some(suffix.cloned().unwrap_or_else(|| ".bak".to_string()));
+        }
+        options.verify = args.verify || !args.no_verify && args.profile.is_none_or(Profile::verify);
+        options.validate = !args.no_validate;
+        options.normalize = args.normalize;
+        options.trim = args.trim;
+        options.preserve_gzip_meta = args.preserve_gzip_meta;
+        options.preserve_members = args.preserve_members;
+        options.strip = args.strip.clone();
+        if !args.strip.is_empty() && !options.dry_run {
+            warn!("--strip permanently removes {} from every file, keep a backup if you might want it back", args.strip.join(", "));
+        }
+        options.warn_on_grow_parse = args.warn_on_grow_parse;
+        options.share_aware = args.share_aware;
+        options.show_diff = args.show_diff;
+        options.format = args.format.map(Format::from);
+        options.out_ext = args.out_ext.clone();
+        options.min_saving = args.min_saving;
+        options.min_saving_percent = args.min_saving_percent;
+        options.keep_larger = args.keep_larger;
+        options.skip_identical = args.no_overwrite_identical;
+        options.chunks = args.chunk.iter().map(|&(x, z)| region::chunk_index(x, z)).collect();
+        options.quick_check = !args.no_quick_check;
+        options.only_if_improvable = args.only_if_improvable;
+        if args.reproducible && matches!(args.iteration_strategy, Some(IterationStrategy::TimeBudget(_))) {
+            eprintln!("Error parsing argument: --reproducible can't be used with a time budget");
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+
+    // stdin can only be read once
+    if manifests.iter().filter(|m| *m == STDIN).count() + paths.iter().filter(|p| *p == STDIN).count() > 1 {
+        eprintln!("Error parsing argument: stdin can only be read once, by --stdin or one --files-from -");
+        std::process::exit(EXIT_USAGE);
+    }
+
+    init_logging(&style);
+
+    // expand patterns the shell left alone, unless a file really has that name
+    let mut expanded = Vec::new();
+    for path in paths {
+        if !is_glob(&path) || std::path::Path::new(&path).exists() {
+            expanded.push(path);
             continue;
         }
+        match expand_glob(&path) {
+            Ok(matches) if matches.is_empty() => warn!("{} matched no files", path),
+            Ok(matches) => expanded.extend(matches),
+            Err(e) => {
+                eprintln!("Error parsing argument: {}", e);
+                std::process::exit(EXIT_USAGE);
+            }
+        }
+    }
+
+    // listed paths are taken literally, as whatever wrote the list has already expanded them
+    for manifest in manifests {
+        let listed = match read_file_list(manifest) {
+            Ok(listed) => listed,
+            Err(e) => {
+                error!("Error reading file list {}: {}", manifest, e);
+                std::process::exit(EXIT_FAILURE);
+            }
+        };
+        for path in listed {
+            if std::path::Path::new(&path).exists() {
+                expanded.push(path);
+            } else {
+                warn!("{} listed in {} does not exist", path, manifest);
+            }
+        }
+    }
 
-        if arg.starts_with("-") {
-            match parse_arg(arg, &args, index) {
-                Ok(i) => iterations = i,
+    let mut files = Vec::new();
+    for path in expanded {
+        if !std::path::Path::new(&path).is_dir() {
+            files.push(path);
+        } else if input.recursive || is_world(Path::new(&path)) {
+            // a world save is only ever useful as a whole, so it needs no -r
+            match collect_files(path.as_ref(), &extensions, input.follow_symlinks, input.include_backups) {
+                Ok(found) => files.extend(found),
                 Err(e) => {
-                    eprintln!("Error parsing argument: {}", e);
-                    std::process::exit(1);
+                    error!("Error reading directory {}: {}", path, e);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         } else {
-            files.push(arg.clone());
+            eprintln!("{} is a directory, use -r to compress the files inside it", path);
+            std::process::exit(EXIT_USAGE);
         }
     }
 
     if files.is_empty() {
-        println!("{}", usage);
-        std::process::exit(1);
+        eprintln!("No files given. Run with --help for a description of every flag.");
+        std::process::exit(EXIT_USAGE);
     }
 
-    let mut total_time = Duration::new(0, 0);
-    let mut total_saved_space = 0;
+    match &command {
+        Command::Inspect(InspectArgs { compare: Some(dir), .. }) => {
+            let mut failed = 0;
+            let mut compared = Vec::new();
+            let mut missing = Vec::new();
+            for file in &files {
+                match compared_sizes(file, dir) {
+                    Ok((size, Some(other_size))) => {
+                        if !style.json {
+                            println!("{}: {}", file, size_difference(size, other_size, dir, &style));
+                        }
+                        compared.push(ComparedFile { path: file, size, other_size, difference: other_size as i64 - size as i64 });
+                    }
+                    Ok((_, None)) => {
+                        warn!("{}: nothing at {} to compare with", file, mirrored_path(dir, file));
+                        missing.push(file.as_str());
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            let size = compared.iter().map(|c| c.size).sum();
+            let other_size = compared.iter().map(|c| c.other_size).sum();
+            if style.json {
+                let document = CompareReport { dir, files: &compared, missing: &missing, size, other_size, difference: other_size as i64 - size as i64 };
+                println!("{}", serde_json::to_string_pretty(&document).unwrap());
+            } else if !compared.is_empty() {
+                let smaller_here = compared.iter().filter(|c| c.difference > 0).count();
+                let smaller_there = compared.iter().filter(|c| c.difference < 0).count();
+                println!("Total over {} files: {}", compared.len(), size_difference(size, other_size, dir, &style));
+                println!(
+                    "{} files smaller here, {} smaller in {}, {} the same size",
+                    smaller_here, smaller_there, dir, compared.len() - smaller_here - smaller_there
+                );
+            }
+            if failed > 0 {
+                error!("{} of {} files failed", failed, files.len());
+                std::process::exit(EXIT_FAILURE);
+            }
+            return;
+        }
+        Command::Inspect(args) if args.sizes => {
+            let mut failed = 0;
+            let mut sizes = Vec::new();
+            for file in &files {
+                match footer_sizes(file) {
+                    Ok(found) => {
+                        if found.may_wrap() {
+                            warn!("{}: the gzip footer only holds the size modulo 4 GiB, so it may be far larger", file);
+                        }
+                        if !style.json {
+                            println!(
+                                "{}: {} compressed, {} uncompressed ({:.1}%)",
+                                file, style.size(found.compressed), style.size(found.uncompressed as usize),
+                                ratio(found.compressed, found.uncompressed as usize) * 100.0
+                            );
+                        }
+                        sizes.push(FooterSize { path: file, compressed: found.compressed, uncompressed: found.uncompressed as usize });
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            let compressed = sizes.iter().map(|s| s.compressed).sum();
+            let uncompressed = sizes.iter().map(|s| s.uncompressed).sum();
+            if style.json {
+                let document = SizesReport { files: &sizes, compressed, uncompressed };
+                println!("{}", serde_json::to_string_pretty(&document).unwrap());
+            } else if sizes.len() > 1 {
+                println!(
+                    "Total: {} compressed, {} uncompressed ({:.1}%)",
+                    style.size(compressed), style.size(uncompressed), ratio(compressed, uncompressed) * 100.0
+                );
+            }
+            if failed > 0 {
+                error!("{} of {} files failed", failed, files.len());
+                std::process::exit(EXIT_FAILURE);
+            }
+            return;
+        }
+        Command::Inspect(args) if args.print || args.pretty => {
+            let mut failed = 0;
+            for file in &files {
+                match read_nbt(file) {
+                    Ok((_, root)) => println!("{}", root.to_snbt(args.pretty)),
+                    Err(e) => {
+                        error!("{}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                error!("{} of {} files failed", failed, files.len());
+                std::process::exit(EXIT_FAILURE);
+            }
+            return;
+        }
+        Command::Inspect(args) if args.stats => {
+            let mut failed = 0;
+            for file in &files {
+                match read_stats(file) {
+                    Ok(stats) => print_stats(file, &stats, &style),
+                    Err(e) => {
+                        error!("{}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                error!("{} of {} files failed", failed, files.len());
+                std::process::exit(EXIT_FAILURE);
+            }
+            return;
+        }
+        Command::Inspect(_) => {
+            let mut failed = 0;
+            for file in &files {
+                match inspect_file(file, &options) {
+                    Ok(info) => {
+                        failed += usize::from(info.problem.is_some());
+                        print_info(file, &info, &style);
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                error!("{} of {} files are unreadable or invalid", failed, files.len());
+                std::process::exit(EXIT_FAILURE);
+            }
+            return;
+        }
+        Command::Check(args) => {
+            start_thread_pool(args.threads.or(threads));
+            let check = |file: &String| {
+                let info = inspect_file(file, &options)?;
+                match (&info.problem, &args.reference) {
+                    (None, Some(dir)) => verify_against(file, &mirrored_path(dir, file), &options).map(|()| info),
+                    _ => Ok(info),
+                }
+            };
+            let results: Vec<(&String, std::io::Result<FileInfo>)> = files.par_iter().map(|file| (file, check(file))).collect();
+            // read and comparison errors already name the file, problems with the contents don't
+            let failures: Vec<CheckFailure> = results
+                .iter()
+                .filter_map(|(file, result)| match result {
+                    Ok(info) => info.problem.as_ref().map(|e| CheckFailure { path: file, error: format!("{}: {}", file, e) }),
+                    Err(e) => Some(CheckFailure { path: file, error: e.to_string() }),
+                })
+                .collect();
+            if style.json {
+                let document = CheckReport { checked: files.len(), failed: &failures };
+                println!("{}", serde_json::to_string_pretty(&document).unwrap());
+            } else {
+                for failure in &failures {
+                    println!("{}", failure.error);
+                }
+            }
+            if !failures.is_empty() {
+                match &args.reference {
+                    Some(dir) => error!("{} of {} files are corrupt, invalid or don't match {}", failures.len(), files.len(), dir),
+                    None => error!("{} of {} files are corrupt or invalid", failures.len(), files.len()),
+                }
+                std::process::exit(EXIT_FAILURE);
+            }
+            if !style.json && !style.quiet {
+                match &args.reference {
+                    Some(dir) => println!("All {} files are valid and match {}", files.len(), dir),
+                    None => println!("All {} files are valid", files.len()),
+                }
+            }
+            return;
+        }
+        Command::Benchmark(args) => {
+            start_thread_pool(args.threads.or(threads));
+            let block_splits = args.block_splits;
+            let mut backends: Vec<Box<dyn CompressionBackend>> = [1, 6, 9, 12]
+                .into_iter()
+                .map(|level| Box::new(Libdeflater { level }) as Box<dyn CompressionBackend>)
+                .chain([5, 15].into_iter().map(|iterations| Box::new(Zopfli { iterations, block_splits }) as Box<dyn CompressionBackend>))
+                .collect();
+            if args.zstd {
+                backends.push(Box::new(Zstd { level: 19 }));
+            }
+            if args.brotli || args.brotli_quality.is_some() {
+                backends.push(Box::new(Brotli { quality: args.brotli_quality.unwrap_or(DEFAULT_BROTLI_QUALITY) }));
+            }
 
-    for file in &files {
-        match compress_file(file, iterations, use_zopfli) {
-            Ok((elapsed_time, saved_space)) => {
-                total_time += elapsed_time;
-                total_saved_space += saved_space;
+            let mut failed = 0;
+            for file in &files {
+                match benchmark_file(file, &backends) {
+                    Ok((original, attempts)) => print_benchmark(file, original, &attempts, &style),
+                    Err(e) => {
+                        error!("{}", e);
+                        failed += 1;
+                    }
+                }
             }
-            Err(_) => {}
+            if failed > 0 {
+                error!("{} of {} files failed", failed, files.len());
+                std::process::exit(EXIT_FAILURE);
+            }
+            return;
         }
+        Command::Compress(_) | Command::Decompress(_) => {}
     }
 
-    if files.len() > 1 {
-        println!("\nDone!");
-        println!("Total time: {:?}", total_time);
-        println!("Total saved space: {} bytes", total_saved_space);
+    // a sample is a dry run over every Nth file, remembering how much there was of all of
+    // them to scale the savings up to
+    let sample = match &command {
+        Command::Compress(CompressArgs { sample: Some(step), .. }) => {
+            options.dry_run = true;
+            let all_bytes: u64 = files.iter().map(|file| std::fs::metadata(file).map_or(0, |m| m.len())).sum();
+            let all_files = files.len();
+            files = files.into_iter().step_by(*step as usize).collect();
+            Some((all_files, all_bytes))
+        }
+        _ => None,
+    };
+
+    // data read from stdin can only go back out on stdout
+    if files.iter().any(|f| f == STDIN) {
+        options.stdout = true;
+    }
+    if options.stdout && options.output_dir.is_some() {
+        eprintln!("--output-dir can't be used with --stdout or stdin");
+        std::process::exit(EXIT_USAGE);
     }
-}
 
-fn compress_file(file: &str, iterations: i32, zopfli: bool) -> Result<(Duration, usize)> {
-    match read_file(file) {
-        Ok(contents) => {
-            let start_time = Instant::now();
+    // output on stdout has to come out in the same order as the files were given, so that
+    // is done on a single thread
+    if options.stdout {
+        threads = Some(1);
+    }
+    start_thread_pool(threads);
 
-            let uncompressed_contents = match decompress(contents.clone()) {
-                Ok(c) => c,
-                Err(e) => return Err(e)
-            };
+    let (backend, timed_out_counter) = match &command {
+        Command::Compress(args) => {
+            let (backend, timed_out) = compress_backend(args, &config);
+            (Some(backend), timed_out)
+        }
+        _ => (None, None),
+    };
+    let dedupe = match (&command, &backend) {
+        (Command::Compress(args), Some(backend)) if args.dedupe => Some(Dedupe::new(backend.as_ref())),
+        _ => None,
+    };
+    let backend: Option<&dyn CompressionBackend> = match &dedupe {
+        Some(dedupe) => Some(dedupe),
+        None => backend.as_deref(),
+    };
+    // --dedupe already keeps every output, so this would only hold copies
+    let lru = match (&command, backend) {
+        (Command::Compress(args), Some(backend)) if dedupe.is_none() && args.chunk_cache > 0 => Some(Lru::new(backend, args.chunk_cache)),
+        _ => None,
+    };
+    // only region chunks repeat often enough to be worth keeping
+    let region_backend: Option<&dyn CompressionBackend> = match &lru {
+        Some(lru) => Some(lru),
+        None => backend,
+    };
 
-            let optimized_contents =
-                match if zopfli { compress_zopfli(uncompressed_contents.clone(), iterations) } else { compress_libdeflater(uncompressed_contents.clone(), 12) } {
-                    Ok(c) => c,
-                    Err(e) => {
-                        eprintln!("Error compressing {}: {}", file, e);
-                        return Err(e);
+    // nothing is cached for output that doesn't replace the original, or for region files
+    // that only had some chunks recompressed
+    let use_cache = matches!(&command, Command::Compress(args) if args.cache && args.chunk.is_empty() && args.strip.is_empty());
+    let cache = (use_cache && !options.stdout && options.output_dir.is_none()).then(|| Mutex::new(Cache::load(Path::new(CACHE_FILE))));
+
+    // nothing needs resuming if nothing is written, or if the output went to stdout
+    let journal = (!options.dry_run && !options.stdout).then(|| {
+        let key: Vec<String> = args[1..].iter().map(|arg| arg.to_string_lossy().into_owned()).filter(|arg| arg != "--resume").collect();
+        let dir = std::env::current_dir().unwrap_or_default();
+        let path = Journal::default_path(&dir, &key);
+        match Journal::open(&path, resume) {
+            Ok(journal) => Some((journal, path)),
+            Err(e) => {
+                warn!("Error opening journal {}, the run can't be resumed: {}", path.display(), e);
+                None
+            }
+        }
+    }).flatten();
+    if let Some((journal, _)) = &journal {
+        let total = files.len();
+        files.retain(|file| !journal.is_done(file));
+        if resume && !style.json && !style.quiet {
+            report!(options, "Resuming: {} of {} files already done", total - files.len(), total);
+        }
+    }
+
+    // a bar only helps with more than a handful of files, and needs a terminal to draw on
+    let show_progress = display.progress || files.len() > PROGRESS_THRESHOLD;
+    if show_progress && !style.json && !options.stdout && std::io::stdout().is_terminal() {
+        let bar = ProgressBar::with_draw_target(Some(files.len() as u64), ProgressDrawTarget::stdout());
+        bar.set_style(ProgressStyle::with_template("{bar:40} {pos}/{len} {wide_msg}").unwrap());
+        style.progress = Some(bar);
+    }
+
+    // the first Ctrl-C lets the files in progress finish, as a write cut short would
+    // leave a temporary file behind, and a second one gives up on them
+    if let Err(e) = ctrlc::set_handler(|| {
+        if STOPPING.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("Stopping after the files in progress, press Ctrl-C again to quit now");
+    }) {
+        warn!("Error setting up Ctrl-C handling: {}", e);
+    }
+
+    let backend_for = |file: &str| match (backend, region_backend) {
+        (Some(_), Some(backend)) if region::is_region_file(file) => Some(backend),
+        (backend, _) => backend,
+    };
+    let finish = |file: &str, result: std::io::Result<CompressionReport>| {
+        if let (Some((journal, path)), Ok(_)) = (&journal, &result) {
+            if let Err(e) = journal.record(file) {
+                style.suspend(|| warn!("Error writing journal {}: {}", path.display(), e));
+            }
+        }
+        if let Some(bar) = &style.progress {
+            bar.inc(1);
+        }
+        result
+    };
+    let starting = |file: &str| {
+        if STOPPING.load(Ordering::SeqCst) {
+            return false;
+        }
+        if let Some(bar) = &style.progress {
+            bar.set_message(file.to_string());
+        }
+        true
+    };
+    let process = |file: &String| {
+        if !starting(file) {
+            return None;
+        }
+        let result = match backend_for(file) {
+            Some(backend) => compress_one(file, None, backend, cache.as_ref(), &options, &style),
+            None => decompress_one(file, &options, &style),
+        };
+        Some(finish(file, result))
+    };
+    // a reader thread keeps up to --queue-depth files read ahead of the workers, so they
+    // don't wait on the disk, and a writer thread writes their output, so they don't wait
+    // on it either. Output on stdout has to keep to the order the files were given in, so
+    // it doesn't.
+    let results: Vec<std::io::Result<CompressionReport>> = match (&command, backend) {
+        (Command::Compress(args), Some(_)) if !options.stdout => std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::sync_channel(args.queue_depth);
+            let (written_sender, written_receiver) = mpsc::sync_channel(args.queue_depth);
+            let (files, options) = (&files, &options);
+            scope.spawn(move || {
+                for (index, file) in files.iter().enumerate() {
+                    // the workers stopped early, so nothing more is needed
+                    if STOPPING.load(Ordering::SeqCst) || sender.send((index, file, read_input(file, options))).is_err() {
+                        break;
                     }
-                };
+                }
+            });
+            let (backend_for, finish, cache) = (&backend_for, &finish, cache.as_ref());
+            let style = &style;
+            let writer = scope.spawn(move || {
+                written_receiver
+                    .into_iter()
+                    .map(|(index, file, prepared)| {
+                        let backend = backend_for(file).unwrap();
+                        (index, finish(file, finish_one(file, prepared, backend, cache, options, style)))
+                    })
+                    .collect::<Vec<_>>()
+            });
+            receiver.into_iter().par_bridge().for_each_with(written_sender, |written, (index, file, contents)| {
+                if starting(file) {
+                    let prepared = prepare_one(file, Some(contents), backend_for(file).unwrap(), cache, options, style);
+                    // the writer only stops if it panicked, which the join below passes on
+                    let _ = written.send((index, file, prepared));
+                }
+            });
+            let mut results = writer.join().unwrap();
+            results.sort_unstable_by_key(|(index, _)| *index);
+            results.into_iter().map(|(_, result)| result).collect()
+        }),
+        _ => files.par_iter().filter_map(process).collect(),
+    };
+    let stopped = STOPPING.load(Ordering::SeqCst);
+
+    if let Some(bar) = style.progress.take() {
+        bar.finish_and_clear();
+    }
+
+    if let Some(cache) = cache {
+        if let Err(e) = cache.into_inner().unwrap().save(Path::new(CACHE_FILE)) {
+            warn!("Error writing {}: {}", CACHE_FILE, e);
+        }
+    }
+
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    let corrupt = results.iter().filter(|r| r.as_ref().is_err_and(is_corrupt)).count();
+    let reports: Vec<CompressionReport> = results.into_iter().flatten().collect();
+    let total_time: Duration = reports.iter().map(|r| r.elapsed).sum();
+    let total_original: usize = reports.iter().map(|r| r.original).sum();
+    let total_saved_space: i64 = reports.iter().map(|r| r.net_saved()).sum();
+    let skipped = reports.iter().filter(|r| r.saved() == 0 && !r.written).count();
+    let duplicates = dedupe.as_ref().map_or(0, Dedupe::duplicates);
+    let timed_out: Vec<&str> = reports.iter().filter(|r| r.backend.contains(TIMED_OUT)).map(|r| r.path.as_str()).collect();
+    // region files only report the backend they were given, so their chunks are only counted
+    let timed_out_streams = timed_out_counter.as_ref().map_or(0, |count| count.load(Ordering::Relaxed));
+
+    let compressed_bytes = reports.iter().map(|r| r.compressed).sum();
+    let mut categories: BTreeMap<Category, CategoryTotal> = BTreeMap::new();
+    for report in &reports {
+        let category = Category::of(&report.path);
+        let total = categories.entry(category).or_insert(CategoryTotal { category: category.name(), files: 0, original_bytes: 0, saved_bytes: 0 });
+        total.files += 1;
+        total.original_bytes += report.original;
+        total.saved_bytes += report.net_saved();
+    }
+    // settings like the iteration count vary between files, so backends are grouped by name
+    let mut throughput: Vec<Throughput> = Vec::new();
+    for report in &reports {
+        let (backend, Some(decompressed)) = (report.backend.split(" (").next().unwrap_or_default(), report.decompressed) else { continue };
+        if backend == "none" {
+            continue;
+        }
+        let index = match throughput.iter().position(|total| total.backend == backend) {
+            Some(index) => index,
+            None => {
+                throughput.push(Throughput { backend: backend.to_string(), files: 0, decompressed_bytes: 0, elapsed: Duration::ZERO, millis: 0, bytes_per_sec: 0 });
+                throughput.len() - 1
+            }
+        };
+        let total = &mut throughput[index];
+        total.files += 1;
+        total.decompressed_bytes += decompressed;
+        total.elapsed += report.elapsed;
+    }
+    for total in &mut throughput {
+        total.millis = total.elapsed.as_millis() as u64;
+        total.bytes_per_sec = (total.decompressed_bytes as f64 / total.elapsed.as_secs_f64().max(1e-6)) as u64;
+    }
+    let estimated_saved_bytes = sample.map(|(_, all_bytes)| match total_original {
+        0 => 0,
+        sampled => (total_saved_space as f64 * all_bytes as f64 / sampled as f64) as i64,
+    });
+    let summary = Summary {
+        files: reports.len(),
+        failed,
+        corrupt,
+        skipped,
+        duplicates,
+        timed_out: timed_out.len(),
+        timed_out_streams,
+        original_bytes: total_original,
+        compressed_bytes,
+        saved_bytes: total_saved_space,
+        ratio: ratio(compressed_bytes, total_original),
+        millis: total_time.as_millis() as u64,
+        estimated_saved_bytes,
+        categories: categories.into_values().collect(),
+        throughput,
+        stripped_bytes: reports.iter().map(|r| r.stripped_bytes).sum(),
+    };
 
-            let elapsed_time = start_time.elapsed();
-
-            if optimized_contents.len() < contents.len() {
-                let saved_space = contents.len() - optimized_contents.len();
-                if let Err(e) = write_file(file, optimized_contents) {
-                    eprintln!("Error writing to {}: {}", file, e);
-                    Err(e)
-                } else {
-                    println!(
-                        "File {} compressed. Saved space: {} bytes. \nCompression time: {:?}",
-                        file, saved_space, elapsed_time
+    if style.json {
+        let results: Vec<FileResult> = reports.iter().map(FileResult::from).collect();
+        let document = JsonReport { files: &results, summary: &summary };
+        report!(options, "{}", serde_json::to_string_pretty(&document).unwrap());
+    } else if files.len() > 1 {
+        report!(options, "\nDone!");
+        report!(options, "Total time: {:?}", total_time);
+        if backend.is_some() {
+            // files that weren't improved keep their original size
+            let total_final = (total_original as i64 - total_saved_space) as usize;
+            let sign = if total_saved_space < 0 { "-" } else { "" };
+            report!(
+                options,
+                "Total saved space: {}{}, compressed {} to {} ({:.1}% saved)",
+                sign, style.size(total_saved_space.unsigned_abs() as usize), style.size(total_original), style.size(total_final),
+                (1.0 - ratio(total_final, total_original)) * 100.0
+            );
+            if summary.categories.len() > 1 {
+                for total in &summary.categories {
+                    let sign = if total.saved_bytes < 0 { "-" } else { "" };
+                    report!(
+                        options,
+                        "  {}: {} files, saved {}{} ({:.1}%)",
+                        total.category, total.files, sign, style.size(total.saved_bytes.unsigned_abs() as usize),
+                        total.saved_bytes as f64 * 100.0 / total.original_bytes.max(1) as f64
                     );
-                    Ok((elapsed_time, saved_space))
                 }
-            } else {
-                println!(
-                    "File {} not compressed. No space saved. \nCompression time: {:?}",
-                    file, elapsed_time
+            }
+            for total in &summary.throughput {
+                report!(
+                    options,
+                    "Throughput with {}: {}/s decompressed, over {} files",
+                    total.backend, style.size(total.bytes_per_sec as usize), total.files
                 );
-                return Ok((elapsed_time, 0));
+            }
+            if skipped > 0 {
+                report!(options, "{} of {} files skipped, no space saved", skipped, reports.len());
+            }
+            if dedupe.is_some() {
+                report!(options, "{} duplicates reused an earlier result", duplicates);
+            }
+            if summary.stripped_bytes > 0 {
+                report!(options, "--strip removed {} of NBT in total", style.size(summary.stripped_bytes));
+            }
+            if let Some(hits) = lru.as_ref().map(Lru::hits).filter(|hits| *hits > 0) {
+                report!(options, "{} identical chunks reused a recent result", hits);
+            }
+            if options.dry_run {
+                print_comparison(&reports, &options, &style);
+            }
+            if !timed_out.is_empty() {
+                report!(options, "{} files ran out of time with zopfli, libdeflater was used instead:", timed_out.len());
+                for file in &timed_out {
+                    report!(options, "  {}", file);
+                }
+            }
+            if timed_out_streams > timed_out.len() {
+                report!(options, "{} streams and region chunks in all ran out of time with zopfli", timed_out_streams);
             }
         }
-        Err(e) => {
-            eprintln!("Error reading from {}: {}", file, e);
-            Err(e)
+    }
+
+    if let (Some((all_files, _)), Some(estimate), false) = (sample, estimated_saved_bytes, style.json) {
+        let sign = if estimate < 0 { "-" } else { "" };
+        report!(
+            options,
+            "Estimated {}{} savings across {} files, from a sample of {} (nothing was written)",
+            sign, style.size(estimate.unsigned_abs() as usize), all_files, reports.len()
+        );
+    }
+
+    if let Some(path) = report_path {
+        if let Err(e) = write_report(path, &reports, &summary, &style) {
+            error!("Error writing report {}: {}", path, e);
+            std::process::exit(EXIT_FAILURE);
         }
     }
+
+    // failed files aren't in the journal, so resuming retries just those
+    if let (Some((_, path)), 0, false) = (&journal, failed, stopped) {
+        let _ = std::fs::remove_file(path);
+    }
+
+    if stopped {
+        let resume = if journal.is_some() { ", run again with --resume to do the rest" } else { "" };
+        error!("Stopped after {} of {} files{}", reports.len() + failed, files.len(), resume);
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+
+    if failed > 0 {
+        let corrupt = if corrupt > 0 { format!(" ({} corrupt)", corrupt) } else { String::new() };
+        error!("{} of {} files failed{}", failed, files.len(), corrupt);
+        std::process::exit(EXIT_FAILURE);
+    }
 }
 
-fn parse_arg(arg: &str, args: &Vec<String>, index: usize) -> std::result::Result<i32, String> {
-    if arg.starts_with("-i") {
-        args[index][2..].parse().map_err(|e| format!("Failed to parse iterations: {}", e))
-    } else if arg.starts_with("--iterations") {
-        args[index][12..].parse().map_err(|e| format!("Failed to parse iterations: {}", e))
+/// Sends log messages to stderr. Errors and warnings are shown by default, only errors with
+/// `--quiet`, and this crate's debug messages too with `--verbose`. `RUST_LOG` overrides all
+/// of these.
+fn init_logging(style: &Style) {
+    let color = style.color_errors;
+    let default = if style.quiet {
+        "error"
+    } else if style.verbose {
+        "warn,nbt_compress=debug"
     } else {
-        Err("Invalid argument for parse_arg".to_string())
+        "warn"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default))
+        // env_logger would strip the colors again off a terminal
+        .write_style(if color { env_logger::WriteStyle::Always } else { env_logger::WriteStyle::Never })
+        .format(move |buf, record| match (record.level(), color) {
+            // errors already say what failed
+            (log::Level::Error, false) => writeln!(buf, "{}", record.args()),
+            (log::Level::Error, true) => writeln!(buf, "{}", record.args().red()),
+            (log::Level::Warn, false) => writeln!(buf, "Warning: {}", record.args()),
+            (log::Level::Warn, true) => writeln!(buf, "{}", format_args!("Warning: {}", record.args()).yellow()),
+            (level, _) => writeln!(buf, "[{}] {}", level.as_str().to_lowercase(), record.args()),
+        })
+        .init();
+}
+
+/// Whether to color output for a stream that is or isn't a terminal, going by `--color`
+/// and, when left to decide, the `NO_COLOR` environment variable.
+fn use_color(choice: ColorChoice, terminal: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()),
+    }
+}
+
+/// How results are presented to the user.
+#[derive(Default)]
+struct Style {
+    /// Print one JSON document at the end instead of a line per file.
+    json: bool,
+    /// Print sizes as plain byte counts rather than KiB/MiB.
+    raw_bytes: bool,
+    /// Only print the totals at the end. Errors are still printed.
+    quiet: bool,
+    /// Also print the input format, decompressed size and every compression attempt.
+    verbose: bool,
+    /// Show a progress bar while files are being processed.
+    progress: Option<ProgressBar>,
+    /// Color per-file results on stdout by whether they saved space.
+    color: bool,
+    /// Color errors and warnings on stderr.
+    color_errors: bool,
+}
+
+impl Style {
+    /// Runs `f` with the progress bar hidden, so that anything it prints isn't mixed up
+    /// with the bar.
+    fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        match &self.progress {
+            Some(bar) => bar.suspend(f),
+            None => f(),
+        }
+    }
+
+    /// `text` in `color` with [`color`](Self::color), and as it is otherwise.
+    fn paint(&self, color: AnsiColors, text: String) -> String {
+        match self.color {
+            true => text.color(color).to_string(),
+            false => text,
+        }
+    }
+
+    fn size(&self, bytes: usize) -> String {
+        const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+        if self.raw_bytes || bytes < 1024 {
+            return format!("{} bytes", bytes);
+        }
+
+        let mut value = bytes as f64 / 1024.0;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// The outcome of processing one file, as reported with `--json`.
+#[derive(Serialize)]
+struct FileResult<'a> {
+    path: &'a str,
+    original_bytes: usize,
+    compressed_bytes: usize,
+    /// `None` if the file wasn't decompressed.
+    decompressed_bytes: Option<usize>,
+    /// How many bytes of NBT `--strip` removed.
+    stripped_bytes: usize,
+    /// What `--warn-on-grow-parse` found wrong with the output's tags, if anything.
+    tag_warning: Option<&'a str>,
+    /// Negative if a larger output was kept with `--keep-larger`.
+    saved_bytes: i64,
+    ratio: f64,
+    backend: &'a str,
+    millis: u64,
+    written: bool,
+}
+
+impl<'a> From<&'a CompressionReport> for FileResult<'a> {
+    fn from(report: &'a CompressionReport) -> Self {
+        FileResult {
+            path: &report.path,
+            original_bytes: report.original,
+            compressed_bytes: report.compressed,
+            decompressed_bytes: report.decompressed,
+            stripped_bytes: report.stripped_bytes,
+            tag_warning: report.tag_warning.as_deref(),
+            saved_bytes: report.net_saved(),
+            ratio: ratio(report.compressed, report.original),
+            backend: &report.backend,
+            millis: report.elapsed.as_millis() as u64,
+            written: report.written,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    files: &'a [FileResult<'a>],
+    summary: &'a Summary,
+}
+
+/// Describes how `size`, of a file given on the command line, compares with `other_size`,
+/// of its counterpart in `dir` with `inspect --compare`.
+fn size_difference(size: u64, other_size: u64, dir: &str, style: &Style) -> String {
+    let (smaller, difference, larger) = match other_size.cmp(&size) {
+        std::cmp::Ordering::Equal => return format!("{} here and in {}, the same size", style.size(size as usize), dir),
+        std::cmp::Ordering::Less => (format!("in {}", dir), size - other_size, size),
+        std::cmp::Ordering::Greater => ("here".to_string(), other_size - size, other_size),
+    };
+    format!(
+        "{} here, {} in {}, smaller {} by {} ({:.1}%)",
+        style.size(size as usize), style.size(other_size as usize), dir, smaller,
+        style.size(difference as usize), ratio(difference as usize, larger as usize) * 100.0
+    )
+}
+
+/// The `--json` output of `inspect --compare`.
+#[derive(Serialize)]
+struct CompareReport<'a> {
+    dir: &'a str,
+    files: &'a [ComparedFile<'a>],
+    /// Files with nothing at the same path in `dir`, which aren't in the totals.
+    missing: &'a [&'a str],
+    size: u64,
+    other_size: u64,
+    /// Positive if the files given are smaller than those in `dir`.
+    difference: i64,
+}
+
+#[derive(Serialize)]
+struct ComparedFile<'a> {
+    path: &'a str,
+    size: u64,
+    other_size: u64,
+    difference: i64,
+}
+
+/// The `--json` output of `inspect --sizes`.
+#[derive(Serialize)]
+struct SizesReport<'a> {
+    files: &'a [FooterSize<'a>],
+    compressed: usize,
+    uncompressed: usize,
+}
+
+#[derive(Serialize)]
+struct FooterSize<'a> {
+    path: &'a str,
+    compressed: usize,
+    uncompressed: usize,
+}
+
+/// The `--json` output of `check`.
+#[derive(Serialize)]
+struct CheckReport<'a> {
+    checked: usize,
+    failed: &'a [CheckFailure<'a>],
+}
+
+#[derive(Serialize)]
+struct CheckFailure<'a> {
+    path: &'a str,
+    error: String,
+}
+
+/// Totals over every file that didn't fail, reported at the end of `--json` output.
+#[derive(Serialize)]
+struct Summary {
+    files: usize,
+    failed: usize,
+    /// Failures because the input couldn't be decompressed.
+    corrupt: usize,
+    /// Files left as they were because no space was saved.
+    skipped: usize,
+    /// With `--dedupe`, streams whose data had already been compressed.
+    duplicates: usize,
+    /// With `--zopfli-timeout`, files that fell back to libdeflater for at least one stream.
+    /// Region files aren't counted, as each chunk is compressed on its own.
+    timed_out: usize,
+    /// With `--zopfli-timeout`, streams and region chunks that fell back to libdeflater.
+    timed_out_streams: usize,
+    original_bytes: usize,
+    compressed_bytes: usize,
+    saved_bytes: i64,
+    ratio: f64,
+    millis: u64,
+    /// With `--sample`, the savings scaled up from the sampled files to all of them by size.
+    estimated_saved_bytes: Option<i64>,
+    /// Subtotals for each part of a world save the files were in, see [`Category`].
+    categories: Vec<CategoryTotal>,
+    /// How fast each backend that ran got through the decompressed data, per thread, as
+    /// the time is summed over files that may have been compressed at once.
+    throughput: Vec<Throughput>,
+    /// With `--strip`, how many bytes of NBT it removed, before compression.
+    stripped_bytes: usize,
+}
+
+#[derive(Serialize)]
+struct Throughput {
+    backend: String,
+    files: usize,
+    decompressed_bytes: usize,
+    #[serde(skip)]
+    elapsed: Duration,
+    millis: u64,
+    /// Decompressed bytes per second, the same unit as the other sizes.
+    bytes_per_sec: u64,
+}
+
+#[derive(Serialize)]
+struct CategoryTotal {
+    category: &'static str,
+    files: usize,
+    original_bytes: usize,
+    saved_bytes: i64,
+}
+
+/// Writes every file's result to `path`, the files that saved the most first, followed by
+/// the totals. The report is JSON, like with `--json`, if `path` ends in `.json`, and text
+/// otherwise.
+fn write_report(path: &str, reports: &[CompressionReport], summary: &Summary, style: &Style) -> std::io::Result<()> {
+    let mut sorted: Vec<&CompressionReport> = reports.iter().collect();
+    sorted.sort_by_key(|report| std::cmp::Reverse(report.net_saved()));
+
+    if Path::new(path).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json")) {
+        let results: Vec<FileResult> = sorted.into_iter().map(FileResult::from).collect();
+        let document = JsonReport { files: &results, summary };
+        return std::fs::write(path, serde_json::to_string_pretty(&document)? + "\n");
+    }
+
+    let mut text = String::new();
+    for report in sorted {
+        let saved = report.net_saved();
+        let sign = if saved < 0 { "-" } else { "" };
+        text += &format!(
+            "{}: saved {}{} ({:.1}%), {} to {} with {}\n",
+            report.path, sign, style.size(saved.unsigned_abs() as usize),
+            saved as f64 * 100.0 / report.original.max(1) as f64, style.size(report.original),
+            style.size((report.original as i64 - saved) as usize), report.backend
+        );
+    }
+    let sign = if summary.saved_bytes < 0 { "-" } else { "" };
+    text += &format!(
+        "\nTotal: {} files, saved {}{} ({:.1}%), {} to {} in {:?}\n",
+        summary.files, sign, style.size(summary.saved_bytes.unsigned_abs() as usize),
+        summary.saved_bytes as f64 * 100.0 / summary.original_bytes.max(1) as f64, style.size(summary.original_bytes),
+        style.size((summary.original_bytes as i64 - summary.saved_bytes) as usize), Duration::from_millis(summary.millis)
+    );
+    if summary.skipped > 0 {
+        text += &format!("{} files skipped, no space saved\n", summary.skipped);
     }
+    if summary.failed > 0 {
+        text += &format!("{} files failed ({} corrupt)\n", summary.failed, summary.corrupt);
+    }
+    std::fs::write(path, text)
+}
+
+fn ratio(compressed: usize, original: usize) -> f64 {
+    if original == 0 { 1.0 } else { compressed as f64 / original as f64 }
 }
 
-fn read_file(path: &str) -> Result<Vec<u8>> {
-    let mut file = std::fs::File::open(path)?;
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents)?;
-    Ok(contents)
+/// Compresses one file and reports the result, unless the report will be JSON. With a
+/// cache, files it knows can't be improved are skipped without decompressing them.
+fn compress_one(file: &str, contents: Option<std::io::Result<Contents>>, backend: &dyn CompressionBackend, cache: Option<&Mutex<Cache>>, options: &Options, style: &Style) -> std::io::Result<CompressionReport> {
+    let prepared = prepare_one(file, contents, backend, cache, options, style);
+    finish_one(file, prepared, backend, cache, options, style)
 }
 
-fn write_file(path: &str, contents: Vec<u8>) -> Result<()> {
-    let mut file = std::fs::File::create(path)?;
-    file.write_all(&contents)?;
-    Ok(())
+/// The compressing half of [`compress_one`], which leaves the output to be written by
+/// [`finish_one`]. Files skipped thanks to the cache have nothing to write.
+fn prepare_one(file: &str, contents: Option<std::io::Result<Contents>>, backend: &dyn CompressionBackend, cache: Option<&Mutex<Cache>>, options: &Options, style: &Style) -> std::io::Result<(CompressionReport, Option<PendingWrite>)> {
+    // describing the backend without data gives its name and any fixed settings
+    let cache_key = backend.describe(&[]);
+    if let Some(cache) = cache {
+        let optimal = match &contents {
+            Some(Ok(contents)) => Some((cache.lock().unwrap().is_optimal(file, contents, &cache_key), contents.len())),
+            Some(Err(_)) => None,
+            None => read_file(file).ok().map(|contents| (cache.lock().unwrap().is_optimal(file, &contents, &cache_key), contents.len())),
+        };
+        if let Some((true, len)) = optimal {
+            if !style.json && !style.quiet {
+                file_report!(options, style, "File {} unchanged since it was last compressed, skipped.", file);
+            }
+            return Ok((CompressionReport::skipped(file, len, "cached"), None));
+        }
+    }
+
+    let contents = match contents {
+        Some(contents) => contents,
+        None => read_input(file, options),
+    };
+    let (report, output) = contents.and_then(|contents| compress_deferred(file, contents, backend, options))?;
+    Ok((report, Some(output)))
 }
 
-fn decompress(data: Vec<u8>) -> Result<Vec<u8>> {
-    let mut decompressor = Decompressor::new();
-    let mut dest = vec![0; data.len() * 2];
-    loop {
-        match decompressor.gzip_decompress(&*data, &mut dest) {
-            Ok(len) => {
-                dest.truncate(len);
-                return Ok(dest);
+/// The writing half of [`compress_one`], which also reports the result, or the error from
+/// either half.
+fn finish_one(file: &str, prepared: std::io::Result<(CompressionReport, Option<PendingWrite>)>, backend: &dyn CompressionBackend, cache: Option<&Mutex<Cache>>, options: &Options, style: &Style) -> std::io::Result<CompressionReport> {
+    let result = match prepared {
+        // skipped, and already reported as such
+        Ok((report, None)) => return Ok(report),
+        Ok((report, Some(output))) => output.write(options).map(|_| report),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(report) => {
+            if let Some(warning) = &report.tag_warning {
+                style.suspend(|| warn!("{}: {}, some NBT may have been lost", file, warning));
+            }
+            let elapsed_time = report.elapsed;
+            let mut details = if style.verbose { verbose_details(&report, options, style) } else { String::new() };
+            if compares_attempts(&report, options) {
+                details += &comparison(&report, style);
             }
-            Err(DecompressionError::InsufficientSpace) => {
-                dest.resize(dest.len() * 2, 0);
+            if report.tree_checked {
+                details += "\n  identical NBT tree";
             }
-            Err(e) => return Err(Error::new(InvalidData, e)),
+            if report.stripped_bytes > 0 {
+                details += &format!("\n  --strip removed {} of NBT", style.size(report.stripped_bytes));
+            }
+            let headline = if report.written && report.saved() == 0 {
+                style.paint(AnsiColors::Yellow, format!(
+                    "File {} compressed with {}, kept although it isn't smaller. Saved space: -{} ({:.1}%).",
+                    file, report.backend, style.size(report.compressed - report.original),
+                    (1.0 - ratio(report.compressed, report.original)) * 100.0
+                ))
+            } else if report.skip_reason.as_deref() == Some(IDENTICAL) {
+                style.paint(AnsiColors::Yellow, format!("File {} already optimal, unchanged.", file))
+            } else if let (true, Some(reason)) = (options.only_if_improvable, &report.skip_reason) {
+                style.paint(AnsiColors::Yellow, format!("File {} skipped, {}.", file, reason))
+            } else if report.saved() > 0 && !report.written && !options.dry_run {
+                style.paint(AnsiColors::Yellow, format!(
+                    "File {} compressed with {}, below threshold, not written. Would have saved: {} ({:.1}%).",
+                    file, report.backend, style.size(report.saved()),
+                    (1.0 - ratio(report.compressed, report.original)) * 100.0
+                ))
+            } else if report.saved() > 0 {
+                style.paint(AnsiColors::Green, format!(
+                    "File {} compressed with {}{}. Saved space: {} ({:.1}%).",
+                    file, report.backend, if options.dry_run { " (dry run, not written)" } else { "" },
+                    style.size(report.saved()), (1.0 - ratio(report.compressed, report.original)) * 100.0
+                ))
+            } else {
+                style.paint(AnsiColors::Yellow, format!("File {} not compressed. No space saved.", file))
+            };
+            if !style.json && !style.quiet {
+                file_report!(options, style, "{} \nCompression time: {:?}{}", headline, elapsed_time, details);
+            }
+            // the file is as small as this backend makes it if it was just replaced, or left
+            // alone because nothing was saved; output written elsewhere says nothing about it
+            let optimal = match report.written {
+                true => report.in_place,
+                false => report.saved() == 0,
+            };
+            if let (Some(cache), true) = (cache, optimal) {
+                if let Ok(contents) = read_file(file) {
+                    cache.lock().unwrap().record(file, &contents, &backend.describe(&[]));
+                }
+            }
+            Ok(report)
+        }
+        Err(e) => {
+            style.suspend(|| error!("{}", e));
+            Err(e)
         }
     }
 }
-fn compress_libdeflater(data: Vec<u8>, level: u8) -> Result<Vec<u8>> {
-    let mut compressor = Compressor::new(CompressionLvl::new(level.into()).unwrap());
-    let capacity = compressor.gzip_compress_bound(data.len());
-    let mut dest = vec![0; capacity];
-    match compressor.gzip_compress(&*data, &mut dest) {
-        Ok(len) => {
-            dest.truncate(len);
-            Ok(dest)
+
+/// Prints a table comparing every backend's output for one file.
+fn print_benchmark(file: &str, original: usize, attempts: &[Attempt], style: &Style) {
+    println!("File {} ({}):", file, style.size(original));
+    println!("  {:<28} {:>14} {:>7} {:>12}", "Backend", "Size", "Ratio", "Time");
+    for attempt in attempts {
+        println!(
+            "  {:<28} {:>14} {:>6.1}% {:>12}",
+            attempt.backend, style.size(attempt.size), ratio(attempt.size, original) * 100.0, format!("{:.1?}", attempt.elapsed)
+        );
+    }
+}
+
+/// Whether a dry run tried several backends on `report`'s file, as with `--best`, which
+/// is then shown as a comparison even without `--verbose`.
+fn compares_attempts(report: &CompressionReport, options: &Options) -> bool {
+    options.dry_run && report.attempts.len() > 1
+}
+
+/// Each attempt's size and time for one file, marking the smallest, which is the one
+/// `--best` would have written.
+fn comparison(report: &CompressionReport, style: &Style) -> String {
+    let chosen = report.attempts.iter().enumerate().min_by_key(|(_, attempt)| attempt.size).map(|(index, _)| index);
+    let mut table = format!("\n  {:<28} {:>14} {:>12}", "Backend", "Size", "Time");
+    for (index, attempt) in report.attempts.iter().enumerate() {
+        let marker = if Some(index) == chosen { "  <- chosen" } else { "" };
+        table += &format!("\n  {:<28} {:>14} {:>12}{}", attempt.backend, style.size(attempt.size), format!("{:.1?}", attempt.elapsed), marker);
+    }
+    table
+}
+
+/// Totals the comparisons of a dry run over every file: for each backend, how many files
+/// it would have won, and the size and time of its best attempt on each.
+fn print_comparison(reports: &[CompressionReport], options: &Options, style: &Style) {
+    let compared: Vec<&CompressionReport> = reports.iter().filter(|report| compares_attempts(report, options)).collect();
+    if compared.is_empty() {
+        return;
+    }
+    // settings like the iteration count vary between files, so attempts are grouped by name
+    let name = |attempt: &Attempt| attempt.backend.split(" (").next().unwrap_or_default().to_string();
+    let mut totals: Vec<(String, usize, usize, Duration)> = Vec::new();
+    for report in &compared {
+        let winner = report.attempts.iter().min_by_key(|attempt| attempt.size).map(name);
+        let mut best: Vec<(String, &Attempt)> = Vec::new();
+        for attempt in &report.attempts {
+            match best.iter_mut().find(|(backend, _)| *backend == name(attempt)) {
+                Some((_, smallest)) if attempt.size < smallest.size => *smallest = attempt,
+                Some(_) => {}
+                None => best.push((name(attempt), attempt)),
+            }
+        }
+        for (backend, attempt) in best {
+            let index = match totals.iter().position(|(name, ..)| *name == backend) {
+                Some(index) => index,
+                None => {
+                    totals.push((backend.clone(), 0, 0, Duration::ZERO));
+                    totals.len() - 1
+                }
+            };
+            let total = &mut totals[index];
+            total.1 += usize::from(winner.as_ref() == Some(&backend));
+            total.2 += attempt.size;
+            total.3 += attempt.elapsed;
         }
-        Err(e) => Err(Error::new(InvalidData, e)),
+    }
+
+    report!(options, "Backends compared over {} files:", compared.len());
+    report!(options, "  {:<16} {:>6} {:>14} {:>12}", "Backend", "Wins", "Size", "Time");
+    for (backend, wins, size, elapsed) in totals {
+        report!(options, "  {:<16} {:>6} {:>14} {:>12}", backend, wins, style.size(size), format!("{:.1?}", elapsed));
     }
 }
 
-fn compress_zopfli(stuff: Vec<u8>, iter: i32) -> Result<Vec<u8>> {
-    let actual_iter = if iter > 0 {
-        iter
-    } else if stuff.len() > 20_000 {
-        100
-    } else {
-        500
+/// Prints one line saying what a file is, as found by `--list`.
+fn print_info(file: &str, info: &FileInfo, style: &Style) {
+    let kind = match info.kind {
+        Some(Kind::Stream(format)) => format_name(format).to_string(),
+        Some(Kind::Bedrock) => "Bedrock level.dat".to_string(),
+        Some(Kind::Region(chunks)) => format!("region file, {} chunks", chunks),
+        None => "unknown format".to_string(),
+    };
+    let decompressed = match info.decompressed {
+        Some(size) => format!(", {} decompressed", style.size(size)),
+        None => String::new(),
     };
+    let validity = match &info.problem {
+        Some(e) => format!("invalid: {}", e),
+        None => "valid NBT".to_string(),
+    };
+    println!("{}: {}, {}{}, {}", file, kind, style.size(info.size), decompressed, validity);
+}
+
+/// Prints how many tags of each type one file holds and how many bytes they take up,
+/// largest first.
+fn print_stats(file: &str, stats: &nbt::Stats, style: &Style) {
+    let total: usize = stats.bytes.iter().sum();
+    println!("File {} ({} of NBT):", file, style.size(total));
+    println!("  {:<12} {:>10} {:>14} {:>7}", "Tag", "Count", "Size", "Share");
+    let mut types: Vec<u8> = (nbt::BYTE..=nbt::LONG_ARRAY).filter(|&tag| stats.counts[usize::from(tag)] > 0).collect();
+    types.sort_by_key(|&tag| std::cmp::Reverse(stats.bytes[usize::from(tag)]));
+    for tag in types {
+        let bytes = stats.bytes[usize::from(tag)];
+        println!(
+            "  {:<12} {:>10} {:>14} {:>6.1}%",
+            nbt::type_name(tag).unwrap(), stats.counts[usize::from(tag)], style.size(bytes), ratio(bytes, total) * 100.0
+        );
+    }
+}
 
-    let options = zopfli::Options {
-        iteration_count: NonZeroU64::new(actual_iter as u64).unwrap(),
-        ..Default::default()
+/// The extra lines printed with `--verbose`, each starting with a newline so they can be
+/// appended to a file's report and printed in one go.
+fn verbose_details(report: &CompressionReport, options: &Options, style: &Style) -> String {
+    let mut details = match (report.format, report.decompressed) {
+        (Some(format), Some(size)) => format!("\n  Input: {}, {} decompressed", format_name(format), style.size(size)),
+        _ => "\n  Input: region file, chunks recompressed separately".to_string(),
     };
+    // shown as a table instead
+    if !compares_attempts(report, options) {
+        for attempt in &report.attempts {
+            details += &format!("\n  Attempt: {}, {} in {:?}", attempt.backend, style.size(attempt.size), attempt.elapsed);
+        }
+    }
+    if report.trailing > 0 {
+        let action = if options.trim { "dropped" } else { "kept, --trim drops it" };
+        details += &format!("\n  Trailing garbage: {} after the stream, {}", style.size(report.trailing), action);
+    }
+    if let Some(header) = &report.gzip_header {
+        let action = if options.preserve_gzip_meta { "kept" } else { "dropped, --preserve-gzip-meta keeps it" };
+        if let Some(name) = &header.name {
+            details += &format!("\n  gzip file name: {}, {}", String::from_utf8_lossy(name), action);
+        }
+        if let Some(comment) = &header.comment {
+            details += &format!("\n  gzip comment: {}, {}", String::from_utf8_lossy(comment), action);
+        }
+    }
+    if let Some(reason) = &report.skip_reason {
+        details += &format!("\n  Skipped {}: {}", report.backend, reason);
+    }
+    match report.normalization_saved {
+        Some(saved) if saved > 0 => details += &format!("\n  Normalizing saved {}", style.size(saved as usize)),
+        Some(saved) => details += &format!("\n  Normalizing would have cost {}, kept the original order", style.size(saved.unsigned_abs() as usize)),
+        None => {}
+    }
+    details
+}
+
+fn format_name(format: Format) -> &'static str {
+    match format {
+        Format::Gzip => "gzip",
+        Format::Zlib => "zlib",
+        Format::Uncompressed => "uncompressed",
+        Format::Zstd => "zstd",
+        Format::Brotli => "brotli",
+        Format::Deflate => "raw deflate",
+    }
+}
+
+/// Decompresses one file and reports the result, unless the report will be JSON.
+fn decompress_one(file: &str, options: &Options, style: &Style) -> std::io::Result<CompressionReport> {
+    match decompress_file(file, options) {
+        Ok(report) => {
+            if !style.json && !style.quiet {
+                let destination = match &options.output_dir {
+                    _ if options.stdout || file == STDIN => "stdout".to_string(),
+                    Some(dir) => mirrored_path(dir, &decompressed_path(file)),
+                    None => decompressed_path(file),
+                };
+                let headline = format!(
+                    "File {} decompressed to {}{}. Size: {}.",
+                    file, destination, if options.dry_run { " (dry run, not written)" } else { "" }, style.size(report.compressed)
+                );
+                file_report!(options, style, "{} \nDecompression time: {:?}", style.paint(AnsiColors::Green, headline), report.elapsed);
+            }
+            Ok(report)
+        }
+        Err(e) => {
+            style.suspend(|| error!("{}", e));
+            Err(e)
+        }
+    }
+}
 
-    let mut output = Vec::with_capacity(stuff.len());
-    match zopfli::compress(options, Gzip, &stuff[..], &mut output) {
-        Ok(_) => {
-            output.shrink_to_fit();
-            Ok(output)
-        },
-        Err(e) => Err(e)
+/// Sets up the thread pool that files, and the chunks of region files, are spread over.
+/// `None` gives one thread per logical CPU. Both 0 and 1 mean fully sequential: one file
+/// and one chunk at a time, for timings that don't depend on what else is running.
+fn start_thread_pool(threads: Option<usize>) {
+    // rayon itself takes 0 to mean one thread per logical CPU
+    let threads = threads.map_or(0, |threads| threads.max(1));
+    if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+        error!("Error starting thread pool: {}", e);
+        std::process::exit(EXIT_FAILURE);
     }
 }
+
+/// Builds the backend picked by `args`, or by the config file if no backend flag was given,
+/// along with how many inputs zopfli ran out of time on if it has `--zopfli-timeout`.
+fn compress_backend(args: &CompressArgs, config: &Config) -> (Box<dyn CompressionBackend>, Option<Arc<AtomicUsize>>) {
+    let mut use_zopfli = args.zopfli || args.time_budget.is_some() || args.iteration_strategy.is_some();
+    let mut use_zstd = args.zstd;
+    let mut use_brotli = args.brotli || args.brotli_quality.is_some();
+    let mut use_best = args.best;
+    let profile = args.profile;
+    if let (false, Some(profile)) = (use_zopfli || use_zstd || use_brotli || use_best, profile) {
+        use_zopfli = profile.iterations().is_some();
+    } else if !(use_zopfli || use_zstd || use_brotli || use_best) {
+        match config.backend {
+            Some(ConfigBackend::Zopfli) => use_zopfli = true,
+            Some(ConfigBackend::Zstd) => use_zstd = true,
+            Some(ConfigBackend::Brotli) => use_brotli = true,
+            Some(ConfigBackend::Best) => use_best = true,
+            Some(ConfigBackend::Libdeflater) | None => {}
+        }
+    }
+
+    // the profile only sets defaults, which flags override, and which override the config
+    let level = args.level.or(profile.and_then(Profile::level)).unwrap_or(DEFAULT_LEVEL);
+    let block_splits = args.block_splits;
+    let iterations = args.iterations.or(profile.and_then(Profile::iterations)).or(config.iterations);
+    let strategy = args
+        .iteration_strategy
+        .or(args.time_budget.map(IterationStrategy::TimeBudget))
+        .unwrap_or_else(|| IterationStrategy::from_iterations(iterations.unwrap_or(-1)));
+    let zopfli = backend::zopfli(strategy, block_splits);
+    let (zopfli, timed_out): (Box<dyn CompressionBackend>, _) = match args.zopfli_timeout {
+        Some(timeout) => {
+            let limit = TimeLimit::new(Arc::from(zopfli), Box::new(Libdeflater { level }), timeout);
+            let timed_out = limit.timed_out_counter();
+            (Box::new(limit), Some(timed_out))
+        }
+        None => (zopfli, None),
+    };
+    let backend: Box<dyn CompressionBackend> = if use_best {
+        Box::new(Best {
+            candidates: vec![Box::new(Libdeflater { level }), zopfli],
+        })
+    } else if use_zstd {
+        Box::new(Zstd { level: 19 })
+    } else if use_brotli {
+        Box::new(Brotli { quality: args.brotli_quality.unwrap_or(DEFAULT_BROTLI_QUALITY) })
+    } else if use_zopfli {
+        zopfli
+    } else {
+        Box::new(Libdeflater { level })
+    };
+    let backend = if args.fallback && backend.name() != "libdeflater" {
+        Box::new(Fallback { primary: backend, secondary: Box::new(Libdeflater { level }) })
+    } else {
+        backend
+    };
+    (backend, timed_out.filter(|_| use_best || use_zopfli))
+}
@@ -0,0 +1,724 @@
+//! The NBT data format itself.
+//!
+//! A file holds a single named tag, normally a compound. Every tag is a 1-byte type,
+//! a name (a 2-byte length and the string) and a payload. Java Edition writes numbers
+//! big-endian and strings as modified UTF-8; Bedrock Edition uses little-endian and plain
+//! UTF-8, see [`Edition`].
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io::{Error, ErrorKind::InvalidData, Result};
+
+pub const END: u8 = 0;
+pub const BYTE: u8 = 1;
+pub const SHORT: u8 = 2;
+pub const INT: u8 = 3;
+pub const LONG: u8 = 4;
+pub const FLOAT: u8 = 5;
+pub const DOUBLE: u8 = 6;
+pub const BYTE_ARRAY: u8 = 7;
+pub const STRING: u8 = 8;
+pub const LIST: u8 = 9;
+pub const COMPOUND: u8 = 10;
+pub const INT_ARRAY: u8 = 11;
+pub const LONG_ARRAY: u8 = 12;
+
+/// How deeply compounds and lists may nest, the same limit Minecraft enforces.
+pub const MAX_DEPTH: usize = 512;
+
+/// Which game wrote the NBT, which decides its byte order and string encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Edition {
+    /// Big-endian, with modified UTF-8 strings.
+    #[default]
+    Java,
+    /// Little-endian, with UTF-8 strings.
+    Bedrock,
+}
+
+/// Bedrock's `level.dat` starts with two little-endian `u32`s: a storage version and the
+/// length of the NBT that follows, which is never compressed.
+pub const BEDROCK_HEADER_SIZE: usize = 8;
+
+/// A tag's payload. Compounds keep their entries in file order, and lists keep their
+/// element type so that empty lists can be written back exactly.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    List(u8, Vec<Tag>),
+    Compound(Vec<(String, Tag)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+/// Parses `data` as exactly one named Java Edition tag, returning the root's name and
+/// payload.
+pub fn parse(data: &[u8]) -> Result<(String, Tag)> {
+    parse_as(data, Edition::Java)
+}
+
+/// Like [`parse`], for NBT written by either edition.
+pub fn parse_as(data: &[u8], edition: Edition) -> Result<(String, Tag)> {
+    let mut reader = Reader { data, pos: 0, edition };
+    let tag = reader.u8()?;
+    if tag == END {
+        return Err(invalid("NBT data starts with an end tag"));
+    }
+    let name = reader.string()?;
+    let root = reader.payload(tag, 0)?;
+    reader.finish()?;
+    Ok((name, root))
+}
+
+/// Serializes a named tag, the inverse of [`parse`].
+pub fn write(name: &str, tag: &Tag) -> Result<Vec<u8>> {
+    write_as(name, tag, Edition::Java)
+}
+
+/// Like [`write`], for either edition.
+pub fn write_as(name: &str, tag: &Tag, edition: Edition) -> Result<Vec<u8>> {
+    let mut out = vec![tag.id()];
+    write_string(&mut out, name, edition)?;
+    tag.write_payload(&mut out, edition)?;
+    Ok(out)
+}
+
+/// Sorts every compound's keys, which often helps deflate find repeats. This changes the
+/// bytes but not the meaning: Minecraft doesn't care about key order. Lists keep their
+/// order, since it is significant.
+///
+/// The result is parsed again and checked against the sorted tree before it is returned.
+pub fn normalize(data: &[u8]) -> Result<Vec<u8>> {
+    let (name, mut root) = parse(data)?;
+    root.sort_keys();
+    let normalized = write(&name, &root)?;
+    let (written_name, written) = parse(&normalized)?;
+    if written_name != name || !written.equals(&root, Equality::Strict) {
+        return Err(invalid("Normalized NBT does not match the original"));
+    }
+    Ok(normalized)
+}
+
+/// Removes every tag at one of `paths`, like `Data.Player.Attributes`, relative to the
+/// root compound, so a plain key like `isLightOn` is one of the root's entries. Lists are
+/// looked through, so `sections.BlockLight` removes `BlockLight` from every compound in
+/// the `sections` list. This loses data, which is the point: it is for tags the game can
+/// rebuild, like cached lighting.
+///
+/// Returns the NBT without them and how many tags were removed, or `None` if no path
+/// matched anything, so that the original bytes can be kept as they are.
+pub fn strip(data: &[u8], paths: &[String]) -> Result<Option<(Vec<u8>, usize)>> {
+    let (name, mut root) = parse(data)?;
+    let removed: usize = paths.iter().map(|path| root.remove_path(&path.split('.').collect::<Vec<_>>())).sum();
+    if removed == 0 {
+        return Ok(None);
+    }
+    Ok(Some((write(&name, &root)?, removed)))
+}
+
+/// How two trees are compared, see [`Tag::equals`] and [`first_difference_as`]. Either
+/// way, lists and arrays have to be in the same order, and floats have to have the same
+/// bits, so a NaN matches itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Equality {
+    /// Compound entries have to be in the same order, as they would be in the same bytes.
+    Strict,
+    /// Compound entries are matched up by key, so trees that only differ in key order,
+    /// like after [`normalize`], count as the same.
+    Normalized,
+}
+
+/// Finds the first place where two trees differ, returning its path, like
+/// `Data.Player.Inventory[3].id`, or `root` for the root itself. Compound entries are
+/// matched up by key, so trees that only differ in key order, like after [`normalize`],
+/// count as the same. Lists and arrays have to be in the same order, and floats have to
+/// have the same bits, so a NaN matches itself.
+pub fn first_difference(a: &Tag, b: &Tag) -> Option<String> {
+    first_difference_as(a, b, Equality::Normalized)
+}
+
+/// Like [`first_difference`], comparing compounds as `mode` says.
+pub fn first_difference_as(a: &Tag, b: &Tag, mode: Equality) -> Option<String> {
+    difference(a, b, mode).map(|path| if path.is_empty() { "root".to_string() } else { path })
+}
+
+/// [`first_difference_as`], with an empty path for `a` and `b` themselves.
+fn difference(a: &Tag, b: &Tag, mode: Equality) -> Option<String> {
+    match (a, b) {
+        (Tag::Compound(a), Tag::Compound(b)) if mode == Equality::Strict => {
+            for ((key, value), (other_key, other)) in a.iter().zip(b) {
+                if key != other_key {
+                    return Some(key.clone());
+                }
+                if let Some(path) = difference(value, other, mode) {
+                    return Some(join_path(key.clone(), path));
+                }
+            }
+            // one is a prefix of the other, so the first entry past it only in one of them
+            a.get(b.len()).or(b.get(a.len())).map(|(key, _)| key.clone())
+        }
+        (Tag::Compound(a), Tag::Compound(b)) => {
+            let others: HashMap<&str, &Tag> = b.iter().map(|(key, value)| (key.as_str(), value)).collect();
+            for (key, value) in a {
+                match others.get(key.as_str()) {
+                    Some(other) => {
+                        if let Some(path) = difference(value, other, mode) {
+                            return Some(join_path(key.clone(), path));
+                        }
+                    }
+                    None => return Some(key.clone()),
+                }
+            }
+            // everything in `a` matched, so anything left is only in `b`
+            let keys: HashSet<&str> = a.iter().map(|(key, _)| key.as_str()).collect();
+            b.iter().find(|(key, _)| !keys.contains(key.as_str())).map(|(key, _)| key.clone())
+        }
+        (Tag::List(a_type, a), Tag::List(b_type, b)) if a_type == b_type && a.len() == b.len() => a
+            .iter()
+            .zip(b)
+            .enumerate()
+            .find_map(|(index, (a, b))| difference(a, b, mode).map(|path| join_path(format!("[{}]", index), path))),
+        (Tag::Float(a), Tag::Float(b)) => (a.to_bits() != b.to_bits()).then(String::new),
+        (Tag::Double(a), Tag::Double(b)) => (a.to_bits() != b.to_bits()).then(String::new),
+        _ => (a != b).then(String::new),
+    }
+}
+
+fn join_path(parent: String, child: String) -> String {
+    match child.starts_with('[') || child.is_empty() {
+        true => parent + &child,
+        false => parent + "." + &child,
+    }
+}
+
+/// Checks that `data` is exactly one well-formed named Java Edition tag, without building
+/// the tree.
+pub fn validate(data: &[u8]) -> Result<()> {
+    validate_as(data, Edition::Java)
+}
+
+/// Like [`validate`], for NBT written by either edition.
+pub fn validate_as(data: &[u8], edition: Edition) -> Result<()> {
+    let mut reader = Reader { data, pos: 0, edition };
+    let tag = reader.u8()?;
+    if tag == END {
+        return Err(invalid("NBT data starts with an end tag"));
+    }
+    reader.skip_string()?;
+    reader.skip_payload(tag, 0)?;
+    reader.finish()
+}
+
+/// How many tags of each type a tree holds and how many bytes they take up, see [`stats`].
+/// Both are indexed by tag type; [`END`] is never counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub counts: [usize; 13],
+    /// A list or compound is counted for its own bytes, which include the type and name of
+    /// each entry and the end tag, but not for its elements' payloads. The root tag's type
+    /// and name are counted for it too, so the sizes add up to the whole input.
+    pub bytes: [usize; 13],
+    /// How many levels below the root the deepest tag is, 0 if the root holds nothing.
+    pub depth: usize,
+}
+
+impl Stats {
+    /// How many tags there are in total, including the root.
+    pub fn tags(&self) -> usize {
+        self.counts.iter().sum()
+    }
+}
+
+/// Tallies where the bytes in `data`, one named Java Edition tag, go, without building
+/// the tree.
+pub fn stats(data: &[u8]) -> Result<Stats> {
+    stats_as(data, Edition::Java)
+}
+
+/// Like [`stats`], for NBT written by either edition.
+pub fn stats_as(data: &[u8], edition: Edition) -> Result<Stats> {
+    let mut reader = Reader { data, pos: 0, edition };
+    let mut stats = Stats::default();
+    let tag = reader.u8()?;
+    if tag == END {
+        return Err(invalid("NBT data starts with an end tag"));
+    }
+    reader.skip_string()?;
+    let header = reader.pos;
+    reader.tally_payload(tag, 0, &mut stats)?;
+    stats.bytes[usize::from(tag)] += header;
+    reader.finish()?;
+    Ok(stats)
+}
+
+/// The name of tag type `tag`, as Minecraft's code calls it, or `None` for an unknown type.
+pub fn type_name(tag: u8) -> Option<&'static str> {
+    const NAMES: [&str; 13] = [
+        "End", "Byte", "Short", "Int", "Long", "Float", "Double", "ByteArray", "String", "List", "Compound", "IntArray",
+        "LongArray",
+    ];
+    NAMES.get(usize::from(tag)).copied()
+}
+
+/// The NBT after a Bedrock `level.dat` header, if `data` starts with one whose length
+/// matches and is followed by a compound.
+pub fn bedrock_payload(data: &[u8]) -> Option<&[u8]> {
+    let (header, payload) = data.split_at_checked(BEDROCK_HEADER_SIZE)?;
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    (len == payload.len() && payload.first() == Some(&COMPOUND)).then_some(payload)
+}
+
+/// Checks that `data` is a Bedrock `level.dat`: a valid header followed by well-formed
+/// little-endian NBT.
+pub fn validate_bedrock(data: &[u8]) -> Result<()> {
+    let payload = bedrock_payload(data).ok_or_else(|| invalid("Missing or invalid Bedrock level.dat header"))?;
+    validate_as(payload, Edition::Bedrock)
+}
+
+impl Tag {
+    /// The tag's type byte.
+    pub fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => BYTE,
+            Tag::Short(_) => SHORT,
+            Tag::Int(_) => INT,
+            Tag::Long(_) => LONG,
+            Tag::Float(_) => FLOAT,
+            Tag::Double(_) => DOUBLE,
+            Tag::ByteArray(_) => BYTE_ARRAY,
+            Tag::String(_) => STRING,
+            Tag::List(..) => LIST,
+            Tag::Compound(_) => COMPOUND,
+            Tag::IntArray(_) => INT_ARRAY,
+            Tag::LongArray(_) => LONG_ARRAY,
+        }
+    }
+
+    /// Recursively sorts compound entries by key, see [`normalize`].
+    pub fn sort_keys(&mut self) {
+        match self {
+            Tag::Compound(entries) => {
+                entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+                entries.iter_mut().for_each(|(_, value)| value.sort_keys());
+            }
+            Tag::List(_, items) => items.iter_mut().for_each(Tag::sort_keys),
+            _ => {}
+        }
+    }
+
+    /// Whether `self` and `other` hold the same tree, comparing compounds as `mode` says.
+    /// Unlike `==`, floats are compared by their bits, so a NaN equals itself.
+    pub fn equals(&self, other: &Tag, mode: Equality) -> bool {
+        difference(self, other, mode).is_none()
+    }
+
+    /// Removes the tags at `path`, a list of compound keys, returning how many there were,
+    /// see [`strip`].
+    pub fn remove_path(&mut self, path: &[&str]) -> usize {
+        let Some((first, rest)) = path.split_first() else { return 0 };
+        match self {
+            Tag::Compound(entries) if rest.is_empty() => {
+                let before = entries.len();
+                entries.retain(|(key, _)| key != first);
+                before - entries.len()
+            }
+            Tag::Compound(entries) => entries.iter_mut().filter(|(key, _)| key == first).map(|(_, value)| value.remove_path(rest)).sum(),
+            Tag::List(_, items) => items.iter_mut().map(|item| item.remove_path(path)).sum(),
+            _ => 0,
+        }
+    }
+
+    fn write_payload(&self, out: &mut Vec<u8>, edition: Edition) -> Result<()> {
+        match self {
+            Tag::Byte(v) => out.extend(v.to_be_bytes()),
+            Tag::Short(v) => put(out, v.to_be_bytes(), edition),
+            Tag::Int(v) => put(out, v.to_be_bytes(), edition),
+            Tag::Long(v) => put(out, v.to_be_bytes(), edition),
+            Tag::Float(v) => put(out, v.to_be_bytes(), edition),
+            Tag::Double(v) => put(out, v.to_be_bytes(), edition),
+            Tag::ByteArray(v) => {
+                write_length(out, v.len(), edition)?;
+                out.extend(v.iter().flat_map(|v| v.to_be_bytes()));
+            }
+            Tag::IntArray(v) => {
+                write_length(out, v.len(), edition)?;
+                v.iter().for_each(|v| put(out, v.to_be_bytes(), edition));
+            }
+            Tag::LongArray(v) => {
+                write_length(out, v.len(), edition)?;
+                v.iter().for_each(|v| put(out, v.to_be_bytes(), edition));
+            }
+            Tag::String(v) => write_string(out, v, edition)?,
+            Tag::List(element, items) => {
+                if let Some(item) = items.iter().find(|item| item.id() != *element) {
+                    return Err(invalid(format!("List of type {} holds a tag of type {}", element, item.id())));
+                }
+                out.push(*element);
+                write_length(out, items.len(), edition)?;
+                for item in items {
+                    item.write_payload(out, edition)?;
+                }
+            }
+            Tag::Compound(entries) => {
+                for (key, value) in entries {
+                    out.push(value.id());
+                    write_string(out, key, edition)?;
+                    value.write_payload(out, edition)?;
+                }
+                out.push(END);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the tag as SNBT, the text format used by Minecraft's commands, like
+    /// `{Data:{version:19133}}`. With `pretty`, compounds and lists are spread over
+    /// indented lines.
+    pub fn to_snbt(&self, pretty: bool) -> String {
+        let mut out = String::new();
+        self.write_snbt(&mut out, pretty, 0);
+        out
+    }
+
+    fn write_snbt(&self, out: &mut String, pretty: bool, indent: usize) {
+        let separator = if pretty { ", " } else { "," };
+        match self {
+            Tag::Byte(v) => write!(out, "{}b", v).unwrap(),
+            Tag::Short(v) => write!(out, "{}s", v).unwrap(),
+            Tag::Int(v) => write!(out, "{}", v).unwrap(),
+            Tag::Long(v) => write!(out, "{}L", v).unwrap(),
+            Tag::Float(v) => write!(out, "{:?}f", v).unwrap(),
+            Tag::Double(v) => write!(out, "{:?}d", v).unwrap(),
+            Tag::String(v) => write_quoted(out, v),
+            Tag::ByteArray(v) => write_array(out, "B", v.iter().map(|v| format!("{}b", v)), separator),
+            Tag::IntArray(v) => write_array(out, "I", v.iter().map(|v| v.to_string()), separator),
+            Tag::LongArray(v) => write_array(out, "L", v.iter().map(|v| format!("{}L", v)), separator),
+            Tag::List(_, items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    out.push_str(if i > 0 { "," } else { "" });
+                    new_line(out, pretty, indent + 1);
+                    item.write_snbt(out, pretty, indent + 1);
+                }
+                if !items.is_empty() {
+                    new_line(out, pretty, indent);
+                }
+                out.push(']');
+            }
+            Tag::Compound(entries) => {
+                out.push('{');
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    out.push_str(if i > 0 { "," } else { "" });
+                    new_line(out, pretty, indent + 1);
+                    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || "._+-".contains(c)) {
+                        out.push_str(key);
+                    } else {
+                        write_quoted(out, key);
+                    }
+                    out.push_str(if pretty { ": " } else { ":" });
+                    value.write_snbt(out, pretty, indent + 1);
+                }
+                if !entries.is_empty() {
+                    new_line(out, pretty, indent);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Appends a big-endian value in `edition`'s byte order.
+fn put<const N: usize>(out: &mut Vec<u8>, mut bytes: [u8; N], edition: Edition) {
+    if edition == Edition::Bedrock {
+        bytes.reverse();
+    }
+    out.extend(bytes);
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize, edition: Edition) -> Result<()> {
+    let len = i32::try_from(len).map_err(|_| invalid("Array or list is too long for NBT"))?;
+    put(out, len.to_be_bytes(), edition);
+    Ok(())
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str, edition: Edition) -> Result<()> {
+    let bytes = match edition {
+        Edition::Java => encode_mutf8(value),
+        Edition::Bedrock => value.as_bytes().to_vec(),
+    };
+    let len = u16::try_from(bytes.len()).map_err(|_| invalid("String is too long for NBT"))?;
+    put(out, len.to_be_bytes(), edition);
+    out.extend(bytes);
+    Ok(())
+}
+
+fn new_line(out: &mut String, pretty: bool, indent: usize) {
+    if pretty {
+        out.push('\n');
+        out.push_str(&"    ".repeat(indent));
+    }
+}
+
+fn write_quoted(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+fn write_array(out: &mut String, prefix: &str, values: impl Iterator<Item = String>, separator: &str) {
+    write!(out, "[{};", prefix).unwrap();
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            out.push_str(separator);
+        }
+        out.push_str(value.as_str());
+    }
+    out.push(']');
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    edition: Edition,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len())
+            .ok_or_else(|| invalid("NBT data ends in the middle of a tag"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Reads `N` bytes, reordered to big-endian if necessary.
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut bytes: [u8; N] = self.take(N)?.try_into().unwrap();
+        if self.edition == Edition::Bedrock {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.pos != self.data.len() {
+            return Err(invalid(format!("{} bytes of trailing data after the root tag", self.data.len() - self.pos)));
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = u16::from_be_bytes(self.array()?);
+        let bytes = self.take(len.into())?;
+        match self.edition {
+            Edition::Java => decode_mutf8(bytes),
+            Edition::Bedrock => String::from_utf8(bytes.to_vec()).map_err(|_| invalid("String is not valid UTF-8")),
+        }
+    }
+
+    /// Reads a length-prefixed array of `N`-byte values.
+    fn values<const N: usize, T>(&mut self, convert: fn([u8; N]) -> T) -> Result<Vec<T>> {
+        let len = self.length()?;
+        let size = len.checked_mul(N).ok_or_else(|| invalid("Array is too long"))?;
+        let reverse = self.edition == Edition::Bedrock;
+        Ok(self.take(size)?.chunks_exact(N).map(|c| {
+            let mut bytes: [u8; N] = c.try_into().unwrap();
+            if reverse {
+                bytes.reverse();
+            }
+            convert(bytes)
+        }).collect())
+    }
+
+    fn payload(&mut self, tag: u8, depth: usize) -> Result<Tag> {
+        Ok(match tag {
+            BYTE => Tag::Byte(i8::from_be_bytes(self.array()?)),
+            SHORT => Tag::Short(i16::from_be_bytes(self.array()?)),
+            INT => Tag::Int(i32::from_be_bytes(self.array()?)),
+            LONG => Tag::Long(i64::from_be_bytes(self.array()?)),
+            FLOAT => Tag::Float(f32::from_be_bytes(self.array()?)),
+            DOUBLE => Tag::Double(f64::from_be_bytes(self.array()?)),
+            BYTE_ARRAY => Tag::ByteArray(self.values(i8::from_be_bytes)?),
+            INT_ARRAY => Tag::IntArray(self.values(i32::from_be_bytes)?),
+            LONG_ARRAY => Tag::LongArray(self.values(i64::from_be_bytes)?),
+            STRING => Tag::String(self.string()?),
+            LIST | COMPOUND if depth >= MAX_DEPTH => return Err(invalid("NBT is nested too deeply")),
+            LIST => {
+                let element = self.u8()?;
+                let len = self.length()?;
+                if element == END && len > 0 {
+                    return Err(invalid("List of end tags is not empty"));
+                }
+                // every element takes at least a byte, so a bogus length can't allocate much
+                let mut items = Vec::with_capacity(len.min(self.data.len() - self.pos));
+                for _ in 0..len {
+                    items.push(self.payload(element, depth + 1)?);
+                }
+                Tag::List(element, items)
+            }
+            COMPOUND => {
+                let mut entries = Vec::new();
+                loop {
+                    let tag = self.u8()?;
+                    if tag == END {
+                        break Tag::Compound(entries);
+                    }
+                    let name = self.string()?;
+                    entries.push((name, self.payload(tag, depth + 1)?));
+                }
+            }
+            _ => return Err(invalid(format!("Unknown tag type {}", tag))),
+        })
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        let len = i32::from_be_bytes(self.array()?);
+        usize::try_from(len).map_err(|_| invalid(format!("Negative length {}", len)))
+    }
+
+    fn skip_string(&mut self) -> Result<()> {
+        let len = u16::from_be_bytes(self.array()?);
+        self.take(len.into())?;
+        Ok(())
+    }
+
+    fn skip_array(&mut self, element_size: usize) -> Result<()> {
+        let len = self.length()?;
+        let size = len.checked_mul(element_size).ok_or_else(|| invalid("Array is too long"))?;
+        self.take(size)?;
+        Ok(())
+    }
+
+    fn skip_payload(&mut self, tag: u8, depth: usize) -> Result<()> {
+        match tag {
+            BYTE => self.take(1).map(drop),
+            SHORT => self.take(2).map(drop),
+            INT | FLOAT => self.take(4).map(drop),
+            LONG | DOUBLE => self.take(8).map(drop),
+            BYTE_ARRAY => self.skip_array(1),
+            INT_ARRAY => self.skip_array(4),
+            LONG_ARRAY => self.skip_array(8),
+            STRING => self.skip_string(),
+            LIST | COMPOUND if depth >= MAX_DEPTH => Err(invalid("NBT is nested too deeply")),
+            LIST => {
+                let element = self.u8()?;
+                let len = self.length()?;
+                if element == END && len > 0 {
+                    return Err(invalid("List of end tags is not empty"));
+                }
+                (0..len).try_for_each(|_| self.skip_payload(element, depth + 1))
+            }
+            COMPOUND => loop {
+                let tag = self.u8()?;
+                if tag == END {
+                    return Ok(());
+                }
+                self.skip_string()?;
+                self.skip_payload(tag, depth + 1)?;
+            },
+            _ => Err(invalid(format!("Unknown tag type {}", tag))),
+        }
+    }
+
+    /// Like [`skip_payload`](Self::skip_payload), but adds the payload to `stats`,
+    /// returning its total size including any elements.
+    fn tally_payload(&mut self, tag: u8, depth: usize, stats: &mut Stats) -> Result<usize> {
+        let start = self.pos;
+        let mut nested = 0;
+        match tag {
+            LIST | COMPOUND if depth >= MAX_DEPTH => return Err(invalid("NBT is nested too deeply")),
+            LIST => {
+                let element = self.u8()?;
+                let len = self.length()?;
+                if element == END && len > 0 {
+                    return Err(invalid("List of end tags is not empty"));
+                }
+                for _ in 0..len {
+                    nested += self.tally_payload(element, depth + 1, stats)?;
+                }
+            }
+            COMPOUND => loop {
+                let tag = self.u8()?;
+                if tag == END {
+                    break;
+                }
+                self.skip_string()?;
+                nested += self.tally_payload(tag, depth + 1, stats)?;
+            },
+            _ => self.skip_payload(tag, depth)?,
+        }
+        let size = self.pos - start;
+        stats.counts[usize::from(tag)] += 1;
+        stats.depth = stats.depth.max(depth);
+        stats.bytes[usize::from(tag)] += size - nested;
+        Ok(size)
+    }
+}
+
+/// Decodes Java's modified UTF-8, which encodes NUL as two bytes and anything outside
+/// the BMP as a surrogate pair of 3-byte sequences.
+fn decode_mutf8(bytes: &[u8]) -> Result<String> {
+    // plain ASCII and most other text is also valid UTF-8, and means the same
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        if !s.chars().any(|c| c.len_utf8() == 4) {
+            return Ok(s.to_string());
+        }
+    }
+
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        let (unit, len) = match b {
+            0x00..=0x7f => (u16::from(b), 1),
+            0xc0..=0xdf if i + 1 < bytes.len() => (u16::from(b & 0x1f) << 6 | u16::from(bytes[i + 1] & 0x3f), 2),
+            0xe0..=0xef if i + 2 < bytes.len() => {
+                (u16::from(b & 0x0f) << 12 | u16::from(bytes[i + 1] & 0x3f) << 6 | u16::from(bytes[i + 2] & 0x3f), 3)
+            }
+            _ => return Err(invalid("String is not valid modified UTF-8")),
+        };
+        if bytes[i + 1..i + len].iter().any(|&c| c & 0xc0 != 0x80) {
+            return Err(invalid("String is not valid modified UTF-8"));
+        }
+        units.push(unit);
+        i += len;
+    }
+    String::from_utf16(&units).map_err(|_| invalid("String is not valid modified UTF-8"))
+}
+
+/// Encodes `value` as Java's modified UTF-8, see [`decode_mutf8`].
+fn encode_mutf8(value: &str) -> Vec<u8> {
+    if !value.contains('\0') && !value.chars().any(|c| c.len_utf8() == 4) {
+        return value.as_bytes().to_vec();
+    }
+
+    let mut out = Vec::with_capacity(value.len() + 2);
+    for unit in value.encode_utf16() {
+        match unit {
+            0x01..=0x7f => out.push(unit as u8),
+            0x00 | 0x80..=0x7ff => out.extend([0xc0 | (unit >> 6) as u8, 0x80 | (unit & 0x3f) as u8]),
+            _ => out.extend([0xe0 | (unit >> 12) as u8, 0x80 | (unit >> 6 & 0x3f) as u8, 0x80 | (unit & 0x3f) as u8]),
+        }
+    }
+    out
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(InvalidData, message.into())
+}
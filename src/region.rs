@@ -0,0 +1,313 @@
+//! Region files: Anvil (`.mca`) and the older McRegion (`.mcr`), which share a layout.
+//!
+//! A region file starts with an 8 KiB header: 1024 big-endian location entries
+//! (a 3-byte sector offset and a 1-byte sector count) followed by 1024
+//! timestamps. Each chunk lives at its sector offset as a 4-byte length, a
+//! 1-byte compression type and the compressed payload, padded to a whole
+//! number of 4 KiB sectors.
+
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind::InvalidData, Result};
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::backend::CompressionBackend;
+use crate::codec::{self, Format};
+use crate::error::context;
+use crate::{nbt, walk};
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const CHUNK_COUNT: usize = 1024;
+const HEADER_SIZE: usize = 2 * SECTOR_SIZE;
+
+/// Chunk compression types, as stored in the byte after each chunk's length.
+pub const GZIP: u8 = 1;
+pub const ZLIB: u8 = 2;
+pub const UNCOMPRESSED: u8 = 3;
+
+/// A single chunk, with its payload exactly as stored on disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub compression: u8,
+    pub payload: Cow<'a, [u8]>,
+}
+
+/// A parsed region file, borrowing chunk payloads from the original bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region<'a> {
+    pub chunks: Vec<Option<Chunk<'a>>>,
+    pub timestamps: Vec<u32>,
+}
+
+/// Whether `path` looks like a region file, or a backup of one, going by its extension.
+pub fn is_region_file(path: &str) -> bool {
+    let original = walk::backup_of(Path::new(path));
+    original.as_deref().unwrap_or(Path::new(path)).extension().is_some_and(|ext| ext == "mca" || ext == "mcr")
+}
+
+impl<'a> Region<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Region<'a>> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::new(InvalidData, "Region file is smaller than its header"));
+        }
+
+        let mut chunks = Vec::with_capacity(CHUNK_COUNT);
+        let mut timestamps = Vec::with_capacity(CHUNK_COUNT);
+
+        for index in 0..CHUNK_COUNT {
+            let location = read_u32(data, index * 4);
+            timestamps.push(read_u32(data, SECTOR_SIZE + index * 4));
+
+            if location == 0 {
+                chunks.push(None);
+                continue;
+            }
+
+            let offset = (location >> 8) as usize * SECTOR_SIZE;
+            if offset < HEADER_SIZE || offset + 5 > data.len() {
+                return Err(Error::new(InvalidData, format!("Chunk {} points outside the file", index)));
+            }
+
+            let length = read_u32(data, offset) as usize;
+            let end = offset + 4 + length;
+            if length == 0 || end > data.len() {
+                return Err(Error::new(InvalidData, format!("Chunk {} has an invalid length", index)));
+            }
+
+            chunks.push(Some(Chunk {
+                compression: data[offset + 4],
+                payload: Cow::Borrowed(&data[offset + 5..end]),
+            }));
+        }
+
+        Ok(Region { chunks, timestamps })
+    }
+
+    /// Serializes the region, laying chunks out back to back in slot order.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut header = vec![0; HEADER_SIZE];
+        let mut body = Vec::new();
+
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            header[SECTOR_SIZE + index * 4..SECTOR_SIZE + index * 4 + 4]
+                .copy_from_slice(&self.timestamps[index].to_be_bytes());
+
+            let Some(chunk) = chunk else { continue };
+
+            let offset = (HEADER_SIZE + body.len()) / SECTOR_SIZE;
+            let length = chunk.payload.len() + 1;
+            let sectors = (length + 4).div_ceil(SECTOR_SIZE);
+            if sectors > 0xff || offset > 0xff_ffff {
+                return Err(Error::new(InvalidData, format!("Chunk {} is too large for a region file", index)));
+            }
+
+            let location = (offset as u32) << 8 | sectors as u32;
+            header[index * 4..index * 4 + 4].copy_from_slice(&location.to_be_bytes());
+
+            body.extend_from_slice(&(length as u32).to_be_bytes());
+            body.push(chunk.compression);
+            body.extend_from_slice(&chunk.payload);
+            body.resize(body.len().next_multiple_of(SECTOR_SIZE), 0);
+        }
+
+        header.extend(body);
+        Ok(header)
+    }
+}
+
+/// Recompresses every gzip and zlib chunk in a region file with `backend`, in parallel,
+/// keeping each chunk's compression type. Other chunks are copied unchanged.
+pub fn recompress(data: &[u8], backend: &dyn CompressionBackend) -> Result<Vec<u8>> {
+    recompress_sized(data, backend).map(|(output, _)| output)
+}
+
+/// Like [`recompress`], but also returns the total decompressed size of the chunks that
+/// were recompressed.
+pub fn recompress_sized(data: &[u8], backend: &dyn CompressionBackend) -> Result<(Vec<u8>, usize)> {
+    recompress_stripped(data, backend, &[]).map(|(output, decompressed, _)| (output, decompressed))
+}
+
+/// Like [`recompress_sized`], but removes the tags at `paths` from every chunk first, see
+/// [`nbt::strip`]. A chunk that loses any is always rewritten, even if it ends up larger.
+/// Also returns how many bytes of NBT were removed, in total.
+pub fn recompress_stripped(data: &[u8], backend: &dyn CompressionBackend, paths: &[String]) -> Result<(Vec<u8>, usize, usize)> {
+    // Minecraft creates empty region files before any chunk is saved
+    if data.is_empty() {
+        return Ok((Vec::new(), 0, 0));
+    }
+
+    let mut region = Region::parse(data)?;
+    // chunks are independent, and to_bytes lays them out in slot order afterwards, so
+    // the output doesn't depend on which thread finishes first
+    // each worker keeps its buffers for the raw and recompressed chunk, so only the
+    // chunks that are replaced need an allocation of their own
+    let scratch = || (Vec::new(), Vec::new());
+    let (decompressed, removed) = region.chunks.par_iter_mut().enumerate().map_init(scratch, |(raw, recompressed), (index, slot)| -> Result<(usize, usize)> {
+        let Some(chunk) = slot else { return Ok((0, 0)) };
+        let format = match chunk.compression {
+            GZIP => Format::Gzip,
+            ZLIB => Format::Zlib,
+            _ => return Ok((0, 0)),
+        };
+
+        let mut recompress = || -> Result<Option<Vec<u8>>> {
+            codec::decompress_into(&chunk.payload, format, raw)?;
+            let stripped = match paths.is_empty() {
+                true => None,
+                false => nbt::strip(raw, paths)?.map(|(stripped, _)| stripped),
+            };
+            backend.compress_into(stripped.as_deref().unwrap_or(raw), format, recompressed)?;
+            Ok(stripped)
+        };
+        let stripped = recompress().map_err(|e| context(e, format!("Chunk {}", index)))?;
+
+        let removed = stripped.map_or(0, |stripped| raw.len() - stripped.len());
+        if removed > 0 || recompressed.len() < chunk.payload.len() {
+            chunk.payload = Cow::Owned(recompressed.clone());
+        }
+        Ok((raw.len(), removed))
+    })
+    .try_reduce(|| (0, 0), |a, b| Ok((a.0 + b.0, a.1 + b.1)))?;
+
+    Ok((region.to_bytes()?, decompressed, removed))
+}
+
+/// The header slot of the chunk at `x`, `z`. Only the lowest 5 bits of each are used, so
+/// they can be world chunk coordinates as well as ones within the region.
+pub fn chunk_index(x: i32, z: i32) -> usize {
+    (x & 31) as usize + (z & 31) as usize * 32
+}
+
+/// Like [`recompress`], but only for the chunks in the header slots `indices`, see
+/// [`chunk_index`]. Every other byte of the file is copied unchanged: a chunk that gets
+/// smaller is written over the start of its own sectors, and the rest of them zeroed.
+/// The file keeps its size, so this also returns how many payload bytes were freed.
+pub fn recompress_chunks(data: &[u8], backend: &dyn CompressionBackend, indices: &[usize]) -> Result<(Vec<u8>, usize)> {
+    let region = Region::parse(data)?;
+    let mut output = data.to_vec();
+    let mut freed = 0;
+    for &index in indices {
+        let Some(chunk) = region.chunks.get(index).and_then(Option::as_ref) else {
+            return Err(Error::new(InvalidData, format!("Chunk {} isn't in the region file", index)));
+        };
+        let format = match chunk.compression {
+            GZIP => Format::Gzip,
+            ZLIB => Format::Zlib,
+            _ => continue,
+        };
+
+        let recompressed = codec::decompress_as(&chunk.payload, format)
+            .and_then(|raw| backend.compress_as(&raw, format))
+            .map_err(|e| context(e, format!("Chunk {}", index)))?;
+        if recompressed.len() >= chunk.payload.len() {
+            continue;
+        }
+
+        let location = read_u32(data, index * 4);
+        let offset = (location >> 8) as usize * SECTOR_SIZE;
+        let length = recompressed.len() + 1;
+        let sectors = (length + 4).div_ceil(SECTOR_SIZE) as u32;
+        output[index * 4..index * 4 + 4].copy_from_slice(&(location & !0xff | sectors).to_be_bytes());
+        output[offset..offset + 4].copy_from_slice(&(length as u32).to_be_bytes());
+        let end = offset + 5 + recompressed.len();
+        output[offset + 5..end].copy_from_slice(&recompressed);
+        output[end..offset + 5 + chunk.payload.len()].fill(0);
+        freed += chunk.payload.len() - recompressed.len();
+    }
+    Ok((output, freed))
+}
+
+/// The total size of the gzip and zlib chunks in a region file, as they are and as
+/// `backend` compresses them, to judge whether recompressing could help at all.
+pub fn payload_sizes(data: &[u8], backend: &dyn CompressionBackend) -> Result<(usize, usize)> {
+    if data.is_empty() {
+        return Ok((0, 0));
+    }
+
+    let region = Region::parse(data)?;
+    let scratch = || (Vec::new(), Vec::new());
+    region.chunks.par_iter().enumerate().map_init(scratch, |(raw, compressed), (index, slot)| -> Result<(usize, usize)> {
+        let Some(chunk) = slot else { return Ok((0, 0)) };
+        let format = match chunk.compression {
+            GZIP => Format::Gzip,
+            ZLIB => Format::Zlib,
+            _ => return Ok((0, 0)),
+        };
+        codec::decompress_into(&chunk.payload, format, raw)
+            .and_then(|()| backend.compress_into(raw, format, compressed))
+            .map_err(|e| context(e, format!("Chunk {}", index)))?;
+        Ok((chunk.payload.len(), compressed.len()))
+    })
+    .try_reduce(|| (0, 0), |a, b| Ok((a.0 + b.0, a.1 + b.1)))
+}
+
+/// Decompresses every chunk in a region file and checks that it is valid NBT, returning
+/// how many chunks there are and their total decompressed size. Chunks in a compression
+/// type this crate can't read are counted, but not checked.
+pub fn inspect(data: &[u8]) -> Result<(usize, usize)> {
+    if data.is_empty() {
+        return Ok((0, 0));
+    }
+
+    let region = Region::parse(data)?;
+    let mut count = 0;
+    let mut decompressed = 0;
+    let mut raw = Vec::new();
+    for (index, chunk) in region.chunks.iter().enumerate() {
+        let Some(chunk) = chunk else { continue };
+        count += 1;
+        let format = match chunk.compression {
+            GZIP => Format::Gzip,
+            ZLIB => Format::Zlib,
+            UNCOMPRESSED => Format::Uncompressed,
+            _ => continue,
+        };
+        codec::decompress_into(&chunk.payload, format, &mut raw)
+            .and_then(|()| nbt::validate(&raw))
+            .map_err(|e| context(e, format!("Chunk {}", index)))?;
+        decompressed += raw.len();
+    }
+    Ok((count, decompressed))
+}
+
+/// Checks that every chunk in `recompressed` has the same compression type and
+/// decompresses to the same data as the corresponding chunk in `original`.
+pub fn verify(original: &[u8], recompressed: &[u8]) -> Result<()> {
+    if original.is_empty() && recompressed.is_empty() {
+        return Ok(());
+    }
+
+    let original = Region::parse(original)?;
+    let recompressed = Region::parse(recompressed)?;
+    let (mut expected, mut actual) = (Vec::new(), Vec::new());
+    let mut decompressed_equal = |a: &[u8], b: &[u8], format| -> Result<bool> {
+        codec::decompress_into(a, format, &mut expected)?;
+        codec::decompress_into(b, format, &mut actual)?;
+        Ok(expected == actual)
+    };
+    for (index, (a, b)) in original.chunks.iter().zip(&recompressed.chunks).enumerate() {
+        let same = match (a, b) {
+            (None, None) => true,
+            (Some(a), Some(b)) if a.compression != b.compression => false,
+            (Some(a), Some(b)) => match a.compression {
+                GZIP => decompressed_equal(&a.payload, &b.payload, Format::Gzip)?,
+                ZLIB => decompressed_equal(&a.payload, &b.payload, Format::Zlib)?,
+                _ => a.payload == b.payload,
+            },
+            _ => false,
+        };
+        if !same {
+            return Err(Error::new(InvalidData, format!("Chunk {} does not match the original", index)));
+        }
+    }
+
+    if original.timestamps != recompressed.timestamps {
+        return Err(Error::new(InvalidData, "Chunk timestamps do not match the original"));
+    }
+    Ok(())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
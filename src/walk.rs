@@ -0,0 +1,176 @@
+//! Finding NBT files inside directories, expanding glob patterns, and reading lists of
+//! files.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind::InvalidInput, Read, Result};
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files Minecraft stores as NBT, used when none are given explicitly.
+/// WorldEdit's `.schematic` and Litematica's `.litematic` files are gzip'd NBT too.
+pub const DEFAULT_EXTENSIONS: &[&str] = &["dat", "nbt", "mca", "mcr", "schematic", "litematic"];
+
+/// Recursively collects every file under `dir` whose extension is in `extensions`
+/// (compared case-insensitively), sorted by path. Backups, see [`backup_of`], are left
+/// out unless `include_backups` is set, in which case they are collected if the file
+/// they are a copy of would be.
+///
+/// Symlinks are skipped unless `follow_symlinks` is set, so that a link back up the tree
+/// can't cause a cycle and a link out of it can't pull in files from elsewhere. When they
+/// are followed, every directory and file is only visited once, by its canonical path, so
+/// a cycle ends where it meets a directory already seen and a file reachable through
+/// several links is only listed once. Broken links are skipped.
+pub fn collect_files(dir: &Path, extensions: &[String], follow_symlinks: bool, include_backups: bool) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    if follow_symlinks {
+        visited.insert(fs::canonicalize(dir)?);
+    }
+    let wanted = |path: &Path| match backup_of(path) {
+        Some(original) => include_backups && has_extension(&original, extensions),
+        None => has_extension(path, extensions),
+    };
+    walk(dir, &wanted, follow_symlinks.then_some(&mut visited), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// If `path` is a backup copy of another file, the path of that file. These are
+/// Minecraft's own `level.dat_old` and the like, which it keeps from before the last
+/// save, and `.bak` copies, as `--backup` makes, numbered like `.bak1` by tools that keep
+/// several.
+pub fn backup_of(path: &Path) -> Option<PathBuf> {
+    let ext = path.extension()?.to_str()?;
+    if ext.eq_ignore_ascii_case("dat_old") {
+        return Some(path.with_extension("dat"));
+    }
+    let number = ext.get(3..).filter(|_| ext[..3].eq_ignore_ascii_case("bak"))?;
+    number.bytes().all(|b| b.is_ascii_digit()).then(|| path.with_extension(""))
+}
+
+/// Whether `arg` contains glob metacharacters. Shells normally expand these before the
+/// program sees them, but Windows' `cmd` doesn't.
+pub fn is_glob(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+/// Expands a glob pattern into the paths it matches, sorted. An empty result means
+/// nothing matched; an invalid pattern is an error.
+pub fn expand_glob(pattern: &str) -> Result<Vec<String>> {
+    let paths = glob::glob(pattern).map_err(|e| Error::new(InvalidInput, format!("Invalid pattern {}: {}", pattern, e)))?;
+    let mut files = Vec::new();
+    for path in paths {
+        let path = path.map_err(Error::from)?;
+        files.push(path.to_string_lossy().into_owned());
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads a list of paths, one per line, from `path`, or from stdin if it is `-`. Blank
+/// lines and lines starting with `#` are skipped, as is whitespace around each path.
+/// The paths aren't checked or expanded.
+pub fn read_file_list(path: &str) -> Result<Vec<String>> {
+    let mut text = String::new();
+    if path == "-" {
+        std::io::stdin().lock().read_to_string(&mut text)?;
+    } else {
+        text = fs::read_to_string(path)?;
+    }
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Whether `dir` is the root of a world save, going by the `level.dat` every world has.
+pub fn is_world(dir: &Path) -> bool {
+    dir.join("level.dat").is_file()
+}
+
+/// Where a file sits in a world save, as used for subtotals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    /// `level.dat` and its backup `level.dat_old`.
+    Level,
+    /// Region files, for terrain in `region/` as well as `entities/` and `poi/`, in every
+    /// dimension's directory.
+    Region,
+    /// One file per player in `playerdata/`, or `players/` before 1.7.
+    PlayerData,
+    /// Maps, raids, scoreboards and the like in `data/`.
+    Data,
+    /// Anything else, such as structures and schematics.
+    Other,
+}
+
+impl Category {
+    /// Classifies `path` by its name and the directory it is in.
+    pub fn of(path: &str) -> Category {
+        let path = Path::new(path);
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let parent = path.parent().and_then(Path::file_name).and_then(|name| name.to_str()).unwrap_or_default();
+        let original = backup_of(path);
+        let extension = original.as_deref().unwrap_or(path).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        match (name, parent) {
+            ("level.dat" | "level.dat_old", _) => Category::Level,
+            (_, "region" | "entities" | "poi") if extension == "mca" || extension == "mcr" => Category::Region,
+            (_, "playerdata" | "players") => Category::PlayerData,
+            (_, "data") => Category::Data,
+            _ => Category::Other,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Level => "level",
+            Category::Region => "region",
+            Category::PlayerData => "playerdata",
+            Category::Data => "data",
+            Category::Other => "other",
+        }
+    }
+}
+
+/// `visited` holds the canonical paths seen so far when symlinks are followed, and is
+/// `None` when they aren't.
+fn walk(dir: &Path, wanted: &dyn Fn(&Path) -> bool, mut visited: Option<&mut HashSet<PathBuf>>, files: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let mut file_type = entry.file_type()?;
+        let path = entry.path();
+
+        if let Some(visited) = visited.as_deref_mut() {
+            if file_type.is_symlink() {
+                match fs::metadata(&path) {
+                    Ok(metadata) => file_type = metadata.file_type(),
+                    Err(e) => {
+                        log::debug!("Skipping broken link {}: {}", path.display(), e);
+                        continue;
+                    }
+                }
+            }
+            let wanted = file_type.is_dir() || (file_type.is_file() && wanted(&path));
+            if wanted && !visited.insert(fs::canonicalize(&path)?) {
+                log::debug!("Skipping {}, already visited", path.display());
+                continue;
+            }
+        }
+
+        if file_type.is_dir() {
+            walk(&path, wanted, visited.as_deref_mut(), files)?;
+        } else if file_type.is_file() && wanted(&path) {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::{Error, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nbt_compress::backend::{Best, CompressionBackend, Dedupe, Fallback, Libdeflater, Lru, TimeLimit, TimedZopfli, Zopfli};
+use nbt_compress::codec::{self, Format};
+
+struct Fixed(&'static str, usize);
+
+impl CompressionBackend for Fixed {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn compress_as(&self, _data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        Ok(vec![0; self.1])
+    }
+}
+
+struct Failing;
+
+impl CompressionBackend for Failing {
+    fn name(&self) -> &str {
+        "failing"
+    }
+
+    fn compress_as(&self, _data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        Err(Error::other("out of cheese"))
+    }
+}
+
+#[test]
+fn best_keeps_smallest_output() {
+    let best = Best {
+        candidates: vec![Box::new(Fixed("big", 10)), Box::new(Fixed("small", 3)), Box::new(Fixed("medium", 5))],
+    };
+
+    let (output, name) = best.compress_named(b"", Format::Gzip).unwrap();
+    assert_eq!((output.len(), name.as_str()), (3, "small"));
+    assert_eq!(best.compress(b"").unwrap().len(), 3);
+}
+
+#[test]
+fn best_of_real_backends_round_trips() {
+    let raw: Vec<u8> = (0..2000u32).flat_map(|i| (i % 100).to_be_bytes()).collect();
+    let best = Best {
+        candidates: vec![Box::new(Libdeflater { level: 12 }), Box::new(Zopfli { iterations: 5, block_splits: codec::DEFAULT_BLOCK_SPLITS })],
+    };
+
+    let (output, name) = best.compress_named(&raw, Format::Zlib).unwrap();
+    assert!(name == "libdeflater" || name == "zopfli");
+    assert_eq!(codec::decompress_as(&output, Format::Zlib).unwrap(), raw);
+}
+
+#[test]
+fn timed_zopfli_reports_iterations() {
+    let raw: Vec<u8> = (0..2000u32).flat_map(|i| (i % 100).to_be_bytes()).collect();
+    let backend = TimedZopfli { budget: Duration::ZERO, block_splits: codec::DEFAULT_BLOCK_SPLITS };
+
+    let (output, name) = backend.compress_named(&raw, Format::Gzip).unwrap();
+    assert_eq!(name, "zopfli (1 iterations)");
+    assert_eq!(codec::decompress(&output).unwrap().1, raw);
+}
+
+#[test]
+fn errors_propagate_without_fallback() {
+    let best = Best { candidates: vec![Box::new(Fixed("small", 3)), Box::new(Failing)] };
+    assert!(best.compress(b"").is_err());
+}
+
+#[test]
+fn fallback_is_reported() {
+    let backend = Fallback { primary: Box::new(Failing), secondary: Box::new(Fixed("small", 3)) };
+    let (output, name) = backend.compress_named(b"", Format::Gzip).unwrap();
+    assert_eq!(output.len(), 3);
+    assert_eq!(name, "small (fallback, failing failed: out of cheese)");
+
+    let backend = Fallback { primary: Box::new(Fixed("big", 10)), secondary: Box::new(Fixed("small", 3)) };
+    assert_eq!(backend.compress_named(b"", Format::Gzip).unwrap().1, "big");
+}
+
+#[test]
+fn best_records_every_attempt() {
+    let best = Best { candidates: vec![Box::new(Fixed("big", 10)), Box::new(Fixed("small", 3))] };
+
+    let compressed = best.compress_traced(b"", Format::Gzip).unwrap();
+    assert_eq!(compressed.backend, "small");
+    let attempts: Vec<_> = compressed.attempts.iter().map(|a| (a.backend.as_str(), a.size)).collect();
+    assert_eq!(attempts, [("big", 10), ("small", 3)]);
+}
+
+#[test]
+fn descriptions_include_settings() {
+    assert_eq!(Libdeflater { level: 12 }.describe(b""), "libdeflater (level 12)");
+    assert_eq!(Zopfli { iterations: 15, block_splits: codec::DEFAULT_BLOCK_SPLITS }.describe(b""), "zopfli (15 iterations)");
+    assert_eq!(Zopfli { iterations: -1, block_splits: codec::DEFAULT_BLOCK_SPLITS }.describe(b""), "zopfli (500 iterations)");
+    assert_eq!(Zopfli { iterations: -1, block_splits: codec::DEFAULT_BLOCK_SPLITS }.describe(&[0; 20_001]), "zopfli (100 iterations)");
+    assert_eq!(Zopfli { iterations: 15, block_splits: 0 }.describe(b""), "zopfli (15 iterations, unlimited block splits)");
+    assert_eq!(Zopfli { iterations: 15, block_splits: 3 }.describe(b""), "zopfli (15 iterations, 3 block splits)");
+}
+
+#[test]
+fn dedupe_compresses_identical_data_once() {
+    let inner = Libdeflater { level: 6 };
+    let dedupe = Dedupe::new(&inner);
+
+    let first = dedupe.compress_traced(b"\x0a\x00\x00\x00", Format::Gzip).unwrap();
+    let again = dedupe.compress_traced(b"\x0a\x00\x00\x00", Format::Gzip).unwrap();
+    assert_eq!(again.data, first.data);
+    assert_eq!(again.backend, "libdeflater (duplicate)");
+    assert!(again.attempts.is_empty());
+
+    // the same data in another format is compressed again
+    dedupe.compress_traced(b"\x0a\x00\x00\x00", Format::Zlib).unwrap();
+    dedupe.compress_traced(b"\x0a\x00\x00", Format::Gzip).unwrap();
+    assert_eq!(dedupe.duplicates(), 1);
+}
+
+/// Counts how many times it is asked to compress something.
+struct Counting(AtomicUsize);
+
+impl CompressionBackend for Counting {
+    fn name(&self) -> &str {
+        "counting"
+    }
+
+    fn compress_as(&self, data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(data.to_vec())
+    }
+}
+
+#[test]
+fn lru_evicts_least_recently_used() {
+    let inner = Counting(AtomicUsize::new(0));
+    let lru = Lru::new(&inner, 2);
+
+    lru.compress_as(b"a", Format::Gzip).unwrap();
+    lru.compress_as(b"b", Format::Gzip).unwrap();
+    assert_eq!(lru.compress_as(b"a", Format::Gzip).unwrap(), b"a");
+    assert_eq!(inner.0.load(Ordering::Relaxed), 2);
+
+    // "b" is the least recently used, so it makes room for "c"
+    lru.compress_as(b"c", Format::Gzip).unwrap();
+    lru.compress_as(b"a", Format::Gzip).unwrap();
+    assert_eq!(inner.0.load(Ordering::Relaxed), 3);
+    lru.compress_as(b"b", Format::Gzip).unwrap();
+    assert_eq!(inner.0.load(Ordering::Relaxed), 4);
+    assert_eq!(lru.hits(), 2);
+}
+
+#[test]
+fn lru_is_bounded_by_bytes() {
+    let inner = Counting(AtomicUsize::new(0));
+    let lru = Lru::new(&inner, 4);
+
+    // too large to keep at all
+    lru.compress_as(b"large", Format::Gzip).unwrap();
+    lru.compress_as(b"large", Format::Gzip).unwrap();
+    assert_eq!(inner.0.load(Ordering::Relaxed), 2);
+
+    // both fit, until a third pushes out the least recently used
+    lru.compress_as(b"ab", Format::Gzip).unwrap();
+    lru.compress_as(b"cd", Format::Gzip).unwrap();
+    lru.compress_as(b"ab", Format::Gzip).unwrap();
+    lru.compress_as(b"e", Format::Gzip).unwrap();
+    assert_eq!(inner.0.load(Ordering::Relaxed), 5);
+    lru.compress_as(b"ab", Format::Gzip).unwrap();
+    lru.compress_as(b"e", Format::Gzip).unwrap();
+    assert_eq!(inner.0.load(Ordering::Relaxed), 5);
+    lru.compress_as(b"cd", Format::Gzip).unwrap();
+    assert_eq!(inner.0.load(Ordering::Relaxed), 6);
+}
+
+/// Only compresses into a buffer it is given, to check that wrappers pass it on.
+struct IntoOnly;
+
+impl CompressionBackend for IntoOnly {
+    fn name(&self) -> &str {
+        "into only"
+    }
+
+    fn compress_as(&self, _data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        panic!("compress_as called instead of compress_into");
+    }
+
+    fn compress_into(&self, data: &[u8], format: Format, dest: &mut Vec<u8>) -> Result<()> {
+        codec::compress_libdeflater_into(data, format, 6, dest)
+    }
+}
+
+#[test]
+fn caching_wrappers_compress_into_the_given_buffer() {
+    let data = b"\x0a\x00\x00\x00";
+    let expected = codec::compress_libdeflater(data, Format::Gzip, 6).unwrap();
+    let (dedupe, lru) = (Dedupe::new(&IntoOnly), Lru::new(&IntoOnly, 1024));
+    for backend in [&dedupe as &dyn CompressionBackend, &lru] {
+        // the first is compressed, the second found again, and both replace what was there
+        for _ in 0..2 {
+            let mut dest = vec![0xff; 100];
+            backend.compress_into(data, Format::Gzip, &mut dest).unwrap();
+            assert_eq!(dest, expected);
+        }
+    }
+    assert_eq!((dedupe.duplicates(), lru.hits()), (1, 1));
+}
+
+/// Takes its time, then hands back a fixed output.
+struct Slow(Duration);
+
+impl CompressionBackend for Slow {
+    fn name(&self) -> &str {
+        "slow"
+    }
+
+    fn compress_as(&self, _data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        std::thread::sleep(self.0);
+        Ok(b"slow".to_vec())
+    }
+}
+
+#[test]
+fn time_limit_falls_back_when_too_slow() {
+    let limit = TimeLimit::new(Arc::new(Slow(Duration::from_secs(5))), Box::new(Fixed("fixed", 3)), Duration::from_millis(50));
+    let compressed = limit.compress_traced(b"data", Format::Gzip).unwrap();
+    assert_eq!(compressed.data.len(), 3);
+    assert_eq!(compressed.backend, "fixed (slow timed out after 50ms)");
+    assert_eq!(limit.timed_out(), 1);
+
+    let limit = TimeLimit::new(Arc::new(Slow(Duration::ZERO)), Box::new(Fixed("fixed", 3)), Duration::from_secs(5));
+    assert_eq!(limit.compress_as(b"data", Format::Gzip).unwrap(), b"slow");
+    assert_eq!(limit.timed_out(), 0);
+}
@@ -0,0 +1,37 @@
+use std::fs;
+
+use nbt_compress::cache::Cache;
+
+#[test]
+fn recorded_files_are_optimal_until_changed() {
+    let mut cache = Cache::default();
+    cache.record("level.dat", b"abc", "libdeflater (level 12)");
+
+    assert!(cache.is_optimal("level.dat", b"abc", "libdeflater (level 12)"));
+    assert!(!cache.is_optimal("level.dat", b"abd", "libdeflater (level 12)"));
+    assert!(!cache.is_optimal("level.dat", b"abc", "zopfli (500 iterations)"));
+    assert!(!cache.is_optimal("other.dat", b"abc", "libdeflater (level 12)"));
+}
+
+#[test]
+fn round_trips_through_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cache.json");
+    let mut cache = Cache::default();
+    cache.record("level.dat", b"abc", "zstd (level 19)");
+    cache.save(&path).unwrap();
+
+    let loaded = Cache::load(&path);
+    assert_eq!(loaded.len(), 1);
+    assert!(loaded.is_optimal("level.dat", b"abc", "zstd (level 19)"));
+}
+
+#[test]
+fn missing_or_corrupt_cache_loads_empty() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cache.json");
+    assert!(Cache::load(&path).is_empty());
+
+    fs::write(&path, "{\"entries\": [").unwrap();
+    assert!(Cache::load(&path).is_empty());
+}
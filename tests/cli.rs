@@ -0,0 +1,565 @@
+use std::fs;
+use std::process::Command;
+
+use nbt_compress::codec::{self, Format};
+
+fn compressed_file(dir: &tempfile::TempDir) -> String {
+    let path = dir.path().join("big.dat");
+    // {"":{a:[B;0b,1b,...]}}
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    // stored without compression, so there is plenty to save
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap()).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// The `-v` output for a dry run with zopfli, which lists the iteration count it used.
+fn verbose_with(iteration_args: &[&str]) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["-z", "-n", "-v", "--no-quick-check"])
+        .args(iteration_args)
+        .arg(&path)
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr)
+}
+
+#[test]
+fn iterations_glued_to_short_flag() {
+    assert!(verbose_with(&["-i3"]).contains("zopfli (3 iterations)"));
+}
+
+#[test]
+fn iterations_after_short_flag() {
+    assert!(verbose_with(&["-i", "3"]).contains("zopfli (3 iterations)"));
+}
+
+#[test]
+fn iterations_after_equals() {
+    assert!(verbose_with(&["--iterations=3"]).contains("zopfli (3 iterations)"));
+}
+
+#[test]
+fn iterations_after_long_flag() {
+    assert!(verbose_with(&["--iterations", "3"]).contains("zopfli (3 iterations)"));
+}
+
+#[test]
+fn missing_iterations_is_a_usage_error() {
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).arg("-i").status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn help_lists_every_subcommand() {
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).arg("--help").output().unwrap();
+    assert!(output.status.success());
+    let help = String::from_utf8(output.stdout).unwrap();
+    for subcommand in ["compress", "decompress", "inspect", "benchmark", "check"] {
+        assert!(help.contains(subcommand), "{} missing from --help", subcommand);
+    }
+}
+
+#[test]
+fn compress_help_lists_every_flag() {
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["compress", "--help"]).output().unwrap();
+    assert!(output.status.success());
+    let help = String::from_utf8(output.stdout).unwrap();
+    for flag in ["--zopfli", "--iterations", "--dry-run", "--output-dir", "--files-from", "--version"] {
+        assert!(help.contains(flag), "{} missing from compress --help", flag);
+    }
+}
+
+#[test]
+fn backends_are_mutually_exclusive() {
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["compress", "--zstd", "--best", "missing.dat"]).status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn old_mode_flags_pick_a_subcommand() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--list", &path]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let explicit = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["inspect", &path]).output().unwrap();
+    assert_eq!(output.stdout, explicit.stdout);
+}
+
+#[test]
+fn version_is_the_crate_version() {
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--version", "missing.dat"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("nbt-compress {}\n", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn report_lists_biggest_savings_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let bigger = compressed_file(&dir);
+    let smaller = dir.path().join("small.dat");
+    fs::write(&smaller, codec::compress_libdeflater(b"\x0a\x00\x00\x00", Format::Gzip, 1).unwrap()).unwrap();
+    let smaller = smaller.to_str().unwrap();
+
+    let json = dir.path().join("report.json");
+    let text = dir.path().join("report.txt");
+    for report in [&json, &text] {
+        let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+            .args(["-n", "-q", "--report", report.to_str().unwrap(), smaller, &bigger])
+            .current_dir(dir.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    let document: serde_json::Value = serde_json::from_slice(&fs::read(&json).unwrap()).unwrap();
+    assert_eq!(document["files"][0]["path"], bigger.as_str());
+    assert_eq!(document["files"][1]["path"], smaller);
+    assert_eq!(document["summary"]["files"], 2);
+
+    let text = fs::read_to_string(&text).unwrap();
+    assert!(text.starts_with(&format!("{}: saved", bigger)), "{}", text);
+    assert!(text.contains("\nTotal: 2 files"), "{}", text);
+}
+
+#[test]
+fn sample_estimates_without_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = compressed_file(&dir);
+    let second = dir.path().join("copy.dat");
+    fs::copy(&first, &second).unwrap();
+    let before = fs::read(&first).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["--json", "--sample", "2", &first, second.to_str().unwrap()])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let document: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(document["summary"]["files"], 1);
+    let saved = document["summary"]["saved_bytes"].as_i64().unwrap();
+    assert!(saved > 0);
+    assert_eq!(document["summary"]["estimated_saved_bytes"].as_i64(), Some(saved * 2));
+    assert_eq!(fs::read(&first).unwrap(), before);
+    assert_eq!(fs::read(&second).unwrap(), before);
+
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--sample", "0", &first]).status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn check_lists_corrupt_files_without_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let good = compressed_file(&dir);
+    let bad = dir.path().join("bad.dat");
+    let mut contents = fs::read(&good).unwrap();
+    contents.truncate(contents.len() / 2);
+    fs::write(&bad, &contents).unwrap();
+    let bad = bad.to_str().unwrap();
+    let before = fs::read(&good).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["check", &good]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "All 1 files are valid\n");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--check", &good, bad]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with(&format!("{}: ", bad)), "{}", stdout);
+    assert!(!stdout.contains(&good), "{}", stdout);
+    assert_eq!(fs::read(&good).unwrap(), before);
+    assert_eq!(fs::read(bad).unwrap(), contents);
+}
+
+#[test]
+fn check_reference_catches_changed_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let world = dir.path().join("world");
+    fs::create_dir(&world).unwrap();
+    let file = compressed_file(&dir);
+    fs::copy(&file, world.join("level.dat")).unwrap();
+    fs::create_dir(dir.path().join("backup")).unwrap();
+    fs::copy(&file, dir.path().join("backup/level.dat")).unwrap();
+
+    let run = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_nbt-compress")).current_dir(&world).args(args).output().unwrap();
+    assert!(run(&["-r", "."]).status.success());
+    assert_ne!(fs::read(world.join("level.dat")).unwrap(), fs::read(&file).unwrap());
+    let output = run(&["check", "--reference", "../backup", "-r", "."]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "All 1 files are valid and match ../backup\n");
+
+    let (_, mut raw) = codec::decompress(&fs::read(&file).unwrap()).unwrap();
+    raw[100] ^= 1;
+    fs::write(world.join("level.dat"), codec::compress_libdeflater(&raw, Format::Gzip, 6).unwrap()).unwrap();
+    let output = run(&["check", "--reference", "../backup", "level.dat"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("at byte 100"));
+}
+
+#[test]
+fn iteration_strategy_picks_zopfli_iterations() {
+    assert!(verbose_with(&["--iteration-strategy", "fixed:3"]).contains("zopfli (3 iterations)"));
+    // the test file is under the size threshold
+    assert!(verbose_with(&["--iteration-strategy", "size"]).contains("zopfli (500 iterations)"));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["--iteration-strategy", "fixed:0", "missing.dat"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["--iteration-strategy", "time:1", "--reproducible", "missing.dat"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn timing_dependent_flags_conflict_with_reproducible() {
+    for flag in ["--time-budget", "--zopfli-timeout"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+            .args(["-z", flag, "1", "--reproducible", "missing.dat"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{}", flag);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("cannot be used with"), "{}", flag);
+    }
+}
+
+#[test]
+fn read_ahead_keeps_the_given_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = compressed_file(&dir);
+    let paths: Vec<String> = (0..6)
+        .map(|i| {
+            let path = dir.path().join(format!("{}.dat", 5 - i));
+            fs::copy(&original, &path).unwrap();
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["--json", "--queue-depth", "1", "--threads", "3"])
+        .args(&paths)
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let document: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let listed: Vec<&str> = document["files"].as_array().unwrap().iter().map(|file| file["path"].as_str().unwrap()).collect();
+    assert_eq!(listed, paths);
+    for path in &paths {
+        assert!(fs::metadata(path).unwrap().len() < fs::metadata(&original).unwrap().len());
+    }
+}
+
+#[test]
+fn best_dry_run_compares_backends() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let before = fs::read(&path).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["--best", "-n", "--no-quick-check", "-i", "2", &path])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("libdeflater (level 12)"), "{}", stdout);
+    assert!(stdout.contains("zopfli (2 iterations)"), "{}", stdout);
+    assert_eq!(stdout.matches("<- chosen").count(), 1, "{}", stdout);
+    assert_eq!(fs::read(&path).unwrap(), before);
+}
+
+#[test]
+fn decompression_bombs_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let zeros = vec![0; 10 << 20];
+    let gzip = dir.path().join("bomb.dat");
+    fs::write(&gzip, codec::compress_libdeflater(&zeros, Format::Gzip, 12).unwrap()).unwrap();
+    assert!(fs::metadata(&gzip).unwrap().len() < 64 << 10);
+    let zstd = dir.path().join("bomb.zst");
+    fs::write(&zstd, codec::compress_zstd(&zeros, 19).unwrap()).unwrap();
+
+    for bomb in [&gzip, &zstd] {
+        let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+            .args(["check", "--max-decompressed", "1000000", bomb.to_str().unwrap()])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("larger than the limit of 1000000 bytes"), "{}", stdout);
+    }
+}
+
+#[test]
+fn color_is_only_used_when_asked_for_off_a_terminal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["-n"]).args(args).arg(&path).arg("missing.dat").current_dir(dir.path()).output().unwrap()
+    };
+
+    let plain = run(&[]);
+    assert!(!plain.stdout.contains(&0x1b) && !plain.stderr.contains(&0x1b));
+
+    let colored = run(&["--color", "always"]);
+    let stdout = String::from_utf8_lossy(&colored.stdout);
+    assert!(stdout.contains("\x1b[32mFile "), "{}", stdout);
+    assert!(String::from_utf8_lossy(&colored.stderr).contains("\x1b[31m"));
+
+    let overridden = run(&["--color", "always", "--no-color"]);
+    assert!(!overridden.stdout.contains(&0x1b));
+}
+
+#[test]
+fn world_saves_are_searched_with_subtotals() {
+    let dir = tempfile::tempdir().unwrap();
+    let world = dir.path().join("world");
+    fs::create_dir_all(world.join("playerdata")).unwrap();
+    fs::create_dir_all(world.join("data")).unwrap();
+    let original = compressed_file(&dir);
+    for file in ["level.dat", "playerdata/a.dat", "playerdata/b.dat", "data/raids.dat"] {
+        fs::copy(&original, world.join(file)).unwrap();
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["--json", "-n", "world"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let document: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(document["summary"]["files"], 4);
+    let categories: Vec<(&str, u64)> = document["summary"]["categories"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|total| (total["category"].as_str().unwrap(), total["files"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(categories, [("level", 1), ("playerdata", 2), ("data", 1)]);
+
+    // other directories still need -r
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["-n", "world/playerdata"]).current_dir(dir.path()).status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn profiles_set_defaults_that_flags_override() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    for (args, expected) in [
+        (&["--profile", "fast"][..], "libdeflater (level 6)"),
+        (&["--profile", "balanced"], "libdeflater (level 9)"),
+        (&["--profile", "fast", "--level", "8"], "libdeflater (level 8)"),
+        (&["--profile", "max", "-i", "3"], "zopfli (3 iterations)"),
+        (&["--profile", "max", "--zstd"], "zstd (level 19)"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+            .args(["-n", "-v", "--no-quick-check"])
+            .args(args)
+            .arg(&path)
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success() && stdout.contains(expected), "{:?}: {}", args, stdout);
+    }
+}
+
+#[test]
+fn sizes_are_read_from_the_footer() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--sizes", "--bytes", &path]).output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("5012 bytes uncompressed"), "{}", stdout);
+
+    fs::write(&path, b"not gzip at all, but long enough").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["inspect", "--sizes", &path]).status().unwrap();
+    assert_eq!(status.code(), Some(1));
+}
+
+#[test]
+fn compare_reports_which_tree_is_smaller() {
+    let dir = tempfile::tempdir().unwrap();
+    for (tree, sizes) in [("a", [300, 100]), ("b", [200, 100])] {
+        fs::create_dir_all(dir.path().join(tree).join("region")).unwrap();
+        fs::write(dir.path().join(tree).join("region/r.0.0.mca"), vec![0; sizes[0]]).unwrap();
+        fs::write(dir.path().join(tree).join("level.dat"), vec![0; sizes[1]]).unwrap();
+    }
+    fs::write(dir.path().join("a/only_here.dat"), [0; 5]).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .current_dir(dir.path().join("a"))
+        .args(["--compare", "../b", "--bytes", "level.dat", "region/r.0.0.mca", "only_here.dat"])
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("level.dat: 100 bytes here and in ../b, the same size"), "{}", stdout);
+    assert!(stdout.contains("Total over 2 files: 400 bytes here, 300 bytes in ../b, smaller in ../b by 100 bytes (25.0%)"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("only_here.dat"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .current_dir(dir.path().join("a"))
+        .args(["inspect", "--compare", "../b", "--json", "-r", "."])
+        .output().unwrap();
+    let document: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!((document["size"].as_u64(), document["other_size"].as_u64()), (Some(400), Some(300)));
+    assert_eq!(document["missing"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn preserve_members_conflicts_with_strip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--preserve-members", "--strip", "name", &path]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn chunk_conflicts_with_strip() {
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["--chunk", "0,0", "--strip", "name", "r.0.0.mca"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn summary_reports_throughput_per_backend() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["-n", "--json", &path]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let document: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(document["files"][0]["decompressed_bytes"], 5012);
+    let throughput = &document["summary"]["throughput"];
+    assert_eq!(throughput.as_array().unwrap().len(), 1);
+    assert_eq!(throughput[0]["backend"], "libdeflater");
+    assert_eq!(throughput[0]["decompressed_bytes"], 5012);
+    assert!(throughput[0]["bytes_per_sec"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn timed_out_region_chunks_are_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    // one stored zlib chunk in the third sector, after the two header sectors
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    let payload = codec::compress_libdeflater(&raw, Format::Zlib, 0).unwrap();
+    let sectors = (payload.len() + 5).div_ceil(4096);
+    let mut region = vec![0; (2 + sectors) * 4096];
+    region[..4].copy_from_slice(&(2 << 8 | sectors as u32).to_be_bytes());
+    region[8192..8196].copy_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    region[8196] = 2;
+    region[8197..8197 + payload.len()].copy_from_slice(&payload);
+    let path = dir.path().join("r.0.0.mca");
+    fs::write(&path, region).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["-z", "-i", "1", "--zopfli-timeout", "0", "-n", "--json"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let document: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(document["summary"]["timed_out"], 0);
+    assert_eq!(document["summary"]["timed_out_streams"], 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_finishes_the_files_in_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = compressed_file(&dir);
+    let files: Vec<String> = (0..50)
+        .map(|i| {
+            let path = dir.path().join(format!("{}.dat", i));
+            fs::copy(&first, &path).unwrap();
+            path.to_str().unwrap().to_string()
+        })
+        .collect();
+    let child = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["-z", "-i", "20", "--no-quick-check", "--threads", "1"])
+        .args(&files)
+        .current_dir(dir.path())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(130), "{}", stderr);
+    assert!(stderr.contains("Stopped after") && stderr.contains("--resume"), "{}", stderr);
+    // every file is either untouched or completely rewritten
+    for file in &files {
+        codec::decompress(&fs::read(file).unwrap()).unwrap();
+    }
+    // big.dat, which the others were copied from, and no temporary files
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), files.len() + 1);
+}
+
+#[test]
+fn cache_only_records_files_that_were_replaced() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = compressed_file(&dir);
+    let original = fs::read(&path).unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+            .args(["compress", "--cache"])
+            .args(args)
+            .arg(&path)
+            .current_dir(dir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        fs::read_to_string(dir.path().join(".nbt-compress-cache.json")).unwrap_or_default()
+    };
+
+    // the copy is smaller, but the original is still as it was
+    assert!(!run(&["--zstd"]).contains("big.dat"));
+    assert!(dir.path().join("big.dat.zst").exists());
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    assert!(run(&[]).contains("big.dat"));
+    assert_ne!(fs::read(&path).unwrap(), original);
+}
+
+#[test]
+fn old_mode_flags_only_count_before_files() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::rename(compressed_file(&dir), dir.path().join("-d")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress"))
+        .args(["-n", "--", "-d"])
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("File -d compressed with"));
+
+    // a flag after a file is the subcommand's own, and compress has no --check
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).args(["./-d", "--check"]).current_dir(dir.path()).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn lone_file_named_like_a_subcommand_is_compressed() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::rename(compressed_file(&dir), dir.path().join("check")).unwrap();
+    let original = fs::read(dir.path().join("check")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_nbt-compress")).arg("check").current_dir(dir.path()).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(fs::read(dir.path().join("check")).unwrap().len() < original.len());
+}
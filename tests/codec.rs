@@ -0,0 +1,272 @@
+use std::num::NonZeroU64;
+use std::time::Duration;
+
+use nbt_compress::codec::{self, Format};
+use nbt_compress::error::{is_corrupt, CorruptData};
+
+/// `hello_world.nbt` from the NBT specification, gzip'd at a low level.
+const HELLO_WORLD: &[u8] = include_bytes!("fixtures/hello_world.nbt");
+
+/// The uncompressed payload of [`HELLO_WORLD`].
+const HELLO_WORLD_RAW: &[u8] = b"\x0a\x00\x0bhello world\x08\x00\x04name\x00\x09Bananrama\x00";
+
+#[test]
+fn decompress_known_blob() {
+    assert_eq!(codec::decompress(HELLO_WORLD).unwrap(), (Format::Gzip, HELLO_WORLD_RAW.to_vec()));
+}
+
+#[test]
+fn libdeflater_round_trip() {
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let compressed = codec::compress_libdeflater(&raw, Format::Gzip, 12).unwrap();
+    assert_eq!(codec::decompress(&compressed).unwrap().1, HELLO_WORLD_RAW);
+}
+
+#[test]
+fn into_variants_replace_what_the_buffer_held() {
+    let mut raw = vec![0xff; 4096];
+    codec::decompress_into(HELLO_WORLD, Format::Gzip, &mut raw).unwrap();
+    assert_eq!(raw, HELLO_WORLD_RAW);
+
+    for format in [Format::Gzip, Format::Zlib, Format::Deflate] {
+        let mut compressed = vec![0xff; 4096];
+        codec::compress_libdeflater_into(HELLO_WORLD_RAW, format, 12, &mut compressed).unwrap();
+        assert_eq!(compressed, codec::compress_libdeflater(HELLO_WORLD_RAW, format, 12).unwrap());
+
+        codec::decompress_into(&compressed, format, &mut raw).unwrap();
+        assert_eq!(raw, HELLO_WORLD_RAW);
+    }
+}
+
+#[test]
+fn zopfli_round_trip() {
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let compressed = codec::compress_zopfli(&raw, Format::Gzip, NonZeroU64::new(15).unwrap(), codec::DEFAULT_BLOCK_SPLITS).unwrap();
+    assert_eq!(codec::decompress(&compressed).unwrap().1, HELLO_WORLD_RAW);
+
+    let optimised = codec::optimise_zopfli(&raw, Format::Gzip, -1, codec::DEFAULT_BLOCK_SPLITS).unwrap();
+    assert_eq!(codec::decompress(&optimised).unwrap().1, HELLO_WORLD_RAW);
+}
+
+#[test]
+fn zopfli_block_splits_are_configurable() {
+    // data whose halves compress best with different codes, so splitting matters
+    let data: Vec<u8> = (0..20_000u32).map(|i| if i < 10_000 { (i % 7) as u8 } else { (i * 31 % 251) as u8 }).collect();
+    for splits in [0, 1, codec::DEFAULT_BLOCK_SPLITS] {
+        let compressed = codec::compress_zopfli(&data, Format::Deflate, NonZeroU64::new(1).unwrap(), splits).unwrap();
+        assert_eq!(codec::decompress_as(&compressed, Format::Deflate).unwrap(), data);
+    }
+}
+
+#[test]
+fn invalid_level_is_an_error() {
+    assert!(codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Gzip, 13).is_err());
+}
+
+#[test]
+fn zlib_round_trip() {
+    let compressed = codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Zlib, 12).unwrap();
+    assert_eq!(compressed[0], 0x78);
+    assert_eq!(codec::decompress_as(&compressed, Format::Zlib).unwrap(), HELLO_WORLD_RAW);
+
+    let compressed = codec::optimise_zopfli(HELLO_WORLD_RAW, Format::Zlib, 5, codec::DEFAULT_BLOCK_SPLITS).unwrap();
+    assert_eq!(codec::decompress_as(&compressed, Format::Zlib).unwrap(), HELLO_WORLD_RAW);
+}
+
+#[test]
+fn raw_deflate_round_trip() {
+    let compressed = codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Deflate, 12).unwrap();
+    assert_eq!(codec::detect_format(&compressed), None);
+    assert_eq!(codec::decompress_as(&compressed, Format::Deflate).unwrap(), HELLO_WORLD_RAW);
+
+    let compressed = codec::optimise_zopfli(HELLO_WORLD_RAW, Format::Deflate, 5, codec::DEFAULT_BLOCK_SPLITS).unwrap();
+    assert_eq!(codec::decompress_as(&compressed, Format::Deflate).unwrap(), HELLO_WORLD_RAW);
+}
+
+#[test]
+fn detects_zlib() {
+    let compressed = codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Zlib, 6).unwrap();
+    assert_eq!(codec::detect_format(&compressed), Some(Format::Zlib));
+    assert_eq!(codec::decompress(&compressed).unwrap(), (Format::Zlib, HELLO_WORLD_RAW.to_vec()));
+}
+
+#[test]
+fn rejects_unknown_format() {
+    assert_eq!(codec::detect_format(b"hello"), None);
+    assert_eq!(codec::detect_format(b"\x78\x00"), None);
+    assert!(codec::decompress(b"hello").is_err());
+}
+
+#[test]
+fn detects_uncompressed_nbt() {
+    assert_eq!(codec::decompress(HELLO_WORLD_RAW).unwrap(), (Format::Uncompressed, HELLO_WORLD_RAW.to_vec()));
+    assert_eq!(codec::detect_format(b"\x0a\x00\x05ab"), None);
+}
+
+#[test]
+fn time_budget_keeps_trying_while_there_is_time() {
+    let (output, iterations) = codec::zopfli_time_budget(HELLO_WORLD_RAW, Format::Gzip, Duration::from_millis(200), codec::DEFAULT_BLOCK_SPLITS).unwrap();
+    assert!(iterations > 1);
+    assert_eq!(codec::decompress(&output).unwrap().1, HELLO_WORLD_RAW);
+}
+
+#[test]
+fn empty_and_tiny_inputs_terminate() {
+    assert!(codec::decompress_as(&[], Format::Gzip).is_err());
+    assert!(codec::decompress_as(&[], Format::Zlib).is_err());
+    assert!(codec::decompress(&[]).is_err());
+
+    for data in [&b""[..], b"\x00", HELLO_WORLD_RAW] {
+        for format in [Format::Gzip, Format::Zlib] {
+            let compressed = codec::compress_libdeflater(data, format, 12).unwrap();
+            assert_eq!(codec::decompress_as(&compressed, format).unwrap(), data);
+        }
+    }
+}
+
+#[test]
+fn size_footer_presizes_output() {
+    let raw = vec![7; 100_000];
+    let mut compressed = codec::compress_libdeflater(&raw, Format::Gzip, 12).unwrap();
+    assert_eq!(codec::decompress_as(&compressed, Format::Gzip).unwrap(), raw);
+
+    // an implausible footer is ignored rather than trusted for the allocation, and
+    // libdeflate then rejects the mismatched size
+    let len = compressed.len();
+    compressed[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(codec::decompress_as(&compressed, Format::Gzip).is_err());
+}
+
+#[test]
+fn understated_size_footer_falls_back_to_growing() {
+    let raw = vec![7; 100_000];
+    let mut compressed = codec::compress_libdeflater(&raw, Format::Gzip, 12).unwrap();
+    let len = compressed.len();
+
+    // too small a buffer is grown until the data fits, at which point libdeflate notices
+    // the size doesn't match; neither step may overflow
+    for footer in [0, 1, u32::MAX / 2] {
+        compressed[len - 4..].copy_from_slice(&footer.to_le_bytes());
+        assert!(codec::decompress_as(&compressed, Format::Gzip).is_err());
+    }
+}
+
+#[test]
+fn zstd_round_trip() {
+    let compressed = codec::compress_zstd(HELLO_WORLD_RAW, 19).unwrap();
+    assert_eq!(codec::detect_format(&compressed), Some(Format::Zstd));
+    assert_eq!(codec::decompress(&compressed).unwrap(), (Format::Zstd, HELLO_WORLD_RAW.to_vec()));
+    assert!(codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Zstd, 6).is_err());
+}
+
+#[test]
+fn brotli_round_trip() {
+    let compressed = codec::compress_brotli(HELLO_WORLD_RAW, 11).unwrap();
+    assert_eq!(codec::decompress_as(&compressed, Format::Brotli).unwrap(), HELLO_WORLD_RAW);
+    assert!(codec::decompress_as(b"not brotli", Format::Brotli).is_err());
+    assert!(codec::compress_zopfli(HELLO_WORLD_RAW, Format::Brotli, std::num::NonZeroU64::new(1).unwrap(), 15).is_err());
+}
+
+#[test]
+fn counts_trailing_garbage_after_gzip() {
+    let mut data = codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Gzip, 6).unwrap();
+    assert_eq!(codec::decompress_trailing(&data).unwrap().2, 0);
+    data.extend_from_slice(b"junk\0\0");
+    assert_eq!(codec::decompress_trailing(&data).unwrap(), (Format::Gzip, HELLO_WORLD_RAW.to_vec(), 6));
+}
+
+#[test]
+fn reads_every_gzip_member() {
+    let (first, second) = HELLO_WORLD_RAW.split_at(10);
+    let mut data = codec::compress_libdeflater(first, Format::Gzip, 6).unwrap();
+    data.extend(codec::compress_libdeflater(second, Format::Gzip, 6).unwrap());
+
+    assert_eq!(codec::decompress(&data).unwrap(), (Format::Gzip, HELLO_WORLD_RAW.to_vec()));
+
+    // a large second member has to grow the buffer sized from the first
+    let big = vec![1; 50_000];
+    let mut data = codec::compress_libdeflater(first, Format::Gzip, 6).unwrap();
+    data.extend(codec::compress_libdeflater(&big, Format::Gzip, 6).unwrap());
+    data.extend(codec::compress_libdeflater(first, Format::Gzip, 6).unwrap());
+    let expected: Vec<u8> = [first, &big, first].concat();
+    assert_eq!(codec::decompress_as(&data, Format::Gzip).unwrap(), expected);
+
+    // a corrupt later member is an error, not silently dropped
+    let len = data.len();
+    data[len - 10] ^= 0xff;
+    assert!(codec::decompress_as(&data, Format::Gzip).is_err());
+}
+
+#[test]
+fn corrupt_data_says_where() {
+    let member = codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Gzip, 6).unwrap();
+    let data = [&member[..], &member[..member.len() - 5]].concat();
+
+    let e = codec::decompress(&data).unwrap_err();
+    assert!(is_corrupt(&e));
+    let corrupt = e.get_ref().unwrap().downcast_ref::<CorruptData>().unwrap();
+    assert_eq!(*corrupt, CorruptData { format: Format::Gzip, offset: member.len(), decompressed: HELLO_WORLD_RAW.len() });
+
+    assert!(is_corrupt(&codec::decompress_as(&[0x78, 0x9c, 0xff], Format::Zlib).unwrap_err()));
+    assert!(!is_corrupt(&codec::decompress(b"not nbt").unwrap_err()));
+}
+
+#[test]
+fn gzip_output_is_reproducible() {
+    let outputs = [
+        codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Gzip, 12).unwrap(),
+        codec::compress_zopfli(HELLO_WORLD_RAW, Format::Gzip, NonZeroU64::new(5).unwrap(), codec::DEFAULT_BLOCK_SPLITS).unwrap(),
+    ];
+    for output in &outputs {
+        // no optional fields, and a zero modification time
+        assert_eq!(output[3], 0, "FLG is {:#04x}", output[3]);
+        assert_eq!(output[4..8], [0; 4]);
+    }
+
+    assert_eq!(codec::compress_libdeflater(HELLO_WORLD_RAW, Format::Gzip, 12).unwrap(), outputs[0]);
+    assert_eq!(codec::compress_zopfli(HELLO_WORLD_RAW, Format::Gzip, NonZeroU64::new(5).unwrap(), codec::DEFAULT_BLOCK_SPLITS).unwrap(), outputs[1]);
+}
+
+#[test]
+fn gzip_header_fields_survive_a_rewrite() {
+    let nbt = b"\x0a\x00\x00\x00";
+    let plain = codec::compress_libdeflater(nbt, Format::Gzip, 12).unwrap();
+    assert_eq!(codec::gzip_header(&plain), None);
+
+    let header = codec::GzipHeader { name: Some(b"level.dat".to_vec()), comment: Some(b"hi".to_vec()) };
+    let named = codec::set_gzip_header(&plain, &header).unwrap();
+    assert_eq!(codec::gzip_header(&named), Some(header.clone()));
+    assert_eq!(codec::decompress(&named).unwrap(), (Format::Gzip, nbt.to_vec()));
+    // a header that already has fields can't be rewritten
+    assert!(codec::set_gzip_header(&named, &header).is_err());
+
+    // FEXTRA comes before the name, and is skipped over
+    let mut extra = plain[..10].to_vec();
+    extra[3] = 0x04 | 0x08;
+    extra.extend([3, 0, b'x', b'y', b'z']);
+    extra.extend(b"a.nbt\0");
+    extra.extend(&plain[10..]);
+    assert_eq!(codec::gzip_header(&extra), Some(codec::GzipHeader { name: Some(b"a.nbt".to_vec()), comment: None }));
+}
+
+#[test]
+fn size_based_iterations_switch_above_the_threshold() {
+    use codec::IterationStrategy;
+
+    let strategy = IterationStrategy::SizeBased;
+    assert_eq!(strategy.iterations(0), Some(codec::SMALL_INPUT_ITERATIONS));
+    assert_eq!(strategy.iterations(19_999), Some(500));
+    assert_eq!(strategy.iterations(20_000), Some(500));
+    assert_eq!(strategy.iterations(20_001), Some(100));
+    assert_eq!(strategy.iterations(1 << 30), Some(codec::LARGE_INPUT_ITERATIONS));
+
+    let fixed = IterationStrategy::Fixed(NonZeroU64::new(7).unwrap());
+    assert_eq!(fixed.iterations(20_001), Some(7));
+    assert_eq!(IterationStrategy::TimeBudget(Duration::from_secs(1)).iterations(10), None);
+
+    assert_eq!(IterationStrategy::from_iterations(7), fixed);
+    assert_eq!(IterationStrategy::from_iterations(0), strategy);
+    assert_eq!(IterationStrategy::from_iterations(-1), strategy);
+    assert_eq!(codec::zopfli_iterations(20_001, -1), 100);
+    assert_eq!(codec::zopfli_iterations(20_001, 9), 9);
+}
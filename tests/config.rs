@@ -0,0 +1,45 @@
+use std::fs;
+
+use nbt_compress::config::{Backend, Config};
+
+#[test]
+fn loads_every_setting() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nbt-compress.toml");
+    fs::write(&path, "backend = \"zopfli\"\niterations = 300\nthreads = 8\nbackup_suffix = \".orig\"\n").unwrap();
+
+    let config = Config::load(&path).unwrap();
+    assert_eq!(
+        config,
+        Config {
+            backend: Some(Backend::Zopfli),
+            iterations: Some(300),
+            threads: Some(8),
+            backup: false,
+            backup_suffix: Some(".orig".to_string()),
+        }
+    );
+}
+
+#[test]
+fn empty_config_changes_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nbt-compress.toml");
+    fs::write(&path, "").unwrap();
+
+    assert_eq!(Config::load(&path).unwrap(), Config::default());
+}
+
+#[test]
+fn mistakes_are_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nbt-compress.toml");
+    for text in ["backend = \"gzip\"", "threads = -1", "iterations = 5\nthreds = 8"] {
+        fs::write(&path, text).unwrap();
+        assert!(Config::load(&path).is_err(), "{}", text);
+    }
+
+    // the line helps find the typo
+    let e = Config::load(&path).unwrap_err();
+    assert!(e.to_string().contains("line 2"), "{}", e);
+}
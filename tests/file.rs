@@ -0,0 +1,926 @@
+use std::fs;
+use std::io::Result;
+
+use nbt_compress::backend::{Brotli, CompressionBackend, Libdeflater, Zopfli, Zstd};
+use nbt_compress::codec::{self, Format};
+use nbt_compress::nbt;
+use nbt_compress::file::{backup_file, benchmark_file, compared_sizes, compress_deferred, compress_file, compress_stream, converted_path, decompress_file, decompressed_path, footer_sizes, inspect_file, mapped_path, mirrored_path, output_path, read_contents, read_input, verify, verify_against, with_retries, write_file, Contents, FooterSizes, Kind, Options, FOOTER_WRAP_RISK, IDENTICAL, MMAP_THRESHOLD, RESCUE_DIR, RETRY_DELAY};
+
+const HELLO_WORLD: &[u8] = include_bytes!("fixtures/hello_world.nbt");
+
+/// [`Fixed`] output can't be decompressed, so tests that use it skip verification.
+fn unverified() -> Options {
+    Options { verify: false, ..Default::default() }
+}
+
+/// A backend that ignores its input and always returns the same bytes.
+struct Fixed(Vec<u8>);
+
+impl CompressionBackend for Fixed {
+    fn name(&self) -> &str {
+        "fixed"
+    }
+
+    fn compress_as(&self, _data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        Ok(self.0.clone())
+    }
+}
+
+fn fixture(dir: &tempfile::TempDir) -> String {
+    let path = dir.path().join("hello_world.nbt");
+    fs::write(&path, HELLO_WORLD).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn smaller_output_is_written() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    let report = compress_file(&path, &Fixed(vec![1, 2, 3]), &unverified()).unwrap();
+    assert_eq!((report.original, report.compressed), (HELLO_WORLD.len(), 3));
+    assert_eq!((report.path.as_str(), report.backend.as_str(), report.written), (path.as_str(), "fixed", true));
+    assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+}
+
+#[test]
+fn larger_output_is_discarded() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    let report = compress_file(&path, &Fixed(vec![0; HELLO_WORLD.len()]), &Options::default()).unwrap();
+    assert_eq!(report.compressed, HELLO_WORLD.len());
+    assert_eq!((report.saved(), report.written), (0, false));
+    assert_eq!(fs::read(&path).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn keep_larger_writes_larger_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    let options = Options { keep_larger: true, ..unverified() };
+    let report = compress_file(&path, &Fixed(vec![0; 100]), &options).unwrap();
+    assert_eq!((report.saved(), report.net_saved(), report.written), (0, HELLO_WORLD.len() as i64 - 100, true));
+    assert_eq!(fs::read(&path).unwrap(), [0; 100]);
+}
+
+#[test]
+fn invalid_nbt_is_rejected_unless_disabled() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("not_nbt.dat");
+    let gzip = codec::compress_libdeflater(b"\x0a\x00\x00 definitely not nbt", Format::Gzip, 0).unwrap();
+    fs::write(&path, &gzip).unwrap();
+    let path = path.to_str().unwrap();
+
+    assert!(compress_file(path, &Libdeflater { level: 12 }, &Options::default()).is_err());
+    assert_eq!(fs::read(path).unwrap(), gzip);
+
+    let options = Options { validate: false, ..Options::default() };
+    compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+}
+
+#[test]
+fn schematics_are_recompressed_as_nbt() {
+    let dir = tempfile::tempdir().unwrap();
+    let raw = codec::decompress(HELLO_WORLD).unwrap().1;
+    for name in ["house.litematic", "wall.schematic"] {
+        let path = dir.path().join(name);
+        fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap()).unwrap();
+        let path = path.to_str().unwrap();
+
+        let options = Options { mmap: true, ..Options::default() };
+        let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+        assert_eq!((report.format, report.decompressed), (Some(Format::Gzip), Some(raw.len())));
+        assert_eq!(codec::decompress(&fs::read(path).unwrap()).unwrap(), (Format::Gzip, raw.clone()));
+    }
+}
+
+#[test]
+fn normalize_keeps_the_smaller_ordering() {
+    use nbt_compress::nbt::{self, Tag};
+
+    let long = |c: char| Tag::String(c.to_string().repeat(200));
+    let entries = (0..20).map(|i| (format!("{}{:02}", if i % 2 == 0 { 'a' } else { 'b' }, i), long(if i % 2 == 0 { 'x' } else { 'y' })));
+    let raw = nbt::write("", &Tag::Compound(entries.collect())).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+
+    let options = Options { normalize: true, warn_on_grow_parse: true, ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert_eq!(report.attempts.len(), 2);
+    assert_eq!(report.tag_warning, None);
+
+    // either ordering may have been kept, but the values must be the same
+    let (_, written) = codec::decompress(&fs::read(path).unwrap()).unwrap();
+    let (_, mut written) = nbt::parse(&written).unwrap();
+    let (_, mut expected) = nbt::parse(&raw).unwrap();
+    written.sort_keys();
+    expected.sort_keys();
+    assert_eq!(written, expected);
+    assert!(report.normalization_saved.is_some());
+    assert!(!report.tree_checked);
+
+    let options = Options { normalize: true, show_diff: true, dry_run: true, ..Options::default() };
+    assert!(compress_file(path, &Libdeflater { level: 12 }, &options).unwrap().tree_checked);
+}
+
+#[test]
+fn bedrock_level_dat_is_left_uncompressed() {
+    use nbt_compress::nbt::{self, Edition, Tag};
+
+    let payload = nbt::write_as("", &Tag::Compound(vec![("LevelName".to_string(), Tag::String("a".repeat(500)))]), Edition::Bedrock).unwrap();
+    let mut data = 10u32.to_le_bytes().to_vec();
+    data.extend((payload.len() as u32).to_le_bytes());
+    data.extend(&payload);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    fs::write(&path, &data).unwrap();
+    let path = path.to_str().unwrap();
+
+    let report = compress_file(path, &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    assert_eq!(report.compressed, data.len());
+    assert_eq!(fs::read(path).unwrap(), data);
+
+    fs::write(path, HELLO_WORLD).unwrap();
+    let options = Options { bedrock: true, ..Options::default() };
+    assert!(compress_file(path, &Libdeflater { level: 12 }, &options).is_err());
+}
+
+#[test]
+fn benchmark_runs_every_backend_without_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    fs::write(&path, HELLO_WORLD).unwrap();
+    let path = path.to_str().unwrap();
+
+    let backends: Vec<Box<dyn CompressionBackend>> = vec![Box::new(Fixed(vec![1, 2, 3])), Box::new(Libdeflater { level: 1 })];
+    let (original, attempts) = benchmark_file(path, &backends).unwrap();
+    assert_eq!(original, HELLO_WORLD.len());
+    assert_eq!(attempts.len(), 2);
+    assert_eq!((attempts[0].backend.as_str(), attempts[0].size), ("fixed", 3));
+    assert_eq!(attempts[1].backend, "libdeflater (level 1)");
+    assert_eq!(fs::read(path).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn large_files_can_be_mapped() {
+    let dir = tempfile::tempdir().unwrap();
+    let small = dir.path().join("small.dat");
+    let large = dir.path().join("large.dat");
+    fs::write(&small, HELLO_WORLD).unwrap();
+    let data: Vec<u8> = (0..MMAP_THRESHOLD as u32).map(|i| i as u8).collect();
+    fs::write(&large, &data).unwrap();
+
+    let contents = read_contents(small.to_str().unwrap(), true).unwrap();
+    assert!(matches!(contents, Contents::Read(_)));
+    assert_eq!(&contents[..], HELLO_WORLD);
+
+    let contents = read_contents(large.to_str().unwrap(), true).unwrap();
+    assert!(matches!(contents, Contents::Mapped(_)));
+    assert_eq!(&contents[..], &data[..]);
+    assert!(matches!(read_contents(large.to_str().unwrap(), false).unwrap(), Contents::Read(_)));
+}
+
+#[test]
+fn multi_member_gzip_becomes_one_member() {
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let (first, second) = raw.split_at(10);
+    let mut data = codec::compress_libdeflater(first, Format::Gzip, 0).unwrap();
+    data.extend(codec::compress_libdeflater(second, Format::Gzip, 0).unwrap());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    fs::write(&path, &data).unwrap();
+    let path = path.to_str().unwrap();
+
+    compress_file(path, &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    let written = fs::read(path).unwrap();
+    assert_eq!(written, codec::compress_libdeflater(&raw, Format::Gzip, 12).unwrap());
+    assert_eq!(codec::decompress(&written).unwrap(), (Format::Gzip, raw));
+}
+
+#[test]
+fn members_can_be_kept_separate() {
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let (first, second) = raw.split_at(10);
+    let mut data = codec::compress_libdeflater(first, Format::Gzip, 0).unwrap();
+    data.extend(codec::compress_libdeflater(second, Format::Gzip, 0).unwrap());
+    assert_eq!(codec::gzip_member_sizes(&data).unwrap(), [10, raw.len() - 10]);
+    assert_eq!(codec::gzip_member_sizes(HELLO_WORLD).unwrap(), [raw.len()]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    fs::write(&path, &data).unwrap();
+    let path = path.to_str().unwrap();
+
+    let options = Options { preserve_members: true, ..Options::default() };
+    compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    let written = fs::read(path).unwrap();
+    let mut expected = codec::compress_libdeflater(first, Format::Gzip, 12).unwrap();
+    expected.extend(codec::compress_libdeflater(second, Format::Gzip, 12).unwrap());
+    assert_eq!(written, expected);
+    assert_eq!(codec::gzip_member_sizes(&written).unwrap(), [10, raw.len() - 10]);
+
+    // stripping moves the boundaries, so the rest goes into one member
+    fs::write(path, &data).unwrap();
+    let options = Options { preserve_members: true, strip: vec!["name".to_string()], ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.stripped_bytes > 0);
+    let written = fs::read(path).unwrap();
+    let (_, stripped) = codec::decompress(&written).unwrap();
+    assert_eq!(codec::gzip_member_sizes(&written).unwrap(), [stripped.len()]);
+    assert_eq!(nbt::parse(&stripped).unwrap().1.to_snbt(false), "{}");
+}
+
+#[test]
+fn raw_deflate_stays_raw_deflate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("payload.bin");
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Deflate, 0).unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+
+    // without a header, it can't be recognized unless asked for
+    assert!(compress_file(path, &Libdeflater { level: 12 }, &Options::default()).is_err());
+
+    let options = Options { raw: true, ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert_eq!(report.format, Some(Format::Deflate));
+    assert_eq!(codec::decompress_as(&fs::read(path).unwrap(), Format::Deflate).unwrap(), raw);
+}
+
+#[test]
+fn zlib_stays_zlib() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("chunk.nbt");
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let mut zlib = codec::compress_libdeflater(&raw, Format::Zlib, 0).unwrap();
+    zlib.extend_from_slice(&[0; 64]);
+    fs::write(&path, &zlib).unwrap();
+
+    compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    assert_eq!(codec::decompress(&fs::read(&path).unwrap()).unwrap(), (Format::Zlib, raw));
+}
+
+#[test]
+fn uncompressed_nbt_is_gzipped() {
+    // {"":{numbers:[I;0,1,...,255]}}
+    let mut raw = b"\x0a\x00\x00\x0b\x00\x07numbers\x00\x00\x01\x00".to_vec();
+    raw.extend((0..256u32).flat_map(|i| i.to_be_bytes()));
+    raw.push(0);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("raw.nbt");
+    fs::write(&path, &raw).unwrap();
+
+    let report = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    assert!(report.compressed < report.original);
+    assert_eq!((report.format, report.decompressed), (Some(Format::Uncompressed), Some(raw.len())));
+    assert_eq!(codec::decompress(&fs::read(&path).unwrap()).unwrap(), (Format::Gzip, raw));
+}
+
+#[test]
+fn out_ext_renames_converted_streams() {
+    assert_eq!(mapped_path("level.nbt", Format::Gzip, Some("dat")), "level.dat");
+    assert_eq!(mapped_path("level.nbt", Format::Gzip, Some(".nbt.gz")), "level.nbt.gz");
+    assert_eq!(mapped_path("level", Format::Zlib, Some("dat")), "level.dat");
+    assert_eq!(mapped_path("level.nbt", Format::Gzip, None), "level.nbt.gz");
+    // never the original's own path, nor a Minecraft extension for zstd
+    assert_eq!(mapped_path("level.dat", Format::Gzip, Some("dat")), "level.dat.gz");
+    assert_eq!(mapped_path("level.nbt", Format::Zstd, Some("dat")), "level.nbt.zst");
+
+    let mut raw = b"\x0a\x00\x00\x0b\x00\x07numbers\x00\x00\x01\x00".to_vec();
+    raw.extend((0..256u32).flat_map(|i| i.to_be_bytes()));
+    raw.push(0);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("raw.nbt");
+    fs::write(&path, &raw).unwrap();
+
+    let options = Options { out_ext: Some("dat".to_string()), ..Options::default() };
+    let report = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.written);
+    assert_eq!(fs::read(&path).unwrap(), raw);
+    let output = dir.path().join("raw.dat");
+    assert_eq!(codec::decompress(&fs::read(&output).unwrap()).unwrap(), (Format::Gzip, raw.clone()));
+
+    // a file that already has the new name isn't the output of an earlier run
+    fs::write(&output, b"someone else's").unwrap();
+    let e = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &options).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&output).unwrap(), b"someone else's");
+    assert_eq!(fs::read(&path).unwrap(), raw);
+}
+
+#[test]
+fn corrupt_gzip_is_not_treated_as_uncompressed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("corrupt.dat");
+    let mut corrupt = HELLO_WORLD.to_vec();
+    corrupt.truncate(20);
+    fs::write(&path, &corrupt).unwrap();
+
+    let e = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &Options::default()).unwrap_err();
+    assert!(nbt_compress::error::is_corrupt(&e));
+    assert!(e.to_string().contains("corrupt.dat"));
+    assert_eq!(fs::read(&path).unwrap(), corrupt);
+}
+
+#[test]
+fn output_dir_mirrors_the_input_and_leaves_it_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let out = dir.path().join("out").to_str().unwrap().to_string();
+    let options = Options { output_dir: Some(out.clone()), ..unverified() };
+
+    let report = compress_file(&path, &Fixed(vec![1, 2, 3]), &options).unwrap();
+    assert!(report.written);
+    assert_eq!(fs::read(&path).unwrap(), HELLO_WORLD);
+    assert_eq!(fs::read(mirrored_path(&out, &path)).unwrap(), [1, 2, 3]);
+
+    // the original is copied when it can't be improved, so the output is complete
+    fs::remove_dir_all(&out).unwrap();
+    let report = compress_file(&path, &Fixed(vec![0; 100]), &options).unwrap();
+    assert!(!report.written);
+    assert_eq!(fs::read(mirrored_path(&out, &path)).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn mirrored_path_stays_inside_the_output_dir() {
+    let expected = std::path::Path::new("out").join("world").join("level.dat");
+    assert_eq!(mirrored_path("out", "world/level.dat"), expected.to_str().unwrap());
+    assert_eq!(mirrored_path("out", "/world/level.dat"), expected.to_str().unwrap());
+    assert_eq!(mirrored_path("out", "../world/./level.dat"), expected.to_str().unwrap());
+}
+
+#[test]
+fn savings_below_the_threshold_are_not_written() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let output = vec![0; HELLO_WORLD.len() - 3];
+
+    let options = Options { min_saving: 3, ..unverified() };
+    let report = compress_file(&path, &Fixed(output.clone()), &options).unwrap();
+    assert_eq!((report.saved(), report.written), (3, false));
+    assert_eq!(fs::read(&path).unwrap(), HELLO_WORLD);
+
+    let options = Options { min_saving_percent: 10.0, ..unverified() };
+    assert!(!compress_file(&path, &Fixed(output.clone()), &options).unwrap().written);
+
+    let options = Options { min_saving: 2, min_saving_percent: 5.0, ..unverified() };
+    assert!(compress_file(&path, &Fixed(output.clone()), &options).unwrap().written);
+    assert_eq!(fs::read(&path).unwrap(), output);
+}
+
+#[test]
+fn inspect_reports_without_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    let info = inspect_file(&path, &Options::default()).unwrap();
+    assert_eq!((info.kind, info.size, info.decompressed), (Some(Kind::Stream(Format::Gzip)), HELLO_WORLD.len(), Some(33)));
+    assert!(info.problem.is_none());
+
+    let not_nbt = dir.path().join("not_nbt.dat");
+    fs::write(&not_nbt, codec::compress_libdeflater(b"\x0a\x00\x00 nope", Format::Zlib, 0).unwrap()).unwrap();
+    let info = inspect_file(not_nbt.to_str().unwrap(), &Options::default()).unwrap();
+    assert_eq!(info.kind, Some(Kind::Stream(Format::Zlib)));
+    assert!(info.problem.is_some());
+
+    let unknown = dir.path().join("readme.dat");
+    fs::write(&unknown, "hello").unwrap();
+    let info = inspect_file(unknown.to_str().unwrap(), &Options::default()).unwrap();
+    assert_eq!((info.kind, info.decompressed), (None, None));
+    assert!(info.problem.is_some());
+    assert!(inspect_file(dir.path().join("missing.dat").to_str().unwrap(), &Options::default()).is_err());
+}
+
+#[test]
+fn dry_run_does_not_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let options = Options { dry_run: true, ..unverified() };
+
+    let report = compress_file(&path, &Fixed(vec![1, 2, 3]), &options).unwrap();
+    assert_eq!((report.original, report.compressed), (HELLO_WORLD.len(), 3));
+    assert!(!report.written);
+    assert_eq!(fs::read(&path).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn write_file_replaces_atomically() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    write_file(&path, &[4, 5, 6]).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), [4, 5, 6]);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn failed_write_leaves_no_temp_file() {
+    let dir = tempfile::tempdir().unwrap();
+    // renaming a file over a non-empty directory fails after the temp file is written
+    let path = dir.path().join("level.dat");
+    fs::create_dir(&path).unwrap();
+    fs::write(path.join("inner"), [0]).unwrap();
+
+    assert!(write_file(path.to_str().unwrap(), &[1]).is_err());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn backup_keeps_original() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let options = Options { backup: Some(".bak".to_string()), ..unverified() };
+
+    compress_file(&path, &Fixed(vec![1, 2, 3]), &options).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+    assert_eq!(fs::read(format!("{}.bak", path)).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn backup_does_not_clobber_existing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    fs::write(format!("{}.bak", path), b"older").unwrap();
+
+    assert_eq!(backup_file(&path, ".bak").unwrap(), format!("{}.bak.1", path));
+    assert_eq!(backup_file(&path, ".bak").unwrap(), format!("{}.bak.2", path));
+    assert_eq!(fs::read(format!("{}.bak", path)).unwrap(), b"older");
+    assert_eq!(fs::read(format!("{}.bak.2", path)).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn verify_rejects_bad_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    let err = compress_file(&path, &Fixed(vec![1, 2, 3]), &Options::default()).unwrap_err();
+    assert!(err.to_string().contains("verifying"));
+    assert_eq!(fs::read(&path).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn verify_accepts_matching_data() {
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let zopfli = codec::optimise_zopfli(&raw, Format::Gzip, 1, codec::DEFAULT_BLOCK_SPLITS).unwrap();
+    assert!(verify("level.dat", HELLO_WORLD, &zopfli).is_ok());
+    assert!(verify("level.dat", HELLO_WORLD, &codec::compress_libdeflater(b"\x0a\x00\x00\x00", Format::Gzip, 1).unwrap()).is_err());
+}
+
+#[test]
+fn decompress_writes_plain_nbt() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+
+    let report = decompress_file(&path, &Options::default()).unwrap();
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    assert_eq!((report.original, report.compressed), (HELLO_WORLD.len(), raw.len()));
+    assert_eq!((report.format, report.written), (Some(Format::Gzip), true));
+    assert_eq!(fs::read(decompressed_path(&path)).unwrap(), raw);
+    assert_eq!(fs::read(&path).unwrap(), HELLO_WORLD);
+}
+
+#[test]
+fn decompress_refuses_uncompressed_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("raw.nbt");
+    fs::write(&path, codec::decompress(HELLO_WORLD).unwrap().1).unwrap();
+
+    assert!(decompress_file(path.to_str().unwrap(), &Options::default()).is_err());
+    assert!(!std::path::Path::new(&decompressed_path(path.to_str().unwrap())).exists());
+}
+
+#[test]
+fn zstd_output_goes_next_to_original() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big.dat");
+    // {"":{a:[B;0b,1b,...]}}
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 1).unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+
+    let backend = Zstd { level: 19 };
+    compress_file(path, &backend, &Options::default()).unwrap();
+    assert_eq!(output_path(path, &backend), format!("{}.zst", path));
+    assert_eq!(codec::decompress(&fs::read(format!("{}.zst", path)).unwrap()).unwrap(), (Format::Zstd, raw));
+    assert_eq!(codec::detect_format(&fs::read(path).unwrap()), Some(Format::Gzip));
+}
+
+#[test]
+fn trailing_garbage_is_kept_unless_trimmed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("junk.dat");
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    let junk = [0xaa; 300];
+    let mut contents = codec::compress_libdeflater(&raw, Format::Gzip, 1).unwrap();
+    contents.extend_from_slice(&junk);
+    fs::write(&path, &contents).unwrap();
+    let path = path.to_str().unwrap();
+
+    let report = compress_file(path, &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    assert_eq!(report.trailing, junk.len());
+    let kept = fs::read(path).unwrap();
+    assert!(kept.ends_with(&junk));
+    assert_eq!(codec::decompress_trailing(&kept).unwrap(), (Format::Gzip, raw.clone(), junk.len()));
+
+    let options = Options { trim: true, ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.written);
+    assert_eq!(codec::decompress_trailing(&fs::read(path).unwrap()).unwrap(), (Format::Gzip, raw, 0));
+}
+
+#[test]
+fn brotli_output_goes_next_to_original_and_decompresses() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("big.dat");
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 1).unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+
+    let backend = Brotli { quality: 11 };
+    compress_file(path, &backend, &Options::default()).unwrap();
+    let output = output_path(path, &backend);
+    assert_eq!(output, format!("{}.br", path));
+    assert_eq!(codec::detect_format(&fs::read(path).unwrap()), Some(Format::Gzip));
+
+    let report = decompress_file(&output, &Options::default()).unwrap();
+    assert_eq!(report.format, Some(Format::Brotli));
+    assert_eq!(fs::read(decompressed_path(&output)).unwrap(), raw);
+}
+
+#[test]
+fn format_override_writes_a_converted_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    let original = codec::compress_libdeflater(&raw, Format::Gzip, 1).unwrap();
+    fs::write(&path, &original).unwrap();
+    let path = path.to_str().unwrap();
+
+    let options = Options { format: Some(Format::Zlib), ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.written);
+    assert_eq!(fs::read(path).unwrap(), original);
+    let zlib = converted_path(path, Format::Zlib);
+    assert_eq!(zlib, format!("{}.zlib", path));
+    assert_eq!(codec::decompress(&fs::read(&zlib).unwrap()).unwrap(), (Format::Zlib, raw.clone()));
+
+    let options = Options { format: Some(Format::Deflate), ..Options::default() };
+    compress_file(&zlib, &Libdeflater { level: 12 }, &options).unwrap();
+    let deflate = converted_path(&zlib, Format::Deflate);
+    let report = decompress_file(&deflate, &Options::default()).unwrap();
+    assert_eq!(report.format, Some(Format::Deflate));
+    assert_eq!(fs::read(decompressed_path(&deflate)).unwrap(), raw);
+}
+
+#[test]
+fn gzip_name_is_kept_only_when_asked() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    let header = codec::GzipHeader { name: Some(b"level.dat".to_vec()), comment: Some(b"saved".to_vec()) };
+    let original = codec::set_gzip_header(&codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap(), &header).unwrap();
+    fs::write(&path, &original).unwrap();
+    let path = path.to_str().unwrap();
+
+    let options = Options { preserve_gzip_meta: true, ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.written);
+    assert_eq!(report.gzip_header.as_ref(), Some(&header));
+    let kept = fs::read(path).unwrap();
+    assert_eq!(codec::gzip_header(&kept), Some(header));
+    assert_eq!(codec::decompress(&kept).unwrap(), (Format::Gzip, raw.clone()));
+
+    fs::write(path, &original).unwrap();
+    compress_file(path, &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    assert_eq!(codec::gzip_header(&fs::read(path).unwrap()), None);
+}
+
+#[test]
+fn unwritable_output_is_caught_before_a_slow_backend_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    let mut raw = b"\x0a\x00\x00\x07\x00\x01a".to_vec();
+    raw.extend(5000u32.to_be_bytes());
+    raw.extend((0..5000u32).map(|i| (i % 13) as u8));
+    raw.push(0);
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+    // a directory can't be created under a file, not even by root
+    let blocked = dir.path().join("blocked");
+    fs::write(&blocked, b"").unwrap();
+    let output_dir = blocked.join("out").to_str().unwrap().to_string();
+
+    let options = Options { output_dir: Some(output_dir.clone()), ..Options::default() };
+    let e = compress_file(path, &Zopfli { iterations: 1, block_splits: 15 }, &options).unwrap_err();
+    assert!(e.to_string().contains("not compressed"), "{}", e);
+    assert!(e.to_string().contains(&mirrored_path(&output_dir, path)), "{}", e);
+
+    // fast backends aren't checked first, but their output is saved elsewhere
+    let e = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap_err();
+    let rescued = mirrored_path(&std::env::temp_dir().join(RESCUE_DIR).to_string_lossy(), &mirrored_path(&output_dir, path));
+    assert!(e.to_string().contains(&format!("saved to {} instead", rescued)), "{}", e);
+    assert_eq!(codec::decompress(&fs::read(&rescued).unwrap()).unwrap(), (Format::Gzip, raw));
+    fs::remove_file(rescued).unwrap();
+}
+
+fn set_old_mtime(path: &str) -> std::time::SystemTime {
+    let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    fs::File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    mtime
+}
+
+#[test]
+fn identical_output_is_not_rewritten() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let mtime = set_old_mtime(&path);
+
+    let options = Options { keep_larger: true, skip_identical: true, ..unverified() };
+    let report = compress_file(&path, &Fixed(HELLO_WORLD.to_vec()), &options).unwrap();
+    assert!(!report.written);
+    assert_eq!(report.skip_reason.as_deref(), Some(IDENTICAL));
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+    let options = Options { keep_larger: true, ..unverified() };
+    assert!(compress_file(&path, &Fixed(HELLO_WORLD.to_vec()), &options).unwrap().written);
+
+    // without --keep-larger an identical output isn't written either way, but is only
+    // reported as identical when asked
+    set_old_mtime(&path);
+    let options = Options { skip_identical: true, ..unverified() };
+    let report = compress_file(&path, &Fixed(HELLO_WORLD.to_vec()), &options).unwrap();
+    assert!(!report.written);
+    assert_eq!(report.skip_reason.as_deref(), Some(IDENTICAL));
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+    let report = compress_file(&path, &Fixed(HELLO_WORLD.to_vec()), &unverified()).unwrap();
+    assert!(!report.written);
+    assert_eq!(report.skip_reason, None);
+}
+
+#[test]
+fn preserves_modification_time_and_permissions() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let mtime = set_old_mtime(&path);
+    let mut permissions = fs::metadata(&path).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(&path, permissions.clone()).unwrap();
+
+    compress_file(&path, &Fixed(vec![1, 2, 3]), &unverified()).unwrap();
+    let metadata = fs::metadata(&path).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+    assert_eq!(metadata.modified().unwrap(), mtime);
+    assert_eq!(metadata.permissions(), permissions);
+}
+
+#[test]
+fn no_preserve_updates_modification_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let mtime = set_old_mtime(&path);
+
+    let options = Options { preserve: false, ..unverified() };
+    compress_file(&path, &Fixed(vec![1, 2, 3]), &options).unwrap();
+    assert_ne!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+}
+
+#[test]
+fn streams_are_recompressed() {
+    let mut output = Vec::new();
+    compress_stream(&Libdeflater { level: 12 }, HELLO_WORLD, &mut output).unwrap();
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    assert_eq!(codec::decompress(&output).unwrap(), (Format::Gzip, raw.clone()));
+    assert!(output.len() <= HELLO_WORLD.len());
+
+    // output that isn't smaller is replaced by the original
+    let mut output = Vec::new();
+    compress_stream(&Fixed(vec![0; 100]), HELLO_WORLD, &mut output).unwrap();
+    assert_eq!(output, HELLO_WORLD);
+
+    assert!(compress_stream(&Libdeflater { level: 12 }, &b"not nbt"[..], &mut Vec::new()).is_err());
+}
+
+#[test]
+fn quick_check_skips_slow_backends_on_optimal_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let zopfli = Zopfli { iterations: 5, block_splits: codec::DEFAULT_BLOCK_SPLITS };
+    let optimal = zopfli.compress(&raw).unwrap();
+    fs::write(&path, &optimal).unwrap();
+    let path = path.to_str().unwrap();
+
+    let report = compress_file(path, &zopfli, &Options::default()).unwrap();
+    assert!(report.skip_reason.is_some());
+    assert_eq!(report.attempts.len(), 1);
+    assert_eq!(fs::read(path).unwrap(), optimal);
+
+    let options = Options { quick_check: false, ..Options::default() };
+    assert!(compress_file(path, &zopfli, &options).unwrap().skip_reason.is_none());
+
+    // stored data is easily improved, so zopfli gets to run
+    fs::write(path, codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap()).unwrap();
+    let report = compress_file(path, &zopfli, &Options::default()).unwrap();
+    assert_eq!((report.skip_reason, report.written), (None, true));
+}
+
+#[test]
+fn only_if_improvable_skips_files_level_9_cannot_shrink() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let level_12 = codec::compress_libdeflater(&raw, Format::Gzip, 12).unwrap();
+    fs::write(&path, &level_12).unwrap();
+    let path = path.to_str().unwrap();
+
+    // unlike the quick check, fast backends are skipped too
+    let options = Options { only_if_improvable: true, ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.skip_reason.unwrap().starts_with("not improvable"));
+    assert_eq!((report.attempts.len(), report.written), (1, false));
+    assert_eq!(fs::read(path).unwrap(), level_12);
+
+    fs::write(path, codec::compress_libdeflater(&raw, Format::Gzip, 0).unwrap()).unwrap();
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert_eq!((report.skip_reason, report.written), (None, true));
+}
+
+#[test]
+fn footer_sizes_come_from_the_gzip_footer() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let sizes = footer_sizes(&path).unwrap();
+    assert_eq!(sizes, FooterSizes { compressed: HELLO_WORLD.len(), uncompressed: raw.len() as u32 });
+    assert!(!sizes.may_wrap());
+
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Zlib, 6).unwrap()).unwrap();
+    assert!(footer_sizes(&path).is_err());
+
+    assert!(FooterSizes { compressed: FOOTER_WRAP_RISK, uncompressed: 1 }.may_wrap());
+    assert!(FooterSizes { compressed: 1000, uncompressed: u32::MAX }.may_wrap());
+}
+
+#[test]
+fn strip_removes_tags_before_compressing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("level.dat");
+    let root = nbt::Tag::Compound(vec![
+        ("keep".to_string(), nbt::Tag::Int(1)),
+        ("cache".to_string(), nbt::Tag::ByteArray((0..2000).map(|i| (i % 7) as i8).collect())),
+    ]);
+    let raw = nbt::write("", &root).unwrap();
+    fs::write(&path, codec::compress_libdeflater(&raw, Format::Gzip, 12).unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+
+    let options = Options { strip: vec!["cache".to_string()], warn_on_grow_parse: true, ..Options::default() };
+    let report = compress_file(path, &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.written);
+    assert_eq!(report.tag_warning, None);
+    assert_eq!(report.stripped_bytes, raw.len() - 15);
+    let (_, written) = codec::decompress(&fs::read(path).unwrap()).unwrap();
+    assert_eq!(nbt::parse(&written).unwrap().1.to_snbt(false), "{keep:1}");
+}
+
+#[test]
+fn only_transient_errors_are_retried() {
+    use std::io::{Error, ErrorKind};
+
+    let mut calls = 0;
+    let start = std::time::Instant::now();
+    let result = with_retries(3, || {
+        calls += 1;
+        match calls {
+            1 | 2 => Err(Error::from(ErrorKind::ResourceBusy)),
+            _ => Ok(calls),
+        }
+    });
+    assert_eq!(result.unwrap(), 3);
+    // waited once, then twice as long
+    assert!(start.elapsed() >= RETRY_DELAY * 3);
+
+    let mut calls = 0;
+    let e = with_retries(3, || -> Result<()> {
+        calls += 1;
+        Err(Error::from(ErrorKind::NotFound))
+    });
+    assert_eq!((e.unwrap_err().kind(), calls), (ErrorKind::NotFound, 1));
+
+    let mut calls = 0;
+    let e = with_retries(2, || -> Result<()> {
+        calls += 1;
+        Err(Error::from(ErrorKind::WouldBlock))
+    });
+    assert_eq!((e.unwrap_err().kind(), calls), (ErrorKind::WouldBlock, 3));
+    assert!(with_retries(0, || -> Result<()> { Err(Error::from(ErrorKind::WouldBlock)) }).is_err());
+}
+
+#[test]
+fn compared_sizes_come_from_the_mirrored_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let other = dir.path().join("other");
+    let other = other.to_str().unwrap();
+    let file = dir.path().join("r.0.0.mca");
+    let file = file.to_str().unwrap();
+    fs::write(file, [0; 300]).unwrap();
+    assert_eq!(compared_sizes(file, other).unwrap(), (300, None));
+
+    let counterpart = mirrored_path(other, file);
+    fs::create_dir_all(std::path::Path::new(&counterpart).parent().unwrap()).unwrap();
+    fs::write(&counterpart, [0; 200]).unwrap();
+    assert_eq!(compared_sizes(file, other).unwrap(), (300, Some(200)));
+    assert!(compared_sizes(&format!("{}.missing", file), other).is_err());
+}
+
+#[test]
+fn only_windows_has_sharing_violations() {
+    use nbt_compress::error::is_sharing_violation;
+
+    assert_eq!(is_sharing_violation(&std::io::Error::from_raw_os_error(32)), cfg!(windows));
+    assert!(!is_sharing_violation(&std::io::Error::from(std::io::ErrorKind::PermissionDenied)));
+}
+
+#[test]
+fn verify_against_compares_decompressed_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let (_, raw) = codec::decompress(HELLO_WORLD).unwrap();
+    let reference = dir.path().join("reference.dat");
+    fs::write(&reference, HELLO_WORLD).unwrap();
+    let file = dir.path().join("level.dat");
+    fs::write(&file, codec::compress_libdeflater(&raw, Format::Zlib, 12).unwrap()).unwrap();
+    let (file, reference) = (file.to_str().unwrap(), reference.to_str().unwrap());
+    verify_against(file, reference, &Options::default()).unwrap();
+
+    let mut changed = raw.clone();
+    *changed.last_mut().unwrap() ^= 1;
+    fs::write(file, codec::compress_libdeflater(&changed, Format::Gzip, 12).unwrap()).unwrap();
+    let e = verify_against(file, reference, &Options::default()).unwrap_err();
+    assert!(e.to_string().contains(&format!("at byte {}", raw.len() - 1)), "{}", e);
+    assert!(verify_against(file, &format!("{}.missing", reference), &Options::default()).is_err());
+}
+
+#[test]
+fn temporary_files_never_replace_existing_ones() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let user_file = format!("{}.tmp", path);
+    fs::write(&user_file, b"keep me").unwrap();
+
+    // slow, so the output directory is probed before compressing
+    let zopfli = Zopfli { iterations: 1, block_splits: codec::DEFAULT_BLOCK_SPLITS };
+    let options = Options { format: Some(Format::Zlib), out_ext: Some("dat_zlib".to_string()), ..Options::default() };
+    assert!(compress_file(&path, &zopfli, &options).unwrap().written);
+    assert!(dir.path().join("hello_world.dat_zlib").exists());
+
+    // and the probe goes where the output does
+    let out = tempfile::tempdir().unwrap();
+    let out_dir = out.path().to_str().unwrap().to_string();
+    let options = Options { output_dir: Some(out_dir.clone()), ..options };
+    assert!(compress_file(&path, &zopfli, &options).unwrap().written);
+    let converted = dir.path().join("hello_world.dat_zlib");
+    assert!(fs::metadata(mirrored_path(&out_dir, converted.to_str().unwrap())).is_ok());
+    assert_eq!(fs::read_dir(out.path()).unwrap().count(), 1);
+
+    compress_file(&path, &Fixed(vec![1, 2, 3]), &unverified()).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+
+    assert_eq!(fs::read(&user_file).unwrap(), b"keep me");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3);
+}
+
+#[test]
+fn deferred_output_is_written_later() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = fixture(&dir);
+    let original = fs::read(&path).unwrap();
+    let options = unverified();
+    let (report, output) = compress_deferred(&path, read_input(&path, &options).unwrap(), &Fixed(vec![1, 2, 3]), &options).unwrap();
+    assert!(report.written && report.in_place);
+    assert_eq!(fs::read(&path).unwrap(), original);
+
+    output.write(&options).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), [1, 2, 3]);
+}
@@ -0,0 +1,124 @@
+//! End-to-end tests against the files in `fixtures/`, copied into a temporary directory
+//! so the library can rewrite them in place.
+
+use std::fs;
+
+use nbt_compress::backend::{CompressionBackend, Libdeflater, Zopfli};
+use nbt_compress::codec::{self, Format};
+use nbt_compress::error::is_corrupt;
+use nbt_compress::file::{compress_file, decompress_file, decompressed_path, Options};
+use nbt_compress::nbt;
+use nbt_compress::region::{self, Region, GZIP, ZLIB};
+
+/// `hello_world.nbt` from the NBT specification, gzip'd at a low level.
+const HELLO_WORLD: &[u8] = include_bytes!("fixtures/hello_world.nbt");
+
+/// A small compound with a repetitive byte array, zlib'd without compression.
+const STORED_ZLIB: &[u8] = include_bytes!("fixtures/stored.zlib");
+
+/// A region file with the same compound as [`STORED_ZLIB`] in two chunks, slot 0 as
+/// stored gzip and slot 33 as stored zlib.
+const REGION: &[u8] = include_bytes!("fixtures/r.0.0.mca");
+
+/// [`HELLO_WORLD`] with part of its deflate stream scrambled, so only the header is valid.
+const CORRUPT: &[u8] = include_bytes!("fixtures/corrupt.dat");
+
+fn backends() -> [Box<dyn CompressionBackend>; 2] {
+    [Box::new(Libdeflater { level: 12 }), Box::new(Zopfli { iterations: 5, block_splits: codec::DEFAULT_BLOCK_SPLITS })]
+}
+
+/// Copies `fixture` into `dir` as `name`, returning its path.
+fn copy(dir: &tempfile::TempDir, name: &str, fixture: &[u8]) -> String {
+    let path = dir.path().join(name);
+    fs::write(&path, fixture).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn formats_are_detected() {
+    assert_eq!(codec::detect_format(HELLO_WORLD), Some(Format::Gzip));
+    assert_eq!(codec::detect_format(STORED_ZLIB), Some(Format::Zlib));
+    assert_eq!(codec::detect_format(CORRUPT), Some(Format::Gzip));
+    assert!(region::is_region_file("r.0.0.mca"));
+}
+
+#[test]
+fn fixtures_decompress_to_valid_nbt() {
+    for fixture in [HELLO_WORLD, STORED_ZLIB] {
+        let (_, raw) = codec::decompress(fixture).unwrap();
+        nbt::validate(&raw).unwrap();
+    }
+    let (_, raw) = codec::decompress(STORED_ZLIB).unwrap();
+    assert_eq!(region::inspect(REGION).unwrap(), (2, 2 * raw.len()));
+}
+
+#[test]
+fn both_backends_round_trip_byte_exact() {
+    for fixture in [HELLO_WORLD, STORED_ZLIB] {
+        let (format, raw) = codec::decompress(fixture).unwrap();
+        for backend in backends() {
+            let compressed = backend.compress_as(&raw, format).unwrap();
+            assert_eq!(codec::decompress(&compressed).unwrap(), (format, raw.clone()), "{}", backend.name());
+        }
+    }
+}
+
+#[test]
+fn stored_files_are_recompressed_with_savings() {
+    for backend in backends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = copy(&dir, "level.dat", STORED_ZLIB);
+        let report = compress_file(&path, backend.as_ref(), &Options::default()).unwrap();
+        assert!(report.written && report.saved() > STORED_ZLIB.len() / 2, "{}", backend.name());
+
+        let output = fs::read(&path).unwrap();
+        assert_eq!(output.len(), report.compressed);
+        assert_eq!(codec::decompress(&output).unwrap(), codec::decompress(STORED_ZLIB).unwrap());
+    }
+}
+
+#[test]
+fn region_chunks_keep_their_types_and_data() {
+    for backend in backends() {
+        let output = region::recompress(REGION, backend.as_ref()).unwrap();
+        region::verify(REGION, &output).unwrap();
+        let (before, after) = (Region::parse(REGION).unwrap(), Region::parse(&output).unwrap());
+        assert_eq!(before.timestamps, after.timestamps);
+        assert_eq!(after.chunks.iter().flatten().count(), 2);
+        for (slot, compression) in [(0, GZIP), (33, ZLIB)] {
+            let (old, new) = (before.chunks[slot].as_ref().unwrap(), after.chunks[slot].as_ref().unwrap());
+            assert_eq!(new.compression, compression);
+            assert!(new.payload.len() < old.payload.len() / 2, "{}", backend.name());
+        }
+    }
+
+    // every chunk still fits in its one sector, so the file as a whole is no smaller
+    let dir = tempfile::tempdir().unwrap();
+    let path = copy(&dir, "r.0.0.mca", REGION);
+    let report = compress_file(&path, &Libdeflater { level: 12 }, &Options::default()).unwrap();
+    assert_eq!((report.saved(), report.written), (0, false));
+    assert_eq!(fs::read(&path).unwrap(), REGION);
+}
+
+#[test]
+fn decompressing_restores_the_original_nbt() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = copy(&dir, "level.dat", STORED_ZLIB);
+    decompress_file(&path, &Options::default()).unwrap();
+    assert_eq!(fs::read(decompressed_path(&path)).unwrap(), codec::decompress(STORED_ZLIB).unwrap().1);
+}
+
+#[test]
+fn corrupt_file_is_an_error_and_left_alone() {
+    assert!(is_corrupt(&codec::decompress(CORRUPT).unwrap_err()));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = copy(&dir, "corrupt.dat", CORRUPT);
+    for backend in backends() {
+        let e = compress_file(&path, backend.as_ref(), &Options::default()).unwrap_err();
+        assert!(is_corrupt(&e), "{}", e);
+    }
+    assert!(decompress_file(&path, &Options::default()).is_err());
+    assert_eq!(fs::read(&path).unwrap(), CORRUPT);
+    assert!(!std::path::Path::new(&decompressed_path(&path)).exists());
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use nbt_compress::journal::Journal;
+
+#[test]
+fn resuming_skips_recorded_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("run.journal");
+
+    let journal = Journal::open(&path, false).unwrap();
+    journal.record("world/level.dat").unwrap();
+    journal.record("world/region/r.0.0.mca").unwrap();
+    drop(journal);
+
+    let journal = Journal::open(&path, true).unwrap();
+    assert_eq!(journal.len(), 2);
+    assert!(journal.is_done("world/level.dat"));
+    assert!(!journal.is_done("world/region/r.1.0.mca"));
+
+    // without resuming, the journal starts over
+    let journal = Journal::open(&path, false).unwrap();
+    assert!(journal.is_empty());
+    assert!(Journal::open(&path, true).unwrap().is_empty());
+}
+
+#[test]
+fn resuming_without_a_journal_starts_afresh() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(Journal::open(&dir.path().join("missing.journal"), true).unwrap().is_empty());
+}
+
+#[test]
+fn default_path_depends_on_the_arguments_and_directory() {
+    let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let a = Journal::default_path(Path::new("/srv/world"), &args(&["-z", "-r", "world"]));
+    assert_eq!(a, Journal::default_path(Path::new("/srv/world"), &args(&["-z", "-r", "world"])));
+    assert_ne!(a, Journal::default_path(Path::new("/srv/other"), &args(&["-z", "-r", "world"])));
+    assert_ne!(a, Journal::default_path(Path::new("/srv/world"), &args(&["-z", "-rworld"])));
+}
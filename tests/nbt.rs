@@ -0,0 +1,259 @@
+use nbt_compress::codec;
+use nbt_compress::nbt::{self, parse, validate, Edition, Tag};
+
+const HELLO_WORLD: &[u8] = include_bytes!("fixtures/hello_world.nbt");
+
+/// `{"":{b:1b,s:2s,i:3,l:4L,f:5f,d:6d,ba:[B;1b],str:"x",li:[[I;1]],c:{},la:[L;1L]}}`
+fn every_tag() -> Vec<u8> {
+    let mut data = b"\x0a\x00\x00".to_vec();
+    data.extend(b"\x01\x00\x01b\x01");
+    data.extend(b"\x02\x00\x01s\x00\x02");
+    data.extend(b"\x03\x00\x01i\x00\x00\x00\x03");
+    data.extend(b"\x04\x00\x01l\x00\x00\x00\x00\x00\x00\x00\x04");
+    data.extend(b"\x05\x00\x01f\x40\xa0\x00\x00");
+    data.extend(b"\x06\x00\x01d\x40\x18\x00\x00\x00\x00\x00\x00");
+    data.extend(b"\x07\x00\x02ba\x00\x00\x00\x01\x01");
+    data.extend(b"\x08\x00\x03str\x00\x01x");
+    data.extend(b"\x09\x00\x02li\x0b\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00\x01");
+    data.extend(b"\x0a\x00\x01c\x00");
+    data.extend(b"\x0c\x00\x02la\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x01");
+    data.push(0);
+    data
+}
+
+#[test]
+fn accepts_well_formed_nbt() {
+    validate(&codec::decompress(HELLO_WORLD).unwrap().1).unwrap();
+    validate(&every_tag()).unwrap();
+    // an empty list may have the end tag as its element type
+    validate(b"\x09\x00\x00\x00\x00\x00\x00\x00").unwrap();
+}
+
+#[test]
+fn rejects_truncated_data() {
+    let data = every_tag();
+    for len in 0..data.len() {
+        assert!(validate(&data[..len]).is_err(), "accepted {} of {} bytes", len, data.len());
+    }
+}
+
+#[test]
+fn rejects_malformed_tags() {
+    assert!(validate(b"\x00").is_err());
+    assert!(validate(b"\x0a\x00\x00\x0d\x00\x00\x00").is_err());
+    assert!(validate(b"\x07\x00\x00\xff\xff\xff\xff").is_err());
+    assert!(validate(b"\x09\x00\x00\x00\x00\x00\x00\x01").is_err());
+    assert!(validate(b"\x0a\x00\x00\x00\x00").is_err());
+}
+
+#[test]
+fn rejects_deep_nesting() {
+    let mut data = b"\x09\x00\x00".to_vec();
+    for _ in 0..1000 {
+        data.extend(b"\x09\x00\x00\x00\x01");
+    }
+    assert!(validate(&data).is_err());
+}
+
+#[test]
+fn parses_every_tag_type() {
+    let (name, root) = parse(&every_tag()).unwrap();
+    assert_eq!(name, "");
+    let Tag::Compound(entries) = &root else { panic!("root is not a compound") };
+    assert_eq!(entries.len(), 11);
+    assert_eq!(entries[0], ("b".to_string(), Tag::Byte(1)));
+    assert_eq!(entries[8], ("li".to_string(), Tag::List(nbt::INT_ARRAY, vec![Tag::IntArray(vec![1])])));
+
+    for len in 0..every_tag().len() {
+        assert!(parse(&every_tag()[..len]).is_err());
+    }
+}
+
+#[test]
+fn renders_snbt() {
+    let (_, root) = parse(&every_tag()).unwrap();
+    assert_eq!(
+        root.to_snbt(false),
+        r#"{b:1b,s:2s,i:3,l:4L,f:5.0f,d:6.0d,ba:[B;1b],str:"x",li:[[I;1]],c:{},la:[L;1L]}"#
+    );
+
+    let (name, root) = parse(&codec::decompress(HELLO_WORLD).unwrap().1).unwrap();
+    assert_eq!(name, "hello world");
+    assert_eq!(root.to_snbt(true), "{\n    name: \"Bananrama\"\n}");
+
+    let root = Tag::Compound(vec![("a b".to_string(), Tag::String("say \"hi\"".to_string()))]);
+    assert_eq!(root.to_snbt(false), r#"{"a b":"say \"hi\""}"#);
+}
+
+#[test]
+fn decodes_modified_utf8() {
+    // NUL as two bytes, then U+1F600 as a surrogate pair
+    let data = b"\x08\x00\x00\x00\x08\xc0\x80\xed\xa0\xbd\xed\xb8\x80";
+    assert_eq!(parse(data).unwrap().1, Tag::String("\0\u{1F600}".to_string()));
+    assert!(parse(b"\x08\x00\x00\x00\x01\xff").is_err());
+}
+
+#[test]
+fn write_round_trips() {
+    let data = every_tag();
+    let (name, root) = parse(&data).unwrap();
+    assert_eq!(nbt::write(&name, &root).unwrap(), data);
+
+    let data = b"\x08\x00\x00\x00\x08\xc0\x80\xed\xa0\xbd\xed\xb8\x80";
+    let (name, root) = parse(data).unwrap();
+    assert_eq!(nbt::write(&name, &root).unwrap(), data);
+
+    assert!(nbt::write("", &Tag::List(nbt::INT, vec![Tag::Byte(1)])).is_err());
+}
+
+#[test]
+fn normalize_sorts_keys_but_not_lists() {
+    let root = Tag::Compound(vec![
+        ("z".to_string(), Tag::List(nbt::COMPOUND, vec![
+            Tag::Compound(vec![("b".to_string(), Tag::Byte(1)), ("a".to_string(), Tag::Byte(2))]),
+            Tag::Compound(vec![]),
+        ])),
+        ("a".to_string(), Tag::Int(3)),
+    ]);
+    let normalized = nbt::normalize(&nbt::write("root", &root).unwrap()).unwrap();
+
+    let (name, root) = parse(&normalized).unwrap();
+    assert_eq!(name, "root");
+    assert_eq!(root.to_snbt(false), "{a:3,z:[{a:2b,b:1b},{}]}");
+}
+
+#[test]
+fn strip_removes_keys_by_path_through_lists() {
+    let root = Tag::Compound(vec![
+        ("isLightOn".to_string(), Tag::Byte(1)),
+        ("sections".to_string(), Tag::List(nbt::COMPOUND, vec![
+            Tag::Compound(vec![("Y".to_string(), Tag::Byte(0)), ("BlockLight".to_string(), Tag::ByteArray(vec![0; 16]))]),
+            Tag::Compound(vec![("Y".to_string(), Tag::Byte(1))]),
+            Tag::Compound(vec![("Y".to_string(), Tag::Byte(2)), ("BlockLight".to_string(), Tag::ByteArray(vec![0; 16]))]),
+        ])),
+        ("Y".to_string(), Tag::Int(3)),
+    ]);
+    let data = nbt::write("", &root).unwrap();
+
+    let paths = ["isLightOn".to_string(), "sections.BlockLight".to_string(), "missing.key".to_string()];
+    let (stripped, removed) = nbt::strip(&data, &paths).unwrap().unwrap();
+    assert_eq!(removed, 3);
+    assert_eq!(parse(&stripped).unwrap().1.to_snbt(false), "{sections:[{Y:0b},{Y:1b},{Y:2b}],Y:3}");
+
+    // nothing matched, so there is nothing to rewrite
+    assert_eq!(nbt::strip(&data, &["sections.Y.deeper".to_string()]).unwrap(), None);
+}
+
+#[test]
+fn bedrock_is_little_endian() {
+    let (name, root) = parse(&every_tag()).unwrap();
+    let data = nbt::write_as(&name, &root, Edition::Bedrock).unwrap();
+    assert_ne!(data, every_tag());
+    assert_eq!(&data[..8], b"\x0a\x00\x00\x01\x01\x00b\x01");
+    assert_eq!(nbt::parse_as(&data, Edition::Bedrock).unwrap(), (name, root));
+    nbt::validate_as(&data, Edition::Bedrock).unwrap();
+    assert!(validate(&data).is_err());
+}
+
+#[test]
+fn detects_bedrock_header() {
+    let payload = nbt::write_as("", &Tag::Compound(vec![("a".to_string(), Tag::Int(1))]), Edition::Bedrock).unwrap();
+    let mut data = 10u32.to_le_bytes().to_vec();
+    data.extend((payload.len() as u32).to_le_bytes());
+    data.extend(&payload);
+
+    assert_eq!(nbt::bedrock_payload(&data), Some(&payload[..]));
+    nbt::validate_bedrock(&data).unwrap();
+    assert_eq!(nbt::bedrock_payload(&every_tag()), None);
+    assert_eq!(nbt::bedrock_payload(&data[..data.len() - 1]), None);
+    assert!(nbt::validate_bedrock(&every_tag()).is_err());
+}
+
+#[test]
+fn stats_account_for_every_byte() {
+    let data = every_tag();
+    let stats = nbt::stats(&data).unwrap();
+    assert_eq!(stats.counts, [0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1]);
+    // a compound's entry types and names, and its end tag, count for the compound
+    assert_eq!(stats.bytes, [0, 1, 2, 4, 8, 4, 8, 5, 3, 5, 54, 8, 12]);
+    assert_eq!(stats.bytes.iter().sum::<usize>(), data.len());
+    // the int arrays in the list are the deepest
+    assert_eq!((stats.tags(), stats.depth), (13, 2));
+
+    assert_eq!(nbt::type_name(nbt::INT_ARRAY), Some("IntArray"));
+    assert_eq!(nbt::type_name(13), None);
+    assert!(nbt::stats(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn first_difference_ignores_key_order() {
+    let (_, tree) = parse(&every_tag()).unwrap();
+    let mut sorted = tree.clone();
+    sorted.sort_keys();
+    assert_eq!(nbt::first_difference(&tree, &sorted), None);
+
+    let nan = Tag::Compound(vec![("f".to_string(), Tag::Float(f32::NAN))]);
+    assert_eq!(nbt::first_difference(&nan, &nan.clone()), None);
+    assert_eq!(nbt::first_difference(&Tag::Int(1), &Tag::Int(2)), Some("root".to_string()));
+}
+
+#[test]
+fn equality_modes_differ_only_in_key_order() {
+    use nbt::Equality::{Normalized, Strict};
+
+    let compound = |entries: &[(&str, Tag)]| Tag::Compound(entries.iter().map(|(key, value)| (key.to_string(), value.clone())).collect());
+    let item = |first: bool| match first {
+        true => compound(&[("id", Tag::String("stone".to_string())), ("Count", Tag::Byte(1))]),
+        false => compound(&[("Count", Tag::Byte(1)), ("id", Tag::String("stone".to_string()))]),
+    };
+    let a = compound(&[("Data", compound(&[("Inventory", Tag::List(10, vec![item(true), item(false)])), ("Time", Tag::Long(5))]))]);
+    let b = compound(&[("Data", compound(&[("Time", Tag::Long(5)), ("Inventory", Tag::List(10, vec![item(true), item(false)]))]))]);
+    assert!(a.equals(&a.clone(), Strict));
+    assert!(a.equals(&b, Normalized));
+    assert!(!a.equals(&b, Strict));
+    assert_eq!(nbt::first_difference_as(&a, &b, Strict), Some("Data.Inventory".to_string()));
+
+    // nested deeper, inside a list
+    let c = compound(&[("Data", compound(&[("Inventory", Tag::List(10, vec![item(false), item(false)])), ("Time", Tag::Long(5))]))]);
+    assert!(a.equals(&c, Normalized));
+    assert_eq!(nbt::first_difference_as(&a, &c, Strict), Some("Data.Inventory[0].id".to_string()));
+
+    // list order matters either way
+    let d = compound(&[("Data", compound(&[("Inventory", Tag::List(10, vec![item(true), compound(&[])])), ("Time", Tag::Long(5))]))]);
+    let e = compound(&[("Data", compound(&[("Inventory", Tag::List(10, vec![compound(&[]), item(true)])), ("Time", Tag::Long(5))]))]);
+    assert!(!d.equals(&e, Normalized));
+    assert!(!d.equals(&e, Strict));
+
+    // an entry only in one of them, at the end
+    let f = compound(&[("Data", compound(&[("Inventory", Tag::List(10, vec![item(true), item(false)])), ("Time", Tag::Long(5)), ("Extra", Tag::Byte(0))]))]);
+    assert_eq!(nbt::first_difference_as(&a, &f, Strict), Some("Data.Extra".to_string()));
+    assert_eq!(nbt::first_difference_as(&f, &a, Normalized), Some("Data.Extra".to_string()));
+
+    let nan = Tag::Double(f64::NAN);
+    assert!(nan.equals(&nan, Strict) && nan != nan);
+}
+
+#[test]
+fn normalize_keeps_nan() {
+    let tree = Tag::Compound(vec![("z".to_string(), Tag::Double(f64::NAN)), ("a".to_string(), Tag::Float(f32::NAN))]);
+    let normalized = nbt::normalize(&nbt::write("", &tree).unwrap()).unwrap();
+    let (_, written) = parse(&normalized).unwrap();
+    assert!(written.equals(&tree, nbt::Equality::Normalized));
+    assert_eq!(written.to_snbt(false), "{a:NaNf,z:NaNd}");
+}
+
+#[test]
+fn first_difference_finds_the_path() {
+    let item = |id: &str| Tag::Compound(vec![("id".to_string(), Tag::String(id.to_string()))]);
+    let player = |items: Vec<Tag>| {
+        let inventory = Tag::List(10, items);
+        Tag::Compound(vec![("Data".to_string(), Tag::Compound(vec![("Inventory".to_string(), inventory)]))])
+    };
+    let a = player(vec![item("stone"), item("dirt")]);
+    let b = player(vec![item("stone"), item("sand")]);
+    assert_eq!(nbt::first_difference(&a, &b), Some("Data.Inventory[1].id".to_string()));
+    assert_eq!(nbt::first_difference(&a, &player(vec![item("stone")])), Some("Data.Inventory".to_string()));
+
+    let extra = Tag::Compound(vec![("Data".to_string(), Tag::Compound(vec![])), ("Extra".to_string(), Tag::Byte(1))]);
+    assert_eq!(nbt::first_difference(&Tag::Compound(vec![("Data".to_string(), Tag::Compound(vec![]))]), &extra), Some("Extra".to_string()));
+}
@@ -0,0 +1,295 @@
+use std::fs;
+use std::io::Result;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use nbt_compress::backend::{CompressionBackend, Libdeflater, TimeLimit};
+use nbt_compress::file::{compress_file, Options};
+use nbt_compress::codec::{self, Format};
+use nbt_compress::region::{self, Region, GZIP, SECTOR_SIZE, UNCOMPRESSED, ZLIB};
+
+/// An uncompressed chunk-like NBT payload that compresses well.
+fn chunk_nbt(seed: u8) -> Vec<u8> {
+    let mut nbt = b"\x0a\x00\x00\x07\x00\x06Blocks\x00\x00\x10\x00".to_vec();
+    nbt.extend((0..4096u32).map(|i| (i % 7) as u8 ^ seed));
+    nbt.push(0);
+    nbt
+}
+
+/// Builds a region file with each `(slot, sector, compression, payload)` chunk placed at
+/// the given sector, leaving gaps between chunks so rewriting has to move them.
+fn build_region(chunks: &[(usize, usize, u8, Vec<u8>)]) -> Vec<u8> {
+    let mut data = vec![0; 2 * SECTOR_SIZE];
+    for (slot, sector, compression, payload) in chunks {
+        let start = sector * SECTOR_SIZE;
+        let length = payload.len() + 1;
+        let sectors = (length + 4).div_ceil(SECTOR_SIZE);
+        data.resize(data.len().max((sector + sectors) * SECTOR_SIZE), 0);
+
+        let location = (*sector as u32) << 8 | sectors as u32;
+        data[slot * 4..slot * 4 + 4].copy_from_slice(&location.to_be_bytes());
+        data[SECTOR_SIZE + slot * 4..SECTOR_SIZE + slot * 4 + 4].copy_from_slice(&(*slot as u32 + 1000).to_be_bytes());
+        data[start..start + 4].copy_from_slice(&(length as u32).to_be_bytes());
+        data[start + 4] = *compression;
+        data[start + 5..start + 5 + payload.len()].copy_from_slice(payload);
+    }
+    data
+}
+
+fn sample_region() -> Vec<u8> {
+    build_region(&[
+        (0, 10, GZIP, codec::compress_libdeflater(&chunk_nbt(0), Format::Gzip, 1).unwrap()),
+        (5, 4, ZLIB, codec::compress_libdeflater(&chunk_nbt(1), Format::Zlib, 1).unwrap()),
+        (1023, 2, UNCOMPRESSED, chunk_nbt(2)),
+    ])
+}
+
+#[test]
+fn parse_reads_chunks_and_timestamps() {
+    let data = sample_region();
+    let region = Region::parse(&data).unwrap();
+
+    assert_eq!(region.chunks.iter().flatten().count(), 3);
+    assert_eq!(region.chunks[0].as_ref().unwrap().compression, GZIP);
+    assert_eq!(region.chunks[5].as_ref().unwrap().compression, ZLIB);
+    assert_eq!(&*region.chunks[1023].as_ref().unwrap().payload, chunk_nbt(2));
+    assert_eq!(region.timestamps[5], 1005);
+    assert_eq!(region.timestamps[6], 0);
+}
+
+#[test]
+fn to_bytes_round_trips() {
+    let data = sample_region();
+    let region = Region::parse(&data).unwrap();
+    let rewritten = region.to_bytes().unwrap();
+
+    assert_eq!(rewritten.len() % SECTOR_SIZE, 0);
+    assert_eq!(Region::parse(&rewritten).unwrap(), region);
+}
+
+#[test]
+fn recompress_preserves_chunk_types_and_data() {
+    let data = sample_region();
+    let recompressed = region::recompress(&data, &Libdeflater { level: 12 }).unwrap();
+    assert!(recompressed.len() < data.len());
+
+    let region = Region::parse(&recompressed).unwrap();
+    let gzip = region.chunks[0].as_ref().unwrap();
+    let zlib = region.chunks[5].as_ref().unwrap();
+    let raw = region.chunks[1023].as_ref().unwrap();
+
+    assert_eq!((gzip.compression, zlib.compression, raw.compression), (GZIP, ZLIB, UNCOMPRESSED));
+    assert_eq!(codec::decompress_as(&gzip.payload, Format::Gzip).unwrap(), chunk_nbt(0));
+    assert_eq!(codec::decompress_as(&zlib.payload, Format::Zlib).unwrap(), chunk_nbt(1));
+    assert_eq!(&*raw.payload, chunk_nbt(2));
+    assert_eq!(region.timestamps[1023], 2023);
+}
+
+#[test]
+fn empty_region_is_left_alone() {
+    assert!(region::recompress(&[], &Libdeflater { level: 12 }).unwrap().is_empty());
+}
+
+#[test]
+fn truncated_header_is_an_error() {
+    assert!(Region::parse(&[0; 100]).is_err());
+}
+
+#[test]
+fn detects_region_extension() {
+    assert!(region::is_region_file("world/region/r.0.0.mca"));
+    assert!(region::is_region_file("world/region/r.-1.2.mcr"));
+    assert!(!region::is_region_file("world/level.dat"));
+}
+
+#[test]
+fn verify_catches_changed_chunks() {
+    let data = sample_region();
+    let recompressed = region::recompress(&data, &Libdeflater { level: 12 }).unwrap();
+    assert!(region::verify(&data, &recompressed).is_ok());
+
+    let mut region = Region::parse(&recompressed).unwrap();
+    region.chunks[5].as_mut().unwrap().compression = GZIP;
+    assert!(region::verify(&data, &region.to_bytes().unwrap()).is_err());
+
+    let mut region = Region::parse(&recompressed).unwrap();
+    region.chunks[7] = region.chunks[5].clone();
+    assert!(region::verify(&data, &region.to_bytes().unwrap()).is_err());
+}
+
+#[test]
+fn mcr_gzip_chunk_is_moved_and_recompressed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("r.0.0.mcr");
+    let payload = codec::compress_libdeflater(&chunk_nbt(3), Format::Gzip, 1).unwrap();
+    // a single chunk, well past the header with empty sectors in between
+    fs::write(&path, build_region(&[(33, 7, GZIP, payload)])).unwrap();
+
+    let options = Options::default();
+    compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &options).unwrap();
+    let data = fs::read(&path).unwrap();
+    assert_eq!(data.len(), 3 * SECTOR_SIZE);
+    assert_eq!(&data[33 * 4..33 * 4 + 4], &[0, 0, 2, 1]);
+
+    let region = Region::parse(&data).unwrap();
+    let chunk = region.chunks[33].as_ref().unwrap();
+    assert_eq!(chunk.compression, GZIP);
+    assert_eq!(codec::decompress_as(&chunk.payload, Format::Gzip).unwrap(), chunk_nbt(3));
+}
+
+#[test]
+fn recompress_is_deterministic() {
+    let chunks: Vec<_> = (0..64)
+        .map(|i| (i * 16, 2 + i * 3, ZLIB, codec::compress_libdeflater(&chunk_nbt(i as u8), Format::Zlib, 1).unwrap()))
+        .collect();
+    let data = build_region(&chunks);
+
+    let first = region::recompress(&data, &Libdeflater { level: 12 }).unwrap();
+    for _ in 0..3 {
+        assert_eq!(region::recompress(&data, &Libdeflater { level: 12 }).unwrap(), first);
+    }
+    assert!(region::verify(&data, &first).is_ok());
+}
+
+#[test]
+fn inspect_checks_every_chunk() {
+    let (chunks, decompressed) = region::inspect(&sample_region()).unwrap();
+    assert_eq!((chunks, decompressed), (3, 3 * chunk_nbt(0).len()));
+    assert_eq!(region::inspect(&[]).unwrap(), (0, 0));
+
+    let broken = build_region(&[(7, 2, UNCOMPRESSED, b"\x0a\x00\x00".to_vec())]);
+    let e = region::inspect(&broken).unwrap_err();
+    assert!(e.to_string().starts_with("Chunk 7"), "{}", e);
+}
+
+#[test]
+fn recompress_chunks_only_touches_the_chosen_chunk() {
+    // chunk 5 is stored, so recompressing it saves plenty
+    let data = build_region(&[
+        (0, 10, GZIP, codec::compress_libdeflater(&chunk_nbt(0), Format::Gzip, 1).unwrap()),
+        (5, 4, ZLIB, codec::compress_libdeflater(&chunk_nbt(1), Format::Zlib, 0).unwrap()),
+        (1023, 2, UNCOMPRESSED, chunk_nbt(2)),
+    ]);
+    assert_eq!(region::chunk_index(5, 0), 5);
+    // world coordinates land in the same slot
+    assert_eq!(region::chunk_index(-27, 32), 5);
+    assert_eq!(region::chunk_index(31, 31), 1023);
+
+    let (output, freed) = region::recompress_chunks(&data, &Libdeflater { level: 12 }, &[5]).unwrap();
+    assert_eq!(output.len(), data.len());
+    let before = Region::parse(&data).unwrap();
+    let after = Region::parse(&output).unwrap();
+    assert_eq!(after.chunks[0], before.chunks[0]);
+    assert_eq!(after.chunks[1023], before.chunks[1023]);
+    assert_eq!(after.timestamps, before.timestamps);
+    let chunk = after.chunks[5].as_ref().unwrap();
+    assert_eq!(chunk.payload.len() + freed, before.chunks[5].as_ref().unwrap().payload.len());
+    assert_eq!(codec::decompress_as(&chunk.payload, Format::Zlib).unwrap(), chunk_nbt(1));
+    region::verify(&data, &output).unwrap();
+
+    // every byte outside chunk 5's header entry and sectors is unchanged
+    let sectors = 4 * SECTOR_SIZE..6 * SECTOR_SIZE;
+    let outside = |bytes: &[u8]| -> Vec<u8> {
+        bytes.iter().enumerate().filter(|(i, _)| !(20..24).contains(i) && !sectors.contains(i)).map(|(_, b)| *b).collect()
+    };
+    assert_eq!(outside(&output), outside(&data));
+
+    assert!(region::recompress_chunks(&data, &Libdeflater { level: 12 }, &[6]).is_err());
+}
+
+#[test]
+fn chosen_chunks_are_written_when_they_shrink() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("r.0.0.mca");
+    let data = build_region(&[
+        (0, 10, GZIP, codec::compress_libdeflater(&chunk_nbt(0), Format::Gzip, 12).unwrap()),
+        (5, 4, ZLIB, codec::compress_libdeflater(&chunk_nbt(1), Format::Zlib, 0).unwrap()),
+    ]);
+    fs::write(&path, &data).unwrap();
+    let options = Options { chunks: vec![5], ..Options::default() };
+    let report = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.written);
+    assert!(report.saved() > 0);
+
+    let written = fs::read(&path).unwrap();
+    assert_eq!(written.len(), data.len());
+    assert_ne!(written, data);
+    region::verify(&data, &written).unwrap();
+
+    // the chunk that was already as small as it gets leaves nothing to write
+    let options = Options { chunks: vec![0], ..Options::default() };
+    let report = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(!report.written);
+    assert_eq!(fs::read(&path).unwrap(), written);
+}
+
+#[test]
+fn payload_sizes_compare_chunks_in_total() {
+    let stored = build_region(&[
+        (0, 2, GZIP, codec::compress_libdeflater(&chunk_nbt(0), Format::Gzip, 0).unwrap()),
+        (1, 8, UNCOMPRESSED, chunk_nbt(1)),
+    ]);
+    let (current, level_9) = region::payload_sizes(&stored, &Libdeflater { level: 9 }).unwrap();
+    // uncompressed chunks aren't counted
+    assert!(current > chunk_nbt(0).len() && current < 2 * chunk_nbt(0).len());
+    assert!(level_9 < current);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("r.0.0.mca");
+    let optimal = build_region(&[(0, 2, GZIP, codec::compress_libdeflater(&chunk_nbt(0), Format::Gzip, 12).unwrap())]);
+    fs::write(&path, &optimal).unwrap();
+    let options = Options { only_if_improvable: true, ..Options::default() };
+    let report = compress_file(path.to_str().unwrap(), &Libdeflater { level: 12 }, &options).unwrap();
+    assert!(report.skip_reason.is_some());
+    assert_eq!(fs::read(&path).unwrap(), optimal);
+}
+
+#[test]
+fn recompress_sized_counts_recompressed_chunks() {
+    let (_, decompressed) = region::recompress_sized(&sample_region(), &Libdeflater { level: 6 }).unwrap();
+    // the uncompressed chunk is copied as it is
+    assert_eq!(decompressed, 2 * chunk_nbt(0).len());
+    assert_eq!(region::recompress_sized(&[], &Libdeflater { level: 6 }).unwrap(), (Vec::new(), 0));
+}
+
+#[test]
+fn recompress_stripped_rewrites_chunks_that_lost_tags() {
+    let (output, decompressed, removed) =
+        region::recompress_stripped(&sample_region(), &Libdeflater { level: 12 }, &["Blocks".to_string()]).unwrap();
+    assert_eq!(decompressed, 2 * chunk_nbt(0).len());
+    // the uncompressed chunk is copied as it is
+    assert_eq!(removed, 2 * (chunk_nbt(0).len() - 4));
+    assert!(region::verify(&sample_region(), &output).is_err());
+    assert_eq!(region::inspect(&output).unwrap().0, 3);
+}
+
+struct Slow;
+
+impl CompressionBackend for Slow {
+    fn name(&self) -> &str {
+        "slow"
+    }
+
+    fn compress_as(&self, _data: &[u8], _format: Format) -> Result<Vec<u8>> {
+        std::thread::sleep(Duration::from_secs(2));
+        Ok(b"slow".to_vec())
+    }
+}
+
+#[test]
+fn timed_out_chunks_are_counted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("r.0.0.mca");
+    fs::write(&path, sample_region()).unwrap();
+    let limit = TimeLimit::new(Arc::new(Slow), Box::new(Libdeflater { level: 12 }), Duration::from_millis(20));
+    let counter = limit.timed_out_counter();
+
+    let report = compress_file(path.to_str().unwrap(), &limit, &Options::default()).unwrap();
+    assert!(report.written);
+    // the report only names the backend, so the two compressed chunks are only counted
+    assert!(!report.backend.contains("timed out"));
+    assert_eq!(limit.timed_out(), 2);
+    assert_eq!(counter.load(Ordering::Relaxed), 2);
+    region::verify(&sample_region(), &fs::read(&path).unwrap()).unwrap();
+}
@@ -0,0 +1,153 @@
+use std::fs;
+
+use nbt_compress::walk::{backup_of, collect_files, expand_glob, is_glob, is_world, read_file_list, Category, DEFAULT_EXTENSIONS};
+
+fn defaults() -> Vec<String> {
+    DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect()
+}
+
+#[test]
+fn finds_nested_nbt_files() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("region")).unwrap();
+    fs::create_dir_all(dir.path().join("playerdata")).unwrap();
+    for file in ["level.dat", "region/r.0.0.mca", "playerdata/a.DAT", "house.litematic", "wall.schematic", "session.lock", "readme"] {
+        fs::write(dir.path().join(file), []).unwrap();
+    }
+
+    let files = collect_files(dir.path(), &defaults(), false, false).unwrap();
+    let root = dir.path().to_str().unwrap();
+    assert_eq!(
+        files,
+        [
+            format!("{}/house.litematic", root),
+            format!("{}/level.dat", root),
+            format!("{}/playerdata/a.DAT", root),
+            format!("{}/region/r.0.0.mca", root),
+            format!("{}/wall.schematic", root),
+        ]
+    );
+}
+
+#[test]
+fn extension_filter_can_be_overridden() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("level.dat"), []).unwrap();
+    fs::write(dir.path().join("house.schem"), []).unwrap();
+
+    let files = collect_files(dir.path(), &["schem".to_string()], false, false).unwrap();
+    assert_eq!(files.len(), 1);
+    assert!(files[0].ends_with("house.schem"));
+}
+
+#[test]
+fn backups_are_only_collected_when_asked_for() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("region")).unwrap();
+    for file in ["level.dat", "level.dat_old", "region/r.0.0.mca", "region/r.0.0.mca.bak", "region/r.0.0.mca.bak2", "notes.txt.bak"] {
+        fs::write(dir.path().join(file), []).unwrap();
+    }
+
+    let root = dir.path().to_str().unwrap();
+    let files = collect_files(dir.path(), &defaults(), false, false).unwrap();
+    assert_eq!(files, [format!("{}/level.dat", root), format!("{}/region/r.0.0.mca", root)]);
+
+    // a backup is picked up if the file it is a copy of would be
+    let files = collect_files(dir.path(), &defaults(), false, true).unwrap();
+    assert_eq!(files.len(), 5);
+    assert!(!files.iter().any(|file| file.ends_with("notes.txt.bak")));
+}
+
+#[test]
+fn backups_map_to_their_originals() {
+    assert_eq!(backup_of("world/level.dat_old".as_ref()), Some("world/level.dat".into()));
+    assert_eq!(backup_of("r.0.0.mca.bak".as_ref()), Some("r.0.0.mca".into()));
+    assert_eq!(backup_of("r.0.0.mca.BAK12".as_ref()), Some("r.0.0.mca".into()));
+    assert_eq!(backup_of("r.0.0.mca.bakery".as_ref()), None);
+    assert_eq!(backup_of("level.dat".as_ref()), None);
+    assert!(nbt_compress::region::is_region_file("r.0.0.mca.bak1"));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_are_skipped() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("level.dat"), []).unwrap();
+    std::os::unix::fs::symlink(dir.path(), dir.path().join("loop")).unwrap();
+    std::os::unix::fs::symlink(dir.path().join("level.dat"), dir.path().join("link.dat")).unwrap();
+
+    assert_eq!(collect_files(dir.path(), &defaults(), false, false).unwrap().len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn followed_symlinks_stop_at_cycles() {
+    let dir = tempfile::tempdir().unwrap();
+    let world = dir.path().join("world");
+    let elsewhere = dir.path().join("elsewhere");
+    fs::create_dir_all(&world).unwrap();
+    fs::create_dir_all(&elsewhere).unwrap();
+    fs::write(world.join("level.dat"), []).unwrap();
+    fs::write(elsewhere.join("r.0.0.mca"), []).unwrap();
+    std::os::unix::fs::symlink(&world, world.join("loop")).unwrap();
+    std::os::unix::fs::symlink(&elsewhere, world.join("region")).unwrap();
+    std::os::unix::fs::symlink(world.join("level.dat"), world.join("link.dat")).unwrap();
+    std::os::unix::fs::symlink(world.join("missing"), world.join("broken.dat")).unwrap();
+
+    let files = collect_files(&world, &defaults(), true, false).unwrap();
+    let root = world.to_str().unwrap();
+    assert_eq!(files, [format!("{}/level.dat", root), format!("{}/region/r.0.0.mca", root)]);
+}
+
+#[test]
+fn globs_expand_to_matching_files() {
+    let dir = tempfile::tempdir().unwrap();
+    for file in ["a.dat", "b.dat", "c.nbt"] {
+        fs::write(dir.path().join(file), []).unwrap();
+    }
+
+    let root = dir.path().to_str().unwrap();
+    let pattern = format!("{}/*.dat", root);
+    assert!(is_glob(&pattern));
+    assert_eq!(expand_glob(&pattern).unwrap(), [format!("{}/a.dat", root), format!("{}/b.dat", root)]);
+    assert!(expand_glob(&format!("{}/*.mca", root)).unwrap().is_empty());
+    assert!(expand_glob("[").is_err());
+    assert!(!is_glob("level.dat"));
+}
+
+#[test]
+fn file_lists_skip_comments_and_blank_lines() {
+    let dir = tempfile::tempdir().unwrap();
+    let manifest = dir.path().join("manifest.txt");
+    fs::write(&manifest, "# exported by the backup script\nworld/level.dat\n\n  world/region/r.0.0.mca  \r\n#world/old.dat\n").unwrap();
+
+    let files = read_file_list(manifest.to_str().unwrap()).unwrap();
+    assert_eq!(files, ["world/level.dat", "world/region/r.0.0.mca"]);
+    assert!(read_file_list(dir.path().join("missing.txt").to_str().unwrap()).is_err());
+}
+
+#[test]
+fn world_files_are_categorized_by_location() {
+    let cases = [
+        ("world/level.dat", Category::Level),
+        ("world/level.dat_old", Category::Level),
+        ("world/region/r.0.0.mca", Category::Region),
+        ("world/DIM-1/region/r.0.0.mca", Category::Region),
+        ("world/DIM1/entities/r.1.-1.mca", Category::Region),
+        ("world/region/r.0.0.mca.bak", Category::Region),
+        ("world/poi/r.0.0.mca", Category::Region),
+        ("world/playerdata/069a79f4-44e9-4726-a5be-fca90e38aaf5.dat", Category::PlayerData),
+        ("world/players/Notch.dat", Category::PlayerData),
+        ("world/data/raids.dat", Category::Data),
+        ("world/generated/minecraft/structures/house.nbt", Category::Other),
+        ("house.litematic", Category::Other),
+    ];
+    for (path, category) in cases {
+        assert_eq!(Category::of(path), category, "{}", path);
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    assert!(!is_world(dir.path()));
+    fs::write(dir.path().join("level.dat"), []).unwrap();
+    assert!(is_world(dir.path()));
+}